@@ -1,3 +1,4 @@
+use crate::open_bazaar_api::open_bazaar_rpc_client::OpenBazaarRpcClient;
 use crate::open_bazaar_api::NodeAddressType;
 use anyhow;
 use bincode;
@@ -7,17 +8,22 @@ use sled::{open, Db};
 use std::collections::HashSet;
 use std::path::Path;
 use tokio::runtime::Runtime;
+use tonic::transport::Channel;
 
 mod network;
+mod wallet;
+
+pub use wallet::{Balance, Transaction, TransactionPage};
 
 pub(crate) mod open_bazaar_api {
-    tonic::include_proto!("openbazaar_api");
+    tonic::include_proto!("openbazaar_rpc");
 }
 
 pub struct Client {
     db: Db,
     runtime: Runtime,
     openbazaar_nodes: OpenBazaarNodes,
+    rpc: Option<OpenBazaarRpcClient<Channel>>,
 }
 
 impl Client {
@@ -34,13 +40,17 @@ impl Client {
             db,
             runtime,
             openbazaar_nodes,
+            rpc: None,
         };
 
         Ok(client)
     }
 
     pub fn connect(&mut self, server_address: String) -> anyhow::Result<()> {
-        match get_server_for_address(&self.runtime, server_address.clone(), b"") {
+        let mut rpc = self
+            .runtime
+            .block_on(OpenBazaarRpcClient::connect(server_address.clone()))?;
+        match get_server_for_address(&self.runtime, &mut rpc, b"") {
             Ok(d) => {
                 let node = Node::new(NodeAddressType::Clear, server_address);
                 self.openbazaar_nodes.add(node).expect("Failed to add node");
@@ -48,12 +58,67 @@ impl Client {
                 self.openbazaar_nodes
                     .add(new_node)
                     .expect("Failed to add node");
+                self.rpc = Some(rpc);
 
                 Ok(())
             }
             Err(e) => return Err(anyhow::Error::from(e)),
         }
     }
+
+    /// The connection made by `connect`, clones share its channel.
+    fn rpc(&self) -> anyhow::Result<OpenBazaarRpcClient<Channel>> {
+        self.rpc
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("Not connected to a server"))
+    }
+
+    /// Reveals a new receiving address of the server's wallet.
+    pub fn get_new_address(&self) -> anyhow::Result<String> {
+        Ok(wallet::get_new_address(&self.runtime, &mut self.rpc()?)?)
+    }
+
+    pub fn get_balance(&self) -> anyhow::Result<Balance> {
+        Ok(wallet::get_balance(&self.runtime, &mut self.rpc()?)?)
+    }
+
+    /// Lists wallet transactions newest first, a `limit` of 0 uses the server's default.
+    pub fn list_transactions(&self, offset: u32, limit: u32) -> anyhow::Result<TransactionPage> {
+        Ok(wallet::list_transactions(
+            &self.runtime,
+            &mut self.rpc()?,
+            offset,
+            limit,
+        )?)
+    }
+
+    /// Returns the fee rate in sat/vB expected to confirm within `target_blocks`.
+    pub fn estimate_fee(&self, target_blocks: u32) -> anyhow::Result<f32> {
+        Ok(wallet::estimate_fee(
+            &self.runtime,
+            &mut self.rpc()?,
+            target_blocks,
+        )?)
+    }
+
+    /// Sends `amount` sats to `address` and returns the txid. Without a fee rate the server
+    /// estimates one.
+    pub fn send(
+        &self,
+        address: String,
+        amount: u64,
+        fee_rate: Option<f32>,
+        rbf: bool,
+    ) -> anyhow::Result<String> {
+        Ok(wallet::send(
+            &self.runtime,
+            &mut self.rpc()?,
+            address,
+            amount,
+            fee_rate,
+            rbf,
+        )?)
+    }
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Debug)]
@@ -1,4 +1,4 @@
-use crate::open_bazaar_api::open_bazaar_api_client::OpenBazaarApiClient;
+use crate::open_bazaar_api::open_bazaar_rpc_client::OpenBazaarRpcClient;
 use crate::open_bazaar_api::{NodeAddressType, NodeLocationRequest};
 use tokio::runtime::Runtime;
 use tonic::transport::Channel;
@@ -31,14 +31,9 @@ impl From<i32> for NodeAddressType {
 
 pub fn get_server_for_address(
     rt: &Runtime,
-    server_address: String,
+    client: &mut OpenBazaarRpcClient<Channel>,
     message_address: &[u8],
 ) -> Result<(NodeAddressType, String), NetError> {
-    let mut client = match rt.block_on(OpenBazaarApiClient::connect(server_address)) {
-        Ok(d) => d,
-        Err(e) => return Err(NetError::TonicError { source: e }),
-    };
-
     // Craft request object
     let request = Request::new(NodeLocationRequest {
         address: message_address.to_vec(),
@@ -0,0 +1,123 @@
+use crate::network::NetError;
+use crate::open_bazaar_api::open_bazaar_rpc_client::OpenBazaarRpcClient;
+use crate::open_bazaar_api::{
+    EstimateFeeRequest, GetBalanceRequest, GetNewAddressRequest, ListTransactionsRequest,
+    SendRequest,
+};
+use tokio::runtime::Runtime;
+use tonic::transport::Channel;
+use tonic::Request;
+
+/// Wallet balance in sats.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Balance {
+    pub confirmed: u64,
+    pub pending: u64,
+    pub immature: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Transaction {
+    pub txid: String,
+    pub received: u64,
+    pub sent: u64,
+    pub fee: Option<u64>,
+    pub confirmation_height: Option<u32>,
+    pub confirmation_time: Option<u64>,
+}
+
+/// One page of wallet transactions along with the total number of transactions.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TransactionPage {
+    pub transactions: Vec<Transaction>,
+    pub total: u32,
+}
+
+pub fn get_new_address(
+    rt: &Runtime,
+    client: &mut OpenBazaarRpcClient<Channel>,
+) -> Result<String, NetError> {
+    let response = rt
+        .block_on(client.get_new_address(Request::new(GetNewAddressRequest {})))
+        .map_err(NetError::ServerResponseErr)?;
+
+    Ok(response.into_inner().address)
+}
+
+pub fn get_balance(
+    rt: &Runtime,
+    client: &mut OpenBazaarRpcClient<Channel>,
+) -> Result<Balance, NetError> {
+    let response = rt
+        .block_on(client.get_balance(Request::new(GetBalanceRequest {})))
+        .map_err(NetError::ServerResponseErr)?
+        .into_inner();
+
+    Ok(Balance {
+        confirmed: response.confirmed,
+        pending: response.pending,
+        immature: response.immature,
+    })
+}
+
+pub fn list_transactions(
+    rt: &Runtime,
+    client: &mut OpenBazaarRpcClient<Channel>,
+    offset: u32,
+    limit: u32,
+) -> Result<TransactionPage, NetError> {
+    let response = rt
+        .block_on(client.list_transactions(Request::new(ListTransactionsRequest { offset, limit })))
+        .map_err(NetError::ServerResponseErr)?
+        .into_inner();
+
+    Ok(TransactionPage {
+        transactions: response
+            .transactions
+            .into_iter()
+            .map(|tx| Transaction {
+                txid: tx.txid,
+                received: tx.received,
+                sent: tx.sent,
+                fee: tx.fee,
+                confirmation_height: tx.confirmation_height,
+                confirmation_time: tx.confirmation_time,
+            })
+            .collect(),
+        total: response.total,
+    })
+}
+
+pub fn estimate_fee(
+    rt: &Runtime,
+    client: &mut OpenBazaarRpcClient<Channel>,
+    target_blocks: u32,
+) -> Result<f32, NetError> {
+    let response = rt
+        .block_on(client.estimate_fee(Request::new(EstimateFeeRequest { target_blocks })))
+        .map_err(NetError::ServerResponseErr)?;
+
+    Ok(response.into_inner().sat_per_vbyte)
+}
+
+pub fn send(
+    rt: &Runtime,
+    client: &mut OpenBazaarRpcClient<Channel>,
+    address: String,
+    amount: u64,
+    fee_rate: Option<f32>,
+    rbf: bool,
+) -> Result<String, NetError> {
+    let request = Request::new(SendRequest {
+        address,
+        amount,
+        // The server estimates a fee rate when none is given
+        fee_rate: fee_rate.unwrap_or_default(),
+        rbf,
+    });
+    let response = rt
+        .block_on(client.send(request))
+        .map_err(NetError::ServerResponseErr)?;
+
+    Ok(response.into_inner().txid)
+}
@@ -4,6 +4,12 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             "NodeAddressType",
             "#[derive(serde::Serialize, serde::Deserialize)]",
         )
-        .compile(&["../Protobufs/OpenBazaarRpc.proto"], &["../Protobufs/"])?;
+        .compile(
+            &[
+                "../Protobufs/OpenBazaarRpc.proto",
+                "../Protobufs/OpenBazaarDirect.proto",
+            ],
+            &["../Protobufs/"],
+        )?;
     Ok(())
 }
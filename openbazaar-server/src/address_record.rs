@@ -0,0 +1,276 @@
+use libp2p::identity::{Keypair, PublicKey};
+use libp2p_identity::PeerId;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::network::NodeData;
+
+#[derive(Debug, thiserror::Error)]
+pub enum AddressRecordError {
+    #[error("Malformed address record")]
+    Malformed(#[from] bincode::Error),
+    #[error("Address record public key could not be decoded")]
+    InvalidPublicKey,
+    #[error("Address record is not signed by the peer it describes")]
+    PeerIdMismatch,
+    #[error("Address record is not stored under its peer id")]
+    KeyMismatch,
+    #[error("Address record signature is invalid")]
+    InvalidSignature,
+    #[error("Address record has expired")]
+    Expired,
+    #[error("Address record sequence {sequence} is older than {seen}")]
+    Stale { sequence: u64, seen: u64 },
+}
+
+impl AddressRecordError {
+    /// Whether the record was forged or garbled rather than just outdated.
+    ///
+    /// Honest peers cache and republish records that expire or get superseded, only
+    /// forged ones count against the peer that sent them.
+    pub fn is_misbehaviour(&self) -> bool {
+        match self {
+            Self::Malformed(_)
+            | Self::InvalidPublicKey
+            | Self::PeerIdMismatch
+            | Self::KeyMismatch
+            | Self::InvalidSignature => true,
+            Self::Expired | Self::Stale { .. } => false,
+        }
+    }
+}
+
+/// A `NodeData` signed by the peer it describes.
+///
+/// This is the value stored in the DHT under the peer's id. The sequence
+/// number lets readers drop records older than one they have already seen.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SignedNodeData {
+    pub node_data: NodeData,
+    pub sequence: u64,
+    /// Seconds since the unix epoch after which the record is no longer valid
+    pub expires: u64,
+    pub public_key: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+impl SignedNodeData {
+    pub fn sign(
+        keypair: &Keypair,
+        node_data: NodeData,
+        sequence: u64,
+        ttl: Duration,
+    ) -> anyhow::Result<Self> {
+        let expires = unix_now().as_secs() + ttl.as_secs();
+        let payload = signing_payload(&node_data, sequence, expires)?;
+        let signature = keypair.sign(&payload)?;
+
+        Ok(Self {
+            node_data,
+            sequence,
+            expires,
+            public_key: keypair.public().encode_protobuf(),
+            signature,
+        })
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, AddressRecordError> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, AddressRecordError> {
+        Ok(bincode::serialize(self)?)
+    }
+
+    /// Checks that the record is signed by the peer it describes, is stored
+    /// under that peer's id and has not expired.
+    pub fn verify(&self, key: &[u8]) -> Result<PeerId, AddressRecordError> {
+        let public_key = PublicKey::try_decode_protobuf(&self.public_key)
+            .map_err(|_| AddressRecordError::InvalidPublicKey)?;
+        let peer_id = public_key.to_peer_id();
+
+        if self.node_data.peer_id != peer_id.to_bytes() {
+            return Err(AddressRecordError::PeerIdMismatch);
+        }
+        if key != peer_id.to_bytes().as_slice() {
+            return Err(AddressRecordError::KeyMismatch);
+        }
+
+        let payload = signing_payload(&self.node_data, self.sequence, self.expires)?;
+        if !public_key.verify(&payload, &self.signature) {
+            return Err(AddressRecordError::InvalidSignature);
+        }
+
+        if self.expires <= unix_now().as_secs() {
+            return Err(AddressRecordError::Expired);
+        }
+
+        Ok(peer_id)
+    }
+}
+
+/// Remembers the sequence of a verified record from `peer_id`, rejecting the record
+/// if it is older than one already seen.
+pub fn check_sequence(
+    seen: &mut HashMap<PeerId, u64>,
+    peer_id: PeerId,
+    sequence: u64,
+) -> Result<(), AddressRecordError> {
+    match seen.get(&peer_id) {
+        Some(&last) if sequence < last => Err(AddressRecordError::Stale {
+            sequence,
+            seen: last,
+        }),
+        _ => {
+            seen.insert(peer_id, sequence);
+            Ok(())
+        }
+    }
+}
+
+fn signing_payload(
+    node_data: &NodeData,
+    sequence: u64,
+    expires: u64,
+) -> Result<Vec<u8>, bincode::Error> {
+    bincode::serialize(&(node_data, sequence, expires))
+}
+
+pub fn unix_now() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::openbazaar::NodeAddressType;
+
+    const TTL: Duration = Duration::from_secs(60);
+
+    fn node_data(keypair: &Keypair) -> NodeData {
+        let peer_id = keypair.public().to_peer_id();
+        NodeData {
+            peer_id: peer_id.to_bytes(),
+            address: format!("/ip4/192.0.2.1/tcp/4001/p2p/{}", peer_id),
+            address_type: NodeAddressType::Clear,
+        }
+    }
+
+    fn key_of(keypair: &Keypair) -> Vec<u8> {
+        keypair.public().to_peer_id().to_bytes()
+    }
+
+    #[test]
+    fn signed_record_round_trips() {
+        let keypair = Keypair::generate_ed25519();
+        let signed = SignedNodeData::sign(&keypair, node_data(&keypair), 1, TTL).unwrap();
+
+        let decoded = SignedNodeData::from_bytes(&signed.to_bytes().unwrap()).unwrap();
+        let peer_id = decoded.verify(&key_of(&keypair)).unwrap();
+
+        assert_eq!(peer_id, keypair.public().to_peer_id());
+        assert_eq!(decoded.node_data.address, signed.node_data.address);
+        assert_eq!(decoded.sequence, 1);
+    }
+
+    #[test]
+    fn tampered_record_is_rejected() {
+        let keypair = Keypair::generate_ed25519();
+        let signed = SignedNodeData::sign(&keypair, node_data(&keypair), 1, TTL).unwrap();
+
+        let mut tampered = signed.clone();
+        tampered.signature[0] ^= 0xff;
+        assert!(matches!(
+            tampered.verify(&key_of(&keypair)),
+            Err(AddressRecordError::InvalidSignature)
+        ));
+
+        let mut tampered = signed.clone();
+        tampered.node_data.address = "/ip4/192.0.2.2/tcp/4001".to_string();
+        assert!(matches!(
+            tampered.verify(&key_of(&keypair)),
+            Err(AddressRecordError::InvalidSignature)
+        ));
+
+        let mut tampered = signed;
+        tampered.sequence += 1;
+        assert!(matches!(
+            tampered.verify(&key_of(&keypair)),
+            Err(AddressRecordError::InvalidSignature)
+        ));
+    }
+
+    #[test]
+    fn record_of_another_peer_is_rejected() {
+        let keypair = Keypair::generate_ed25519();
+        let other = Keypair::generate_ed25519();
+
+        // Signed by a key other than the one of the peer it describes
+        let signed = SignedNodeData::sign(&other, node_data(&keypair), 1, TTL).unwrap();
+        assert!(matches!(
+            signed.verify(&key_of(&keypair)),
+            Err(AddressRecordError::PeerIdMismatch)
+        ));
+
+        // Public key swapped for another peer's
+        let mut signed = SignedNodeData::sign(&keypair, node_data(&keypair), 1, TTL).unwrap();
+        signed.public_key = other.public().encode_protobuf();
+        assert!(matches!(
+            signed.verify(&key_of(&keypair)),
+            Err(AddressRecordError::PeerIdMismatch)
+        ));
+
+        // Stored under another peer's id
+        let signed = SignedNodeData::sign(&keypair, node_data(&keypair), 1, TTL).unwrap();
+        assert!(matches!(
+            signed.verify(&key_of(&other)),
+            Err(AddressRecordError::KeyMismatch)
+        ));
+    }
+
+    #[test]
+    fn expired_record_is_rejected() {
+        let keypair = Keypair::generate_ed25519();
+        let signed =
+            SignedNodeData::sign(&keypair, node_data(&keypair), 1, Duration::ZERO).unwrap();
+
+        assert!(matches!(
+            signed.verify(&key_of(&keypair)),
+            Err(AddressRecordError::Expired)
+        ));
+    }
+
+    #[test]
+    fn outdated_records_are_not_misbehaviour() {
+        assert!(AddressRecordError::InvalidSignature.is_misbehaviour());
+        assert!(AddressRecordError::KeyMismatch.is_misbehaviour());
+        assert!(!AddressRecordError::Expired.is_misbehaviour());
+        assert!(!AddressRecordError::Stale {
+            sequence: 1,
+            seen: 2
+        }
+        .is_misbehaviour());
+    }
+
+    #[test]
+    fn stale_sequence_is_rejected() {
+        let peer_id = Keypair::generate_ed25519().public().to_peer_id();
+        let mut seen = HashMap::new();
+
+        check_sequence(&mut seen, peer_id, 5).unwrap();
+        // The same record may be returned by several peers
+        check_sequence(&mut seen, peer_id, 5).unwrap();
+        assert!(matches!(
+            check_sequence(&mut seen, peer_id, 4),
+            Err(AddressRecordError::Stale {
+                sequence: 4,
+                seen: 5
+            })
+        ));
+        check_sequence(&mut seen, peer_id, 6).unwrap();
+        assert_eq!(seen[&peer_id], 6);
+    }
+}
@@ -1,55 +1,156 @@
 use std::collections::{HashMap, VecDeque};
+use std::pin::Pin;
+use std::time::Duration;
 
+use crate::address_record::unix_now;
+use crate::content::{self, HashAlgorithm};
+use crate::dag::{Chunker, Link, Manifest};
 use crate::db::DB;
-use crate::network::Client;
+use crate::escrow::{self, EscrowRole, EscrowService};
+use crate::network::{Client, Event, NetworkError, QueryKind};
 use crate::openbazaar::open_bazaar_rpc_server::OpenBazaarRpc;
 use crate::openbazaar::GetPeerIdRequest;
 use crate::openbazaar::GetPeerIdResponse;
 use crate::openbazaar::HashType;
 use crate::openbazaar::SaveMessageRequest;
 use crate::openbazaar::SetProfileResponse;
+use crate::openbazaar::{
+    network_event, BootstrapFinished, ListenAddressAdded, NetworkEvent, PeerConnected,
+    PeerDisconnected, QueryCompleted, QueryType, RecordPublished, WatchNetworkEventsRequest,
+};
+use crate::openbazaar::{
+    ApproveEscrowPayoutRequest, ApproveEscrowPayoutResponse, EscrowOutput, EscrowPayout,
+    EscrowRole as EscrowRoleMessage, GetEscrowKeyRequest, GetEscrowKeyResponse, GetEscrowRequest,
+    GetEscrowResponse, OpenEscrowRequest, OpenEscrowResponse, ProposeEscrowPayoutRequest,
+    ProposeEscrowPayoutResponse, RejectEscrowPayoutRequest, RejectEscrowPayoutResponse,
+};
+use crate::openbazaar::{
+    BannedPeer, ListBannedPeersRequest, ListBannedPeersResponse, UnbanPeerRequest,
+    UnbanPeerResponse,
+};
+use crate::openbazaar::{
+    EstimateFeeRequest, EstimateFeeResponse, GetBalanceRequest, GetBalanceResponse,
+    GetNewAddressRequest, GetNewAddressResponse, ListTransactionsRequest, ListTransactionsResponse,
+    SendRequest, SendResponse, WalletTransaction,
+};
+use crate::openbazaar::{
+    GetContentRequest, GetContentResponse, PutContentRequest, PutContentResponse,
+};
 use crate::openbazaar::{
     GetMessageRequest, GetMessageResponse, GetProfileRequest, GetProfileResponse,
     MessageLocationResponse, NodeLocationRequest, NodeLocationResponse, Profile as ProfileMessage,
     SaveMessageResponse, SetProfileRequest,
 };
+use crate::openbazaar::{GetNodeInfoRequest, GetNodeInfoResponse};
+use crate::openbazaar::{ListPeersRequest, ListPeersResponse, Peer};
+use crate::openbazaar::{PubSubMessage, PublishRequest, PublishResponse, SubscribeRequest};
 use crate::profile::Profile;
 use crate::profile::ProfileData;
-use sha3::{Digest, Sha3_256};
-use tonic::{Request, Response, Status};
+use crate::wallet::{WalletError, WalletService};
+use bdk::bitcoin::{Address, Txid};
+use bdk_chain::ConfirmationTime;
+use futures::Stream;
+use libp2p_identity::PeerId;
+use tokio::sync::broadcast::error::RecvError;
+use tonic::{Request, Response, Status, Streaming};
 use tracing::log::trace;
 use tracing::{event, instrument, Level};
 
+// Transactions returned by ListTransactions when no limit is given
+const DEFAULT_TRANSACTION_PAGE: usize = 50;
+
+// How long messages and chunks fetched from other nodes are served from the local cache
+const REMOTE_MESSAGE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
 #[derive(Debug)]
 pub struct OpenBazaarRpcService<T: DB> {
     client: Client,
     dbconn: T,
+    wallet: WalletService,
+    escrows: EscrowService<T>,
 }
 
 impl<T: DB> OpenBazaarRpcService<T> {
-    pub fn new(client: Client, dbconn: T) -> Self {
-        Self { client, dbconn }
+    pub fn new(
+        client: Client,
+        dbconn: T,
+        wallet: WalletService,
+        escrows: EscrowService<T>,
+    ) -> Self {
+        Self {
+            client,
+            dbconn,
+            wallet,
+            escrows,
+        }
+    }
+
+    /// Returns a message stored on other nodes, from the cache if it was fetched recently.
+    ///
+    /// Providers are tried one at a time, each first over a connection the network already
+    /// knows how to make and then through its published clear address. Only content verified
+    /// against its content id is cached, anything else is whatever the provider sent and is
+    /// fetched again every time.
+    async fn fetch_message(&self, address: &[u8]) -> Result<Vec<u8>, Status> {
+        if let Some(content) = self
+            .dbconn
+            .get_cached_message(address)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+        {
+            return Ok(content);
+        }
+
+        let local_peer_id = self.client.get_peer_id().await?;
+        let providers = match self.client.get_providers(address.to_vec()).await {
+            Ok(providers) => providers,
+            Err(NetworkError::Timeout | NetworkError::NotFound) => {
+                return Err(Status::not_found("Message not found"))
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        for peer_id in providers.into_iter().filter(|p| *p != local_peer_id) {
+            let content = match fetch_from(&self.client, peer_id, address).await {
+                Some(content) => content,
+                None => continue,
+            };
+            // A provider can't be trusted to send what the content id promises
+            if !content::is_content_id(address) {
+                return Ok(content);
+            }
+            if let Err(e) = content::verify(address, &content) {
+                trace!("Provider {} sent invalid content: {}", peer_id, e);
+                continue;
+            }
+            self.dbconn
+                .cache_message(address, &content, REMOTE_MESSAGE_TTL)
+                .await
+                .map_err(|e| Status::internal(e.to_string()))?;
+            return Ok(content);
+        }
+
+        Err(Status::not_found("Message not found"))
     }
 }
 
 #[tonic::async_trait]
-impl<T: DB + Sync + Send + 'static> OpenBazaarRpc for OpenBazaarRpcService<T> {
+impl<T: DB + Clone + Sync + Send + 'static> OpenBazaarRpc for OpenBazaarRpcService<T> {
+    type GetContentStream = Pin<Box<dyn Stream<Item = Result<GetContentResponse, Status>> + Send>>;
+    type SubscribeStream = Pin<Box<dyn Stream<Item = Result<PubSubMessage, Status>> + Send>>;
+    type WatchNetworkEventsStream =
+        Pin<Box<dyn Stream<Item = Result<NetworkEvent, Status>> + Send>>;
+
     async fn look_up(
         &self,
         request: Request<NodeLocationRequest>,
     ) -> Result<Response<NodeLocationResponse>, Status> {
         let address = request.into_inner().address;
         println!("Looking up address: {:?}", address);
-        let peer_id = match self.client.get_closest_peers(address).await {
-            Ok(d) => d,
-            Err(e) => return Err(Status::internal(e.to_string())),
-        };
+        let peer_id = self.client.get_closest_peers(address).await?;
 
         // Grab the clear address of the peer
-        let nodedata = match self.client.get_clear_address(peer_id).await {
-            Ok(d) => d,
-            Err(e) => return Err(Status::internal(e.to_string())),
-        };
+        let nodedata = self.client.get_clear_address(peer_id).await?;
 
         println!("Found peer: {:?}", nodedata);
 
@@ -67,7 +168,7 @@ impl<T: DB + Sync + Send + 'static> OpenBazaarRpc for OpenBazaarRpcService<T> {
         request: Request<NodeLocationRequest>,
     ) -> Result<Response<MessageLocationResponse>, Status> {
         let address = request.into_inner().address;
-        let peer_ids = self.client.get_providers(address.clone()).await;
+        let peer_ids = self.client.get_providers(address.clone()).await?;
 
         let mut peer_queue = VecDeque::with_capacity(peer_ids.len());
 
@@ -75,7 +176,7 @@ impl<T: DB + Sync + Send + 'static> OpenBazaarRpc for OpenBazaarRpcService<T> {
         for peer_id in peer_ids.clone() {
             let client = self.client.clone();
             let handle = tokio::spawn(async move {
-                let nodedata = client.get_clear_address(peer_id).await.unwrap();
+                let nodedata = client.get_clear_address(peer_id).await;
                 (peer_id, nodedata)
             });
             peer_queue.push_back(handle);
@@ -84,14 +185,22 @@ impl<T: DB + Sync + Send + 'static> OpenBazaarRpc for OpenBazaarRpcService<T> {
         let mut clear_peers = HashMap::with_capacity(peer_queue.len());
 
         for found_peer in peer_queue {
-            let (peer_id, nodedata) = found_peer.await.unwrap();
-            clear_peers.insert(peer_id, nodedata);
+            let (peer_id, nodedata) = found_peer
+                .await
+                .map_err(|e| Status::internal(e.to_string()))?;
+            match nodedata {
+                Ok(nodedata) => {
+                    clear_peers.insert(peer_id, nodedata);
+                }
+                // A provider without a reachable address is left out of the response
+                Err(e) => trace!("No clear address for provider {}: {}", peer_id, e),
+            }
         }
 
         // Iterate through the peers and get their clear addresses and return them as NodeLocationResponses
         let response_addresses = peer_ids
             .iter()
-            .map(|peer_id| clear_peers.get(peer_id).unwrap())
+            .filter_map(|peer_id| clear_peers.get(peer_id))
             .map(|nodedata| NodeLocationResponse {
                 address_type: nodedata.address_type.into(),
                 address: nodedata.address.clone(),
@@ -113,29 +222,57 @@ impl<T: DB + Sync + Send + 'static> OpenBazaarRpc for OpenBazaarRpcService<T> {
         let client_clone = self.client.clone();
 
         let request_data = request.into_inner();
-        let addr = request_data.address.clone(); // pull out address to save message in DHT at
+        let hash = request_data.hash_content()?;
+        event!(Level::DEBUG, "Calculated Hash");
+
+        // Without an address chosen by the caller the content id is the storage and DHT key
+        let addr = if request_data.address.is_empty() {
+            hash.clone()
+        } else {
+            request_data.address.clone()
+        };
+
+        // A content id given as the address has to match the content it is stored with
+        let content_addressed = content::is_content_id(&addr);
+        if content_addressed {
+            content::verify(&addr, &request_data.content)
+                .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        }
+
+        // Identical content is already stored and announced under its content id
+        if content_addressed
+            && self
+                .dbconn
+                .get_message(&addr)
+                .await
+                .map_err(|e| Status::internal(e.to_string()))?
+                .is_some()
+        {
+            event!(Level::DEBUG, "Content already stored");
+            return Ok(Response::new(SaveMessageResponse { hash }));
+        }
+
+        // Saved first, so the message can be served as soon as it is announced
+        self.dbconn
+            .save_message(&addr, &request_data.content)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        event!(Level::DEBUG, "Saved to DB");
+
+        let dht_addr = addr.clone();
 
         // Spawn a task to propagate the message to the DHT
         let dht = tokio::spawn(async move {
-            println!("Propagating {:?}", addr);
+            println!("Propagating {:?}", dht_addr);
             event!(Level::DEBUG, "Propagating to DHT");
-            client_clone.start_providing(addr).await;
+            let result = client_clone.start_providing(dht_addr).await;
             event!(Level::DEBUG, "Propagated to DHT");
+            result
         });
 
-        let hash = request_data.hash_content();
-        event!(Level::DEBUG, "Calculated Hash");
-        let reply = SaveMessageResponse { hash: hash };
+        let reply = SaveMessageResponse { hash };
         event!(Level::DEBUG, "Formulated Response");
-        dht.await.unwrap();
-
-        // Save the message to the database
-        self.dbconn
-            .save_message(&request_data.address, &request_data.content)
-            .await
-            .expect("Error saving message");
-
-        event!(Level::DEBUG, "Saved to DB");
+        dht.await.map_err(|e| Status::internal(e.to_string()))??;
 
         Ok(Response::new(reply))
     }
@@ -150,23 +287,139 @@ impl<T: DB + Sync + Send + 'static> OpenBazaarRpc for OpenBazaarRpcService<T> {
 
         let request_data = request.into_inner();
 
-        let content = self
+        let stored = self
             .dbconn
             .get_message(&request_data.address)
             .await
-            .expect("Didn't get message")
-            .expect("Empty");
+            .map_err(|e| Status::internal(e.to_string()))?;
+        let content = match stored {
+            Some(content) => content,
+            None => self.fetch_message(&request_data.address).await?,
+        };
+
+        // Content stored under its content id must still hash to it
+        let hash_type = match content::hash_algorithm(&request_data.address) {
+            Ok(algorithm) => {
+                content::verify(&request_data.address, &content)
+                    .map_err(|e| Status::data_loss(e.to_string()))?;
+                Some(HashType::from(algorithm).into())
+            }
+            Err(_) => None,
+        };
 
         event!(Level::DEBUG, "Saved to DB");
 
         let response = GetMessageResponse {
             address: request_data.address.clone(),
             content: content.clone(),
+            hash_type,
         };
 
         Ok(Response::new(response))
     }
 
+    #[instrument(skip(self, request))]
+    async fn put_content(
+        &self,
+        request: Request<Streaming<PutContentRequest>>,
+    ) -> Result<Response<PutContentResponse>, Status> {
+        event!(Level::INFO, "Processing Request");
+
+        let mut stream = request.into_inner();
+        let mut chunker = Chunker::default();
+        let mut manifest = Manifest::default();
+        let mut algorithm = None;
+
+        while let Some(message) = stream.message().await? {
+            let algorithm = match algorithm {
+                Some(algorithm) => algorithm,
+                None => *algorithm.insert(requested_algorithm(message.hash_type)?),
+            };
+            for chunk in chunker.push(&message.data) {
+                let cid = manifest.add_chunk(&chunk, algorithm);
+                self.dbconn
+                    .save_chunk(&cid, &chunk)
+                    .await
+                    .map_err(|e| Status::internal(e.to_string()))?;
+            }
+        }
+        let algorithm = algorithm.unwrap_or_default();
+        if let Some(chunk) = chunker.finish() {
+            let cid = manifest.add_chunk(&chunk, algorithm);
+            self.dbconn
+                .save_chunk(&cid, &chunk)
+                .await
+                .map_err(|e| Status::internal(e.to_string()))?;
+        }
+        event!(Level::DEBUG, "Saved {} chunks", manifest.links.len());
+
+        // Only the manifest is announced, its providers serve the chunks as well
+        let hash = manifest.id(algorithm);
+        self.dbconn
+            .save_message(&hash, &manifest.encode())
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        self.client.start_providing(hash.clone()).await?;
+
+        Ok(Response::new(PutContentResponse {
+            hash,
+            size: manifest.size(),
+            chunks: manifest.links.len() as u32,
+        }))
+    }
+
+    #[instrument(skip(self, request))]
+    async fn get_content(
+        &self,
+        request: Request<GetContentRequest>,
+    ) -> Result<Response<Self::GetContentStream>, Status> {
+        event!(Level::INFO, "Processing Request");
+
+        let request_data = request.into_inner();
+        if !content::is_manifest_id(&request_data.hash) {
+            return Err(Status::invalid_argument("Not the hash of chunked content"));
+        }
+
+        let stored = self
+            .dbconn
+            .get_message(&request_data.hash)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        let encoded = match stored {
+            Some(encoded) => encoded,
+            None => self.fetch_message(&request_data.hash).await?,
+        };
+        let manifest = content::verify(&request_data.hash, &encoded)
+            .and_then(|()| Manifest::decode(&encoded))
+            .map_err(|e| Status::data_loss(e.to_string()))?;
+
+        let (links, skip) = match manifest.locate(request_data.offset) {
+            Some((index, skip)) => (manifest.links[index..].to_vec(), skip),
+            None if request_data.offset == manifest.size() => (Vec::new(), 0),
+            None => {
+                return Err(Status::out_of_range(
+                    "Offset is past the end of the content",
+                ))
+            }
+        };
+
+        let download = ContentDownload {
+            client: self.client.clone(),
+            dbconn: self.dbconn.clone(),
+            manifest_id: request_data.hash,
+            links: links.into_iter(),
+            offset: request_data.offset,
+            skip,
+            providers: None,
+        };
+        let stream = futures::stream::unfold(download, |mut download| async move {
+            let chunk = download.next_chunk().await?;
+            Some((chunk, download))
+        });
+
+        Ok(Response::new(Box::pin(stream)))
+    }
+
     #[instrument(skip(self))]
     async fn get_profile(
         &self,
@@ -236,20 +489,975 @@ impl<T: DB + Sync + Send + 'static> OpenBazaarRpc for OpenBazaarRpcService<T> {
         let client_clone = self.client.clone();
 
         let response = GetPeerIdResponse {
-            id: client_clone.get_peer_id().await.to_string(),
+            id: client_clone.get_peer_id().await?.to_string(),
+        };
+
+        Ok(Response::new(response))
+    }
+
+    async fn get_node_info(
+        &self,
+        _: Request<GetNodeInfoRequest>,
+    ) -> Result<Response<GetNodeInfoResponse>, Status> {
+        event!(Level::INFO, "Processing Get Node Info Request");
+
+        let info = self.client.get_node_info().await?;
+        let response = GetNodeInfoResponse {
+            peer_id: info.peer_id.to_string(),
+            listen_addresses: info
+                .listen_addresses
+                .iter()
+                .map(|a| a.to_string())
+                .collect(),
+            external_addresses: info
+                .external_addresses
+                .iter()
+                .map(|a| a.to_string())
+                .collect(),
+            protocol_version: info.protocol_version,
+            agent_version: info.agent_version,
+            uptime: info.uptime.as_secs(),
+            connected_peers: info.connected_peers as u32,
+            routing_table_size: info.routing_table_size as u32,
         };
 
         Ok(Response::new(response))
     }
+
+    async fn list_peers(
+        &self,
+        _: Request<ListPeersRequest>,
+    ) -> Result<Response<ListPeersResponse>, Status> {
+        event!(Level::INFO, "Processing List Peers Request");
+
+        let peers = self
+            .client
+            .list_peers()
+            .await?
+            .into_iter()
+            .map(|peer| Peer {
+                id: peer.peer_id.to_string(),
+                addresses: peer.addresses.iter().map(|a| a.to_string()).collect(),
+                last_seen: peer.last_seen,
+                failures: peer.failures,
+            })
+            .collect();
+
+        Ok(Response::new(ListPeersResponse { peers }))
+    }
+
+    async fn list_banned_peers(
+        &self,
+        _: Request<ListBannedPeersRequest>,
+    ) -> Result<Response<ListBannedPeersResponse>, Status> {
+        event!(Level::INFO, "Processing List Banned Peers Request");
+
+        let peers = self
+            .client
+            .list_banned_peers()
+            .await?
+            .into_iter()
+            .map(|peer| BannedPeer {
+                id: peer.peer_id.to_string(),
+                banned_until: peer.until,
+                reason: peer.reason,
+            })
+            .collect();
+
+        Ok(Response::new(ListBannedPeersResponse { peers }))
+    }
+
+    async fn unban_peer(
+        &self,
+        request: Request<UnbanPeerRequest>,
+    ) -> Result<Response<UnbanPeerResponse>, Status> {
+        event!(Level::INFO, "Processing Unban Peer Request");
+
+        let peer_id: PeerId = request
+            .into_inner()
+            .id
+            .parse()
+            .map_err(|_| Status::invalid_argument("Invalid peer id"))?;
+        self.client.unban_peer(peer_id).await?;
+
+        Ok(Response::new(UnbanPeerResponse {}))
+    }
+
+    #[instrument(skip(self, request))]
+    async fn subscribe(
+        &self,
+        request: Request<SubscribeRequest>,
+    ) -> Result<Response<Self::SubscribeStream>, Status> {
+        let topic = request.into_inner().topic;
+        event!(Level::INFO, "Subscribing to {}", topic);
+
+        // Listen before joining the topic so no message is missed in between
+        let events = self.client.events();
+        self.client.subscribe(topic.clone()).await?;
+        let subscription = Subscription {
+            client: self.client.clone(),
+            topic,
+        };
+
+        // The subscription lives in the stream state and leaves the topic when the client
+        // hangs up
+        let stream = futures::stream::unfold(
+            (events, subscription),
+            |(mut events, subscription)| async move {
+                loop {
+                    match events.recv().await {
+                        Ok(Event::PubSubMessage {
+                            topic: message_topic,
+                            source,
+                            data,
+                        }) if message_topic == subscription.topic => {
+                            let message = PubSubMessage {
+                                topic: message_topic,
+                                source: source.to_string(),
+                                data,
+                            };
+                            return Some((Ok(message), (events, subscription)));
+                        }
+                        Ok(_) => {}
+                        Err(RecvError::Lagged(skipped)) => {
+                            trace!("Subscriber lagged behind by {} events", skipped);
+                        }
+                        Err(RecvError::Closed) => return None,
+                    }
+                }
+            },
+        );
+
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    #[instrument(skip(self, request))]
+    async fn publish(
+        &self,
+        request: Request<PublishRequest>,
+    ) -> Result<Response<PublishResponse>, Status> {
+        event!(Level::INFO, "Processing Publish Request");
+
+        let request_data = request.into_inner();
+        self.client
+            .publish(request_data.topic, request_data.data)
+            .await?;
+
+        Ok(Response::new(PublishResponse {}))
+    }
+
+    #[instrument(skip(self, _request))]
+    async fn watch_network_events(
+        &self,
+        _request: Request<WatchNetworkEventsRequest>,
+    ) -> Result<Response<Self::WatchNetworkEventsStream>, Status> {
+        event!(Level::INFO, "Watching network events");
+
+        let events = self.client.events();
+        let stream = futures::stream::unfold(events, |mut events| async move {
+            loop {
+                match events.recv().await {
+                    Ok(event) => {
+                        if let Some(event) = network_event(event) {
+                            let event = NetworkEvent {
+                                timestamp: unix_now().as_secs(),
+                                event: Some(event),
+                            };
+                            return Some((Ok(event), events));
+                        }
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        trace!("Network event watcher lagged behind by {} events", skipped);
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        });
+
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn get_new_address(
+        &self,
+        _: Request<GetNewAddressRequest>,
+    ) -> Result<Response<GetNewAddressResponse>, Status> {
+        event!(Level::INFO, "Processing Get New Address Request");
+
+        let address = self.wallet.get_new_address().await?;
+        Ok(Response::new(GetNewAddressResponse {
+            address: address.to_string(),
+        }))
+    }
+
+    async fn get_balance(
+        &self,
+        _: Request<GetBalanceRequest>,
+    ) -> Result<Response<GetBalanceResponse>, Status> {
+        event!(Level::INFO, "Processing Get Balance Request");
+
+        let balance = self.wallet.balance().await;
+        Ok(Response::new(GetBalanceResponse {
+            confirmed: balance.confirmed,
+            pending: balance.trusted_pending + balance.untrusted_pending,
+            immature: balance.immature,
+        }))
+    }
+
+    async fn list_transactions(
+        &self,
+        request: Request<ListTransactionsRequest>,
+    ) -> Result<Response<ListTransactionsResponse>, Status> {
+        event!(Level::INFO, "Processing List Transactions Request");
+
+        let request_data = request.into_inner();
+        let limit = match request_data.limit {
+            0 => DEFAULT_TRANSACTION_PAGE,
+            limit => limit as usize,
+        };
+        let (transactions, total) = self
+            .wallet
+            .list_transactions(request_data.offset as usize, limit)
+            .await;
+
+        let transactions = transactions
+            .into_iter()
+            .map(|tx| {
+                let (height, time) = match tx.confirmation_time {
+                    ConfirmationTime::Confirmed { height, time } => (Some(height), Some(time)),
+                    ConfirmationTime::Unconfirmed => (None, None),
+                };
+                WalletTransaction {
+                    txid: tx.txid.to_string(),
+                    received: tx.received,
+                    sent: tx.sent,
+                    fee: tx.fee,
+                    confirmation_height: height,
+                    confirmation_time: time,
+                }
+            })
+            .collect();
+
+        Ok(Response::new(ListTransactionsResponse {
+            transactions,
+            total: total as u32,
+        }))
+    }
+
+    async fn estimate_fee(
+        &self,
+        request: Request<EstimateFeeRequest>,
+    ) -> Result<Response<EstimateFeeResponse>, Status> {
+        event!(Level::INFO, "Processing Estimate Fee Request");
+
+        let target_blocks = request.into_inner().target_blocks as usize;
+        let sat_per_vbyte = self.wallet.estimate_fee(target_blocks).await?;
+        Ok(Response::new(EstimateFeeResponse { sat_per_vbyte }))
+    }
+
+    #[instrument(skip(self, request))]
+    async fn send(&self, request: Request<SendRequest>) -> Result<Response<SendResponse>, Status> {
+        event!(Level::INFO, "Processing Send Request");
+
+        let request_data = request.into_inner();
+        if !request_data.fee_rate.is_finite() || request_data.fee_rate < 0.0 {
+            return Err(Status::invalid_argument("Invalid fee rate"));
+        }
+        let fee_rate = (request_data.fee_rate > 0.0).then_some(request_data.fee_rate);
+
+        let txid = self
+            .wallet
+            .send(
+                &request_data.address,
+                request_data.amount,
+                fee_rate,
+                request_data.rbf,
+            )
+            .await?;
+        Ok(Response::new(SendResponse {
+            txid: txid.to_string(),
+        }))
+    }
+
+    async fn get_escrow_key(
+        &self,
+        _: Request<GetEscrowKeyRequest>,
+    ) -> Result<Response<GetEscrowKeyResponse>, Status> {
+        event!(Level::INFO, "Processing Get Escrow Key Request");
+
+        Ok(Response::new(GetEscrowKeyResponse {
+            key: self.escrows.key().to_string(),
+        }))
+    }
+
+    #[instrument(skip(self, request))]
+    async fn open_escrow(
+        &self,
+        request: Request<OpenEscrowRequest>,
+    ) -> Result<Response<OpenEscrowResponse>, Status> {
+        event!(Level::INFO, "Processing Open Escrow Request");
+
+        let request_data = request.into_inner();
+        let address = self
+            .escrows
+            .open(
+                &request_data.order_id,
+                request_data.role().into(),
+                &request_data.buyer_key,
+                &request_data.vendor_key,
+                &request_data.moderator_key,
+            )
+            .await?;
+        Ok(Response::new(OpenEscrowResponse {
+            address: address.to_string(),
+        }))
+    }
+
+    #[instrument(skip(self, request))]
+    async fn get_escrow(
+        &self,
+        request: Request<GetEscrowRequest>,
+    ) -> Result<Response<GetEscrowResponse>, Status> {
+        event!(Level::INFO, "Processing Get Escrow Request");
+
+        let order_id = request.into_inner().order_id;
+        let record = self.escrows.escrow(&order_id).await?;
+        let balance = self.escrows.balance(&order_id).await?;
+
+        let payouts = self
+            .escrows
+            .payouts(&order_id)
+            .await?
+            .into_iter()
+            .map(|psbt| EscrowPayout {
+                txid: psbt.unsigned_tx.txid().to_string(),
+                outputs: psbt
+                    .unsigned_tx
+                    .output
+                    .iter()
+                    .map(|output| EscrowOutput {
+                        address: Address::from_script(
+                            &output.script_pubkey,
+                            self.escrows.network(),
+                        )
+                        .map_or_else(|| format!("{:x}", output.script_pubkey), |a| a.to_string()),
+                        amount: output.value,
+                    })
+                    .collect(),
+                signatures: escrow::signature_count(&psbt) as u32,
+            })
+            .collect();
+
+        Ok(Response::new(GetEscrowResponse {
+            address: record.address,
+            role: EscrowRoleMessage::from(record.role).into(),
+            confirmed: balance.confirmed,
+            pending: balance.trusted_pending + balance.untrusted_pending,
+            payouts,
+            payout_txid: record.payout_txid,
+        }))
+    }
+
+    #[instrument(skip(self, request))]
+    async fn propose_escrow_payout(
+        &self,
+        request: Request<ProposeEscrowPayoutRequest>,
+    ) -> Result<Response<ProposeEscrowPayoutResponse>, Status> {
+        event!(Level::INFO, "Processing Propose Escrow Payout Request");
+
+        let request_data = request.into_inner();
+        if !request_data.fee_rate.is_finite() || request_data.fee_rate < 0.0 {
+            return Err(Status::invalid_argument("Invalid fee rate"));
+        }
+        let fee_rate = (request_data.fee_rate > 0.0).then_some(request_data.fee_rate);
+        let peer_id: PeerId = request_data
+            .peer_id
+            .parse()
+            .map_err(|_| Status::invalid_argument("Invalid peer id"))?;
+
+        let psbt = self
+            .escrows
+            .propose_payout(&request_data.order_id, &request_data.address, fee_rate)
+            .await?;
+        let txid = psbt.unsigned_tx.txid();
+        self.escrows
+            .send_payout(&self.client, peer_id, &request_data.order_id, &txid)
+            .await?;
+        Ok(Response::new(ProposeEscrowPayoutResponse {
+            txid: txid.to_string(),
+        }))
+    }
+
+    #[instrument(skip(self, request))]
+    async fn approve_escrow_payout(
+        &self,
+        request: Request<ApproveEscrowPayoutRequest>,
+    ) -> Result<Response<ApproveEscrowPayoutResponse>, Status> {
+        event!(Level::INFO, "Processing Approve Escrow Payout Request");
+
+        let request_data = request.into_inner();
+        let txid: Txid = request_data
+            .txid
+            .parse()
+            .map_err(|_| Status::invalid_argument("Invalid txid"))?;
+        let txid = self.escrows.approve(&request_data.order_id, &txid).await?;
+        Ok(Response::new(ApproveEscrowPayoutResponse {
+            txid: txid.to_string(),
+        }))
+    }
+
+    #[instrument(skip(self, request))]
+    async fn reject_escrow_payout(
+        &self,
+        request: Request<RejectEscrowPayoutRequest>,
+    ) -> Result<Response<RejectEscrowPayoutResponse>, Status> {
+        event!(Level::INFO, "Processing Reject Escrow Payout Request");
+
+        let request_data = request.into_inner();
+        let txid: Txid = request_data
+            .txid
+            .parse()
+            .map_err(|_| Status::invalid_argument("Invalid txid"))?;
+        self.escrows
+            .reject_payout(&request_data.order_id, &txid)
+            .await?;
+        Ok(Response::new(RejectEscrowPayoutResponse {}))
+    }
+}
+
+/// A `Subscribe` stream's share of a pub/sub topic, given up when the stream is dropped.
+struct Subscription {
+    client: Client,
+    topic: String,
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        // The runtime may already be gone when the server shuts down
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let client = self.client.clone();
+        let topic = std::mem::take(&mut self.topic);
+        runtime.spawn(async move {
+            if let Err(e) = client.unsubscribe(topic.clone()).await {
+                trace!("Failed to unsubscribe from {}: {}", topic, e);
+            }
+        });
+    }
+}
+
+/// Asks a single provider for `address`, dialing its clear address if the network can't
+/// reach it otherwise.
+async fn fetch_from(client: &Client, peer_id: PeerId, address: &[u8]) -> Option<Vec<u8>> {
+    match client
+        .fetch_block(peer_id, Vec::new(), address.to_vec())
+        .await
+    {
+        Ok(content) => return content,
+        Err(e) => trace!("Fetching from provider {} failed: {}", peer_id, e),
+    }
+
+    let nodedata = match client.get_clear_address(peer_id).await {
+        Ok(nodedata) => nodedata,
+        Err(e) => {
+            trace!("No clear address for provider {}: {}", peer_id, e);
+            return None;
+        }
+    };
+    let addr = nodedata.address.parse().ok()?;
+    match client
+        .fetch_block(peer_id, vec![addr], address.to_vec())
+        .await
+    {
+        Ok(content) => content,
+        Err(e) => {
+            trace!("Fetching from provider {} failed: {}", peer_id, e);
+            None
+        }
+    }
+}
+
+/// Chunks still to be streamed by `get_content`.
+///
+/// Chunks missing locally are fetched from the providers of the manifest and cached for as long
+/// as fetched messages, so an interrupted transfer picks up from the first chunk it didn't get.
+struct ContentDownload<T> {
+    client: Client,
+    dbconn: T,
+    manifest_id: Vec<u8>,
+    links: std::vec::IntoIter<Link>,
+    offset: u64,
+    // Bytes of the next chunk before the offset the download was asked to start at
+    skip: usize,
+    providers: Option<Vec<PeerId>>,
+}
+
+impl<T: DB> ContentDownload<T> {
+    async fn next_chunk(&mut self) -> Option<Result<GetContentResponse, Status>> {
+        let link = self.links.next()?;
+        let chunk = match self.load_chunk(&link).await {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                // Nothing is sent after an error
+                self.links = Vec::new().into_iter();
+                return Some(Err(e));
+            }
+        };
+
+        let data = chunk[self.skip.min(chunk.len())..].to_vec();
+        let response = GetContentResponse {
+            offset: self.offset,
+            data,
+        };
+        self.offset += response.data.len() as u64;
+        self.skip = 0;
+        Some(Ok(response))
+    }
+
+    async fn load_chunk(&mut self, link: &Link) -> Result<Vec<u8>, Status> {
+        let chunk = self.find_chunk(link).await?;
+        // Offsets into the content are worked out from the sizes the manifest lists
+        if chunk.len() as u64 != link.size {
+            return Err(Status::data_loss("Chunk size does not match the manifest"));
+        }
+        Ok(chunk)
+    }
+
+    async fn find_chunk(&mut self, link: &Link) -> Result<Vec<u8>, Status> {
+        let stored = self
+            .dbconn
+            .get_chunk(&link.cid)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        let cached = self
+            .dbconn
+            .get_cached_chunk(&link.cid)
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?;
+        for chunk in stored.into_iter().chain(cached) {
+            // A damaged chunk is fetched again and cached
+            match content::verify(&link.cid, &chunk) {
+                Ok(()) => return Ok(chunk),
+                Err(e) => trace!("Stored chunk failed verification: {}", e),
+            }
+        }
+
+        if self.providers.is_none() {
+            let local_peer_id = self.client.get_peer_id().await?;
+            let providers = match self.client.get_providers(self.manifest_id.clone()).await {
+                Ok(providers) => providers,
+                Err(NetworkError::Timeout | NetworkError::NotFound) => Default::default(),
+                Err(e) => return Err(e.into()),
+            };
+            self.providers = Some(
+                providers
+                    .into_iter()
+                    .filter(|p| *p != local_peer_id)
+                    .collect(),
+            );
+        }
+        let providers = self
+            .providers
+            .as_mut()
+            .ok_or_else(|| Status::unavailable("No providers known for the content"))?;
+
+        for i in 0..providers.len() {
+            let peer_id = providers[i];
+            let chunk = match fetch_from(&self.client, peer_id, &link.cid).await {
+                Some(chunk) => chunk,
+                None => continue,
+            };
+            if let Err(e) = content::verify(&link.cid, &chunk) {
+                trace!("Provider {} sent an invalid chunk: {}", peer_id, e);
+                continue;
+            }
+            // The provider that answered is asked first for the following chunks
+            providers.swap(0, i);
+            self.dbconn
+                .cache_chunk(&link.cid, &chunk, REMOTE_MESSAGE_TTL)
+                .await
+                .map_err(|e| Status::internal(e.to_string()))?;
+            return Ok(chunk);
+        }
+
+        Err(Status::not_found("Chunk not available from any provider"))
+    }
+}
+
+/// Converts the network events shown to watchers, messages are left to `Subscribe`.
+fn network_event(event: Event) -> Option<network_event::Event> {
+    let event = match event {
+        Event::PeerConnected { peer, address } => {
+            network_event::Event::PeerConnected(PeerConnected {
+                peer: peer.to_string(),
+                address: address.to_string(),
+            })
+        }
+        Event::PeerDisconnected { peer } => {
+            network_event::Event::PeerDisconnected(PeerDisconnected {
+                peer: peer.to_string(),
+            })
+        }
+        Event::ListenAddressAdded { address } => {
+            network_event::Event::ListenAddressAdded(ListenAddressAdded {
+                address: address.to_string(),
+            })
+        }
+        Event::RecordPublished { key } => {
+            network_event::Event::RecordPublished(RecordPublished { key })
+        }
+        Event::QueryCompleted { kind, success } => {
+            network_event::Event::QueryCompleted(QueryCompleted {
+                query_type: QueryType::from(kind).into(),
+                success,
+            })
+        }
+        Event::BootstrapFinished {
+            routing_table_size,
+            success,
+        } => network_event::Event::BootstrapFinished(BootstrapFinished {
+            routing_table_size: routing_table_size as u32,
+            success,
+        }),
+        Event::DirectMessage { .. } | Event::PubSubMessage { .. } => return None,
+    };
+    Some(event)
+}
+
+impl From<QueryKind> for QueryType {
+    fn from(kind: QueryKind) -> Self {
+        match kind {
+            QueryKind::Bootstrap => QueryType::Bootstrap,
+            QueryKind::GetClosestPeers => QueryType::GetClosestPeers,
+            QueryKind::GetProviders => QueryType::GetProviders,
+            QueryKind::StartProviding => QueryType::StartProviding,
+            QueryKind::RepublishProvider => QueryType::RepublishProvider,
+            QueryKind::GetRecord => QueryType::GetRecord,
+            QueryKind::PutRecord => QueryType::PutRecord,
+            QueryKind::RepublishRecord => QueryType::RepublishRecord,
+        }
+    }
+}
+
+impl From<WalletError> for Status {
+    fn from(error: WalletError) -> Self {
+        let message = error.to_string();
+        match error {
+            WalletError::InvalidArgument(_) => Status::invalid_argument(message),
+            WalletError::InsufficientFunds { .. } => Status::failed_precondition(message),
+            WalletError::ChainSource(_) => Status::unavailable(message),
+            WalletError::Other(_) => Status::internal(message),
+        }
+    }
+}
+
+impl From<NetworkError> for Status {
+    fn from(error: NetworkError) -> Self {
+        let message = error.to_string();
+        match error {
+            NetworkError::Timeout => Status::deadline_exceeded(message),
+            NetworkError::NoPeers | NetworkError::QuorumFailed => Status::unavailable(message),
+            NetworkError::NotFound => Status::not_found(message),
+            NetworkError::EventLoopGone => Status::unavailable(message),
+            NetworkError::Rejected(_) => Status::failed_precondition(message),
+            NetworkError::Other(_) => Status::internal(message),
+        }
+    }
 }
 
 impl SaveMessageRequest {
-    pub fn hash_content(&self) -> Vec<u8> {
-        let content = &self.content;
-        let mut hasher = Sha3_256::new();
-        hasher.update(content);
-        let hash = hasher.finalize();
-        println!("Hash: {:?}", hash);
-        hash.to_vec()
+    /// Returns the content id of the message hashed with the requested algorithm.
+    pub fn hash_content(&self) -> Result<Vec<u8>, Status> {
+        let algorithm = requested_algorithm(self.hash_type)?;
+        Ok(content::content_id(&self.content, algorithm))
+    }
+}
+
+/// The hash function a request asks for, values outside `HashType` are rejected rather than
+/// read as the default.
+fn requested_algorithm(hash_type: Option<i32>) -> Result<HashAlgorithm, Status> {
+    let Some(hash_type) = hash_type else {
+        return Ok(HashAlgorithm::default());
+    };
+    HashType::from_i32(hash_type)
+        .map(HashAlgorithm::from)
+        .ok_or_else(|| Status::invalid_argument(format!("Unknown hash type {}", hash_type)))
+}
+
+impl From<HashType> for HashAlgorithm {
+    fn from(hash_type: HashType) -> Self {
+        match hash_type {
+            HashType::Sha2256 => HashAlgorithm::Sha2_256,
+            HashType::Sha3256 => HashAlgorithm::Sha3_256,
+            HashType::Sha2512 => HashAlgorithm::Sha2_512,
+            HashType::Blake3 => HashAlgorithm::Blake3,
+        }
+    }
+}
+
+impl From<HashAlgorithm> for HashType {
+    fn from(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Sha2_256 => HashType::Sha2256,
+            HashAlgorithm::Sha3_256 => HashType::Sha3256,
+            HashAlgorithm::Sha2_512 => HashType::Sha2512,
+            HashAlgorithm::Blake3 => HashType::Blake3,
+        }
+    }
+}
+
+impl From<EscrowRoleMessage> for EscrowRole {
+    fn from(role: EscrowRoleMessage) -> Self {
+        match role {
+            EscrowRoleMessage::Buyer => EscrowRole::Buyer,
+            EscrowRoleMessage::Vendor => EscrowRole::Vendor,
+            EscrowRoleMessage::Moderator => EscrowRole::Moderator,
+        }
+    }
+}
+
+impl From<EscrowRole> for EscrowRoleMessage {
+    fn from(role: EscrowRole) -> Self {
+        match role {
+            EscrowRole::Buyer => EscrowRoleMessage::Buyer,
+            EscrowRole::Vendor => EscrowRoleMessage::Vendor,
+            EscrowRole::Moderator => EscrowRoleMessage::Moderator,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::generate_mnemonic;
+    use crate::dag::CHUNK_SIZE;
+    use crate::db::OpenBazaarDb;
+    use crate::openbazaar::open_bazaar_rpc_client::OpenBazaarRpcClient;
+    use crate::openbazaar::open_bazaar_rpc_server::OpenBazaarRpcServer;
+    use crate::wallet::{open_wallet, WalletConfig};
+    use bdk::bitcoin::Network;
+    use libp2p::identity::Keypair;
+    use tonic::transport::{Channel, Server};
+
+    /// Starts a node listening on a local port and serves its API on another one.
+    async fn start_node(name: &str, db: OpenBazaarDb) -> (Client, OpenBazaarRpcClient<Channel>) {
+        let (mut client, mut event_loop) =
+            crate::network::new(Keypair::generate_ed25519(), &db, Default::default())
+                .await
+                .unwrap();
+        tokio::spawn(async move { event_loop.run().await });
+        client
+            .start_listening("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .await
+            .unwrap();
+
+        let data_dir =
+            std::env::temp_dir().join(format!("openbazaar-api-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&data_dir);
+        let data_dir = data_dir.to_str().unwrap();
+        let mnemonic = generate_mnemonic();
+        let wallet = open_wallet(&mnemonic, data_dir, Network::Regtest).unwrap();
+        let wallet = WalletService::new(wallet, WalletConfig::default()).unwrap();
+        let escrows =
+            EscrowService::new(db.clone(), &mnemonic, Network::Regtest, data_dir, &wallet).unwrap();
+        let service = OpenBazaarRpcService::new(client.clone(), db, wallet, escrows);
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let incoming = futures::stream::unfold(listener, |listener| async move {
+            let stream = listener.accept().await.map(|(stream, _)| stream);
+            Some((stream, listener))
+        });
+        tokio::spawn(
+            Server::builder()
+                .add_service(OpenBazaarRpcServer::new(service))
+                .serve_with_incoming(incoming),
+        );
+        let api = OpenBazaarRpcClient::connect(format!("http://{}", addr))
+            .await
+            .unwrap();
+        (client, api)
+    }
+
+    async fn download(
+        api: &mut OpenBazaarRpcClient<Channel>,
+        hash: &[u8],
+        offset: u64,
+    ) -> Result<Vec<u8>, Status> {
+        let request = GetContentRequest {
+            hash: hash.to_vec(),
+            offset,
+        };
+        let mut stream = api.get_content(request).await?.into_inner();
+        let mut data = Vec::new();
+        while let Some(response) = stream.message().await? {
+            assert_eq!(response.offset, offset + data.len() as u64);
+            data.extend(response.data);
+        }
+        Ok(data)
+    }
+
+    #[test]
+    fn unknown_hash_types_are_rejected() {
+        let algorithm = requested_algorithm(Some(HashType::Blake3.into())).unwrap();
+        assert_eq!(algorithm, HashAlgorithm::Blake3);
+        let algorithm = requested_algorithm(Some(HashType::Sha2512.into())).unwrap();
+        assert_eq!(algorithm, HashAlgorithm::Sha2_512);
+        assert_eq!(requested_algorithm(None).unwrap(), HashAlgorithm::Sha3_256);
+
+        // Numbers of the hash types that are no longer supported
+        for hash_type in [1, 2, 42] {
+            let status = requested_algorithm(Some(hash_type)).unwrap_err();
+            assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        }
+    }
+
+    #[tokio::test]
+    async fn content_download_resumes_from_offset() {
+        let (provider, mut provider_api) = start_node("provider", OpenBazaarDb::temporary()).await;
+        let fetcher_db = OpenBazaarDb::temporary();
+        let (mut fetcher, mut fetcher_api) = start_node("fetcher", fetcher_db.clone()).await;
+
+        let addr = crate::network::wait_for_listen_addr(&provider).await;
+        let provider_peer_id = provider.get_peer_id().await.unwrap();
+        fetcher.dial(provider_peer_id, addr).await.unwrap();
+
+        // No two chunks are alike, so each has a content id of its own
+        let content: Vec<u8> = (0..2 * CHUNK_SIZE + 1000)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let requests: Vec<_> = content
+            .chunks(100_000)
+            .map(|data| PutContentRequest {
+                data: data.to_vec(),
+                hash_type: Some(HashType::Sha2256.into()),
+            })
+            .collect();
+        let put = provider_api
+            .put_content(futures::stream::iter(requests))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(put.size, content.len() as u64);
+        assert_eq!(put.chunks, 3);
+
+        // Resume partway into the second chunk, the provider is found once identify has run
+        let offset = CHUNK_SIZE as u64 + 10;
+        let mut resumed = None;
+        for _ in 0..50 {
+            if let Ok(data) = download(&mut fetcher_api, &put.hash, offset).await {
+                resumed = Some(data);
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        let resumed = resumed.expect("Fetcher to download the content");
+        assert_eq!(resumed, content[offset as usize..]);
+
+        // Only the chunks from the offset on were fetched
+        let manifest = fetcher_db.get_cached_message(&put.hash).await.unwrap();
+        let manifest = Manifest::decode(&manifest.unwrap()).unwrap();
+        let cached = fetcher_db
+            .get_cached_chunk(&manifest.links[0].cid)
+            .await
+            .unwrap();
+        assert_eq!(cached, None);
+        for link in &manifest.links[1..] {
+            let cached = fetcher_db.get_cached_chunk(&link.cid).await.unwrap();
+            assert!(cached.is_some());
+        }
+
+        let data = download(&mut fetcher_api, &put.hash, 0).await.unwrap();
+        assert_eq!(data, content);
+    }
+
+    #[tokio::test]
+    async fn node_info_describes_the_running_node() {
+        let (node, mut node_api) = start_node("info-node", OpenBazaarDb::temporary()).await;
+        let (mut peer, _peer_api) = start_node("info-peer", OpenBazaarDb::temporary()).await;
+        let node_peer_id = node.get_peer_id().await.unwrap();
+        let addr = crate::network::wait_for_listen_addr(&node).await;
+
+        let info = node_api
+            .get_node_info(GetNodeInfoRequest {})
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(info.peer_id, node_peer_id.to_string());
+        assert_eq!(info.listen_addresses, vec![addr.to_string()]);
+        assert_eq!(info.protocol_version, crate::network::PROTOCOL_VERSION);
+        assert_eq!(info.agent_version, crate::network::AGENT_VERSION);
+        assert_eq!((info.connected_peers, info.routing_table_size), (0, 0));
+
+        peer.dial(node_peer_id, addr).await.unwrap();
+        let mut counts = None;
+        for _ in 0..50 {
+            let info = node_api
+                .get_node_info(GetNodeInfoRequest {})
+                .await
+                .unwrap()
+                .into_inner();
+            counts = Some((info.connected_peers, info.routing_table_size));
+            if counts == Some((1, 1)) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(counts, Some((1, 1)));
+    }
+
+    #[tokio::test]
+    async fn wallet_rpcs_round_trip() {
+        let (_client, mut api) = start_node("wallet", OpenBazaarDb::temporary()).await;
+
+        let first = api
+            .get_new_address(GetNewAddressRequest {})
+            .await
+            .unwrap()
+            .into_inner()
+            .address;
+        let second = api
+            .get_new_address(GetNewAddressRequest {})
+            .await
+            .unwrap()
+            .into_inner()
+            .address;
+        assert_ne!(first, second);
+        let address: Address = first.parse().unwrap();
+        assert!(address.is_valid_for_network(Network::Regtest));
+
+        let balance = api
+            .get_balance(GetBalanceRequest {})
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(
+            (balance.confirmed, balance.pending, balance.immature),
+            (0, 0, 0)
+        );
+        let page = api
+            .list_transactions(ListTransactionsRequest {
+                offset: 0,
+                limit: 0,
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(page.transactions.is_empty());
+        assert_eq!(page.total, 0);
+
+        let send = |address: &str| SendRequest {
+            address: address.to_string(),
+            amount: 10_000,
+            fee_rate: 1.0,
+            rbf: true,
+        };
+        let status = api.send(send(&first)).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+        let status = api.send(send("not an address")).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        let mainnet = "bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq";
+        let status = api.send(send(mainnet)).await.unwrap_err();
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
     }
 }
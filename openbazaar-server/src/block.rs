@@ -0,0 +1,32 @@
+use libp2p::request_response::ProtocolName;
+
+use crate::codec::{ProtobufCodec, ProtobufProtocol};
+use crate::openbazaar_direct::{BlockRequest, BlockResponse};
+
+pub const BLOCK_PROTOCOL: &str = "/openbazaar/3.0.0/block";
+
+// Upper bound on a single block, matching the largest message the gRPC API accepts
+const MAX_BLOCK_SIZE: usize = 4 * 1024 * 1024;
+
+// Requests only carry an address
+const MAX_REQUEST_SIZE: usize = 1024;
+
+#[derive(Debug, Clone)]
+pub struct BlockProtocol();
+
+impl ProtocolName for BlockProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        BLOCK_PROTOCOL.as_bytes()
+    }
+}
+
+impl ProtobufProtocol for BlockProtocol {
+    type Request = BlockRequest;
+    type Response = BlockResponse;
+
+    const MAX_REQUEST_SIZE: usize = MAX_REQUEST_SIZE;
+    const MAX_RESPONSE_SIZE: usize = MAX_BLOCK_SIZE;
+}
+
+/// Codec for fetching stored content from the peers providing it.
+pub type BlockCodec = ProtobufCodec<BlockProtocol>;
@@ -0,0 +1,79 @@
+use async_trait::async_trait;
+use futures::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use libp2p::core::upgrade::{read_length_prefixed, write_length_prefixed};
+use libp2p::request_response::{self, ProtocolName};
+use prost::Message;
+use std::io;
+use std::marker::PhantomData;
+
+/// A request-response protocol exchanging one protobuf message each way.
+pub trait ProtobufProtocol: ProtocolName + Clone + Send + Sync + 'static {
+    type Request: Message + Default;
+    type Response: Message + Default;
+
+    /// Upper bound on an encoded request, in bytes.
+    const MAX_REQUEST_SIZE: usize;
+    /// Upper bound on an encoded response, in bytes.
+    const MAX_RESPONSE_SIZE: usize;
+}
+
+/// Length-prefixed protobuf codec for the protocol `P`.
+pub struct ProtobufCodec<P>(PhantomData<P>);
+
+impl<P> Default for ProtobufCodec<P> {
+    fn default() -> Self {
+        Self(PhantomData)
+    }
+}
+
+impl<P> Clone for ProtobufCodec<P> {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl<P: ProtobufProtocol> request_response::Codec for ProtobufCodec<P> {
+    type Protocol = P;
+    type Request = P::Request;
+    type Response = P::Response;
+
+    async fn read_request<T>(&mut self, _: &P, io: &mut T) -> io::Result<Self::Request>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let bytes = read_length_prefixed(io, P::MAX_REQUEST_SIZE).await?;
+        P::Request::decode(bytes.as_slice())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    async fn read_response<T>(&mut self, _: &P, io: &mut T) -> io::Result<Self::Response>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let bytes = read_length_prefixed(io, P::MAX_RESPONSE_SIZE).await?;
+        P::Response::decode(bytes.as_slice())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    async fn write_request<T>(&mut self, _: &P, io: &mut T, request: P::Request) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_length_prefixed(io, request.encode_to_vec()).await?;
+        io.close().await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &P,
+        io: &mut T,
+        response: P::Response,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_length_prefixed(io, response.encode_to_vec()).await?;
+        io.close().await
+    }
+}
@@ -0,0 +1,39 @@
+use serde::Deserialize;
+use std::path::Path;
+
+/// Settings read from the TOML file passed with `--config`.
+///
+/// Every key is optional, command-line flags take precedence over the file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Multiaddrs of the peers used to join the network, replacing the built-in ones. An empty
+    /// list leaves the node to find peers through `--mdns` or peers dialing it.
+    pub bootstrap_nodes: Option<Vec<String>>,
+    /// Established connections across all peers
+    pub max_connections: Option<u32>,
+    /// Established connections to a single peer
+    pub max_connections_per_peer: Option<u32>,
+    /// Inbound connections from a single IP address
+    pub max_connections_per_ip: Option<u32>,
+    /// Bitcoin network: mainnet, testnet, signet or regtest
+    pub network: Option<String>,
+    /// Esplora HTTP API the wallet syncs against
+    pub esplora_url: Option<String>,
+    /// Seconds between wallet syncs, at least 1
+    pub wallet_sync_interval: Option<u64>,
+    /// Unused addresses scanned past the last used one
+    pub wallet_stop_gap: Option<usize>,
+}
+
+impl Config {
+    pub fn load(path: Option<&Path>) -> anyhow::Result<Self> {
+        match path {
+            Some(path) => {
+                let contents = std::fs::read_to_string(path)?;
+                Ok(toml::from_str(&contents)?)
+            }
+            None => Ok(Self::default()),
+        }
+    }
+}
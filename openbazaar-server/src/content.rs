@@ -0,0 +1,202 @@
+use libp2p::multihash::MultihashGeneric;
+use sha2::{Sha256, Sha512};
+use sha3::{Digest, Sha3_256};
+
+type Multihash = MultihashGeneric<64>;
+
+// Multicodec codes, see https://github.com/multiformats/multicodec
+const CID_V1: u8 = 0x01;
+const RAW: u8 = 0x55;
+const DAG_PB: u8 = 0x70;
+const SHA2_256: u64 = 0x12;
+const SHA2_512: u64 = 0x13;
+const SHA3_256: u64 = 0x16;
+const BLAKE3: u64 = 0x1e;
+
+/// Hash functions content ids can be computed with.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    Sha2_256,
+    #[default]
+    Sha3_256,
+    Sha2_512,
+    Blake3,
+}
+
+impl HashAlgorithm {
+    fn code(self) -> u64 {
+        match self {
+            HashAlgorithm::Sha2_256 => SHA2_256,
+            HashAlgorithm::Sha3_256 => SHA3_256,
+            HashAlgorithm::Sha2_512 => SHA2_512,
+            HashAlgorithm::Blake3 => BLAKE3,
+        }
+    }
+
+    fn from_code(code: u64) -> Option<Self> {
+        match code {
+            SHA2_256 => Some(HashAlgorithm::Sha2_256),
+            SHA3_256 => Some(HashAlgorithm::Sha3_256),
+            SHA2_512 => Some(HashAlgorithm::Sha2_512),
+            BLAKE3 => Some(HashAlgorithm::Blake3),
+            _ => None,
+        }
+    }
+
+    fn digest(self, content: &[u8]) -> Vec<u8> {
+        match self {
+            HashAlgorithm::Sha2_256 => Sha256::digest(content).to_vec(),
+            HashAlgorithm::Sha3_256 => Sha3_256::digest(content).to_vec(),
+            HashAlgorithm::Sha2_512 => Sha512::digest(content).to_vec(),
+            HashAlgorithm::Blake3 => blake3::hash(content).as_bytes().to_vec(),
+        }
+    }
+
+    fn digest_size(self) -> u8 {
+        match self {
+            HashAlgorithm::Sha2_512 => 64,
+            _ => 32,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ContentError {
+    #[error("Not a content id")]
+    InvalidId,
+    #[error("Unsupported hash function {0:#x}")]
+    UnsupportedHash(u64),
+    #[error("Content does not match its content id")]
+    Mismatch,
+    #[error("Invalid chunk manifest")]
+    InvalidManifest,
+}
+
+/// Returns the content id of `content` hashed with `algorithm`.
+///
+/// This is a binary CIDv1 with the raw codec over a multihash, so the same
+/// content always ends up under the same storage and DHT key.
+pub fn content_id(content: &[u8], algorithm: HashAlgorithm) -> Vec<u8> {
+    cid(RAW, content, algorithm)
+}
+
+/// Returns the content id of an encoded chunk manifest, see `dag::Manifest`.
+///
+/// Manifests use the dag-pb codec so they can be told apart from raw content.
+pub fn manifest_id(manifest: &[u8], algorithm: HashAlgorithm) -> Vec<u8> {
+    cid(DAG_PB, manifest, algorithm)
+}
+
+fn cid(codec: u8, content: &[u8], algorithm: HashAlgorithm) -> Vec<u8> {
+    let digest = algorithm.digest(content);
+    let multihash = Multihash::wrap(algorithm.code(), &digest).expect("Digest fits a multihash");
+
+    let mut cid = vec![CID_V1, codec];
+    cid.extend_from_slice(&multihash.to_bytes());
+    cid
+}
+
+/// Returns true if `address` is a content id rather than a caller-chosen address.
+pub fn is_content_id(address: &[u8]) -> bool {
+    hash_algorithm(address).is_ok()
+}
+
+/// Returns true if `cid` is the content id of a chunk manifest.
+pub fn is_manifest_id(cid: &[u8]) -> bool {
+    matches!(cid, [CID_V1, DAG_PB, ..]) && multihash_of(cid).is_ok()
+}
+
+/// Returns the hash function `cid` was computed with.
+pub fn hash_algorithm(cid: &[u8]) -> Result<HashAlgorithm, ContentError> {
+    multihash_of(cid).map(|(algorithm, _)| algorithm)
+}
+
+/// Checks that `content` hashes to `cid`.
+pub fn verify(cid: &[u8], content: &[u8]) -> Result<(), ContentError> {
+    let (algorithm, multihash) = multihash_of(cid)?;
+
+    if multihash.digest() == algorithm.digest(content).as_slice() {
+        Ok(())
+    } else {
+        Err(ContentError::Mismatch)
+    }
+}
+
+fn multihash_of(cid: &[u8]) -> Result<(HashAlgorithm, Multihash), ContentError> {
+    match cid {
+        [CID_V1, RAW | DAG_PB, multihash @ ..] => {
+            let multihash =
+                Multihash::from_bytes(multihash).map_err(|_| ContentError::InvalidId)?;
+            let algorithm = HashAlgorithm::from_code(multihash.code())
+                .ok_or(ContentError::UnsupportedHash(multihash.code()))?;
+            // A truncated digest can't be told apart from a wrong one
+            if multihash.size() != algorithm.digest_size() {
+                return Err(ContentError::InvalidId);
+            }
+            Ok((algorithm, multihash))
+        }
+        _ => Err(ContentError::InvalidId),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    const ALGORITHMS: [HashAlgorithm; 4] = [
+        HashAlgorithm::Sha2_256,
+        HashAlgorithm::Sha3_256,
+        HashAlgorithm::Sha2_512,
+        HashAlgorithm::Blake3,
+    ];
+
+    #[test]
+    fn content_id_is_stable_and_verifies() {
+        let content = b"openbazaar listing";
+        for algorithm in ALGORITHMS {
+            let cid = content_id(content, algorithm);
+
+            assert_eq!(cid, content_id(content, algorithm));
+            assert_eq!(hash_algorithm(&cid).unwrap(), algorithm);
+            assert!(verify(&cid, content).is_ok());
+            assert!(matches!(
+                verify(&cid, b"tampered listing"),
+                Err(ContentError::Mismatch)
+            ));
+        }
+    }
+
+    #[test]
+    fn algorithms_give_distinct_content_ids() {
+        let content = b"openbazaar listing";
+        let cids: HashSet<_> = ALGORITHMS
+            .iter()
+            .map(|algorithm| content_id(content, *algorithm))
+            .collect();
+        assert_eq!(cids.len(), ALGORITHMS.len());
+    }
+
+    #[test]
+    fn manifest_ids_differ_from_content_ids() {
+        let manifest = b"encoded manifest";
+        let cid = manifest_id(manifest, HashAlgorithm::default());
+
+        assert!(is_manifest_id(&cid));
+        assert!(verify(&cid, manifest).is_ok());
+        assert!(!is_manifest_id(&content_id(
+            manifest,
+            HashAlgorithm::default()
+        )));
+    }
+
+    #[test]
+    fn caller_chosen_addresses_are_not_content_ids() {
+        assert!(!is_content_id(b"my-listing"));
+        assert!(!is_content_id(&[]));
+        assert!(matches!(
+            verify(b"my-listing", b"content"),
+            Err(ContentError::InvalidId)
+        ));
+    }
+}
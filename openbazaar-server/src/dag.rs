@@ -0,0 +1,207 @@
+use prost::Message;
+use std::mem;
+
+use crate::content::{self, ContentError, HashAlgorithm};
+use crate::openbazaar_direct::{DagLink, DagNode};
+
+/// Size of every chunk of a piece of content except the last one.
+pub const CHUNK_SIZE: usize = 256 * 1024;
+
+/// Splits content arriving in pieces of any size into fixed-size chunks.
+#[derive(Debug, Default)]
+pub struct Chunker {
+    buffer: Vec<u8>,
+}
+
+impl Chunker {
+    /// Adds `data`, returning the chunks it completed.
+    pub fn push(&mut self, mut data: &[u8]) -> Vec<Vec<u8>> {
+        let mut chunks = Vec::new();
+        while !data.is_empty() {
+            let take = (CHUNK_SIZE - self.buffer.len()).min(data.len());
+            self.buffer.extend_from_slice(&data[..take]);
+            data = &data[take..];
+
+            if self.buffer.len() == CHUNK_SIZE {
+                chunks.push(mem::take(&mut self.buffer));
+            }
+        }
+        chunks
+    }
+
+    /// Returns the last, shorter chunk if any bytes are left over.
+    pub fn finish(self) -> Option<Vec<u8>> {
+        (!self.buffer.is_empty()).then_some(self.buffer)
+    }
+}
+
+/// A chunk as listed in a manifest.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Link {
+    pub cid: Vec<u8>,
+    pub size: u64,
+}
+
+/// The chunks making up a piece of content, in order.
+///
+/// The manifest is stored and provided under its own content id, which covers the content id
+/// of every chunk, so verifying each chunk against its link verifies the whole content.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Manifest {
+    pub links: Vec<Link>,
+}
+
+impl Manifest {
+    /// Appends `chunk` and returns its content id.
+    pub fn add_chunk(&mut self, chunk: &[u8], algorithm: HashAlgorithm) -> Vec<u8> {
+        let cid = content::content_id(chunk, algorithm);
+        self.links.push(Link {
+            cid: cid.clone(),
+            size: chunk.len() as u64,
+        });
+        cid
+    }
+
+    /// Total size of the content in bytes.
+    pub fn size(&self) -> u64 {
+        self.links.iter().map(|link| link.size).sum()
+    }
+
+    /// Returns the index of the chunk holding byte `offset` and the position of that byte
+    /// within the chunk, or `None` past the end of the content.
+    pub fn locate(&self, offset: u64) -> Option<(usize, usize)> {
+        let mut start = 0;
+        for (index, link) in self.links.iter().enumerate() {
+            if offset < start + link.size {
+                return Some((index, (offset - start) as usize));
+            }
+            start += link.size;
+        }
+        None
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        DagNode {
+            data: Vec::new(),
+            links: self
+                .links
+                .iter()
+                .map(|link| DagLink {
+                    hash: link.cid.clone(),
+                    name: String::new(),
+                    tsize: link.size,
+                })
+                .collect(),
+        }
+        .encode_to_vec()
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, ContentError> {
+        let node = DagNode::decode(bytes).map_err(|_| ContentError::InvalidManifest)?;
+        let mut total: u64 = 0;
+        let links = node
+            .links
+            .into_iter()
+            .map(|link| {
+                // Manifests only ever point at raw chunks, never at other manifests
+                if !content::is_content_id(&link.hash) || content::is_manifest_id(&link.hash) {
+                    return Err(ContentError::InvalidManifest);
+                }
+                // Chunks are never empty or bigger than the chunker makes them
+                if link.tsize == 0 || link.tsize > CHUNK_SIZE as u64 {
+                    return Err(ContentError::InvalidManifest);
+                }
+                total = total
+                    .checked_add(link.tsize)
+                    .ok_or(ContentError::InvalidManifest)?;
+                Ok(Link {
+                    cid: link.hash,
+                    size: link.tsize,
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Manifest { links })
+    }
+
+    /// Returns the content id the manifest is stored and provided under.
+    pub fn id(&self, algorithm: HashAlgorithm) -> Vec<u8> {
+        content::manifest_id(&self.encode(), algorithm)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk_all(pieces: &[&[u8]]) -> Vec<Vec<u8>> {
+        let mut chunker = Chunker::default();
+        let mut chunks: Vec<_> = pieces
+            .iter()
+            .flat_map(|piece| chunker.push(piece))
+            .collect();
+        chunks.extend(chunker.finish());
+        chunks
+    }
+
+    #[test]
+    fn chunks_do_not_depend_on_how_content_arrives() {
+        let content: Vec<u8> = (0..CHUNK_SIZE * 2 + 100).map(|i| i as u8).collect();
+
+        let whole = chunk_all(&[&content]);
+        let pieces: Vec<&[u8]> = content.chunks(1000).collect();
+        assert_eq!(whole, chunk_all(&pieces));
+
+        let sizes: Vec<_> = whole.iter().map(Vec::len).collect();
+        assert_eq!(sizes, vec![CHUNK_SIZE, CHUNK_SIZE, 100]);
+        assert_eq!(whole.concat(), content);
+    }
+
+    #[test]
+    fn manifest_round_trips_and_locates_offsets() {
+        let mut manifest = Manifest::default();
+        for chunk in chunk_all(&[vec![7; CHUNK_SIZE + 10].as_slice()]) {
+            let cid = manifest.add_chunk(&chunk, HashAlgorithm::default());
+            assert!(content::verify(&cid, &chunk).is_ok());
+        }
+
+        let encoded = manifest.encode();
+        assert_eq!(Manifest::decode(&encoded).unwrap(), manifest);
+        assert!(content::verify(&manifest.id(HashAlgorithm::default()), &encoded).is_ok());
+
+        assert_eq!(manifest.size(), CHUNK_SIZE as u64 + 10);
+        assert_eq!(manifest.locate(0), Some((0, 0)));
+        assert_eq!(manifest.locate(CHUNK_SIZE as u64 + 3), Some((1, 3)));
+        assert_eq!(manifest.locate(manifest.size()), None);
+    }
+
+    #[test]
+    fn manifests_cannot_link_other_manifests() {
+        let nested = Manifest {
+            links: vec![Link {
+                cid: Manifest::default().id(HashAlgorithm::default()),
+                size: 1,
+            }],
+        };
+        assert!(matches!(
+            Manifest::decode(&nested.encode()),
+            Err(ContentError::InvalidManifest)
+        ));
+    }
+
+    #[test]
+    fn manifests_with_impossible_chunk_sizes_are_rejected() {
+        let cid = content::content_id(b"chunk", HashAlgorithm::default());
+        for size in [0, CHUNK_SIZE as u64 + 1, u64::MAX] {
+            let manifest = Manifest {
+                links: vec![Link {
+                    cid: cid.clone(),
+                    size,
+                }],
+            };
+            assert!(matches!(
+                Manifest::decode(&manifest.encode()),
+                Err(ContentError::InvalidManifest)
+            ));
+        }
+    }
+}
@@ -1,7 +1,21 @@
 use crate::crypto::{self, generate_mnemonic};
+use crate::escrow::EscrowRecord;
 use crate::profile::Profile;
 use async_trait::async_trait;
 use sled;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Messages fetched from other nodes, kept apart from our own so they are never provided
+const MESSAGE_CACHE_TREE: &str = "message_cache";
+
+// Chunks of large content, keyed by content id and served to any peer asking for them
+const CHUNKS_TREE: &str = "chunks";
+
+// Chunks fetched from other nodes, kept apart from our own like fetched messages
+const CHUNK_CACHE_TREE: &str = "chunk_cache";
+
+// Escrows of moderated orders we are a party to, keyed by order id
+const ESCROWS_TREE: &str = "escrows";
 
 #[async_trait]
 pub trait DB {
@@ -14,14 +28,44 @@ pub trait DB {
         -> anyhow::Result<Option<Vec<u8>>>;
     async fn get_message(&self, address: &[u8]) -> anyhow::Result<Option<Vec<u8>>>;
     async fn remove_message(&self, address: &[u8]) -> anyhow::Result<Option<Vec<u8>>>;
+    async fn cache_message(
+        &self,
+        address: &[u8],
+        content: &[u8],
+        ttl: Duration,
+    ) -> anyhow::Result<()>;
+    async fn get_cached_message(&self, address: &[u8]) -> anyhow::Result<Option<Vec<u8>>>;
+    async fn save_chunk(&self, cid: &[u8], chunk: &[u8]) -> anyhow::Result<()>;
+    async fn get_chunk(&self, cid: &[u8]) -> anyhow::Result<Option<Vec<u8>>>;
+    async fn cache_chunk(&self, cid: &[u8], chunk: &[u8], ttl: Duration) -> anyhow::Result<()>;
+    async fn get_cached_chunk(&self, cid: &[u8]) -> anyhow::Result<Option<Vec<u8>>>;
     async fn get_profile(&self) -> anyhow::Result<Option<crate::profile::Profile>>;
     async fn set_profile(&self, profile: &Profile) -> anyhow::Result<()>;
+    async fn get_onion_key(&self) -> anyhow::Result<Option<String>>;
+    async fn set_onion_key(&self, key: &str) -> anyhow::Result<()>;
+    async fn get_network(&self) -> anyhow::Result<Option<String>>;
+    async fn set_network(&self, network: &str) -> anyhow::Result<()>;
+    async fn save_escrow(&self, escrow: &EscrowRecord) -> anyhow::Result<()>;
+    async fn get_escrow(&self, order_id: &str) -> anyhow::Result<Option<EscrowRecord>>;
+    async fn get_escrows(&self) -> anyhow::Result<Vec<EscrowRecord>>;
 }
 #[derive(Clone, Debug)]
 pub struct OpenBazaarDb {
     pub db: sled::Db,
 }
 
+impl OpenBazaarDb {
+    /// A database that only lives in memory, for tests.
+    #[cfg(test)]
+    pub fn temporary() -> Self {
+        let db = sled::Config::new()
+            .temporary(true)
+            .open()
+            .expect("Failed to open temporary db");
+        OpenBazaarDb { db }
+    }
+}
+
 #[async_trait]
 impl DB for OpenBazaarDb {
     async fn new(db_file: String) -> anyhow::Result<Self> {
@@ -73,6 +117,42 @@ impl DB for OpenBazaarDb {
         Ok(prev_val.map(|e| e.to_vec()))
     }
 
+    async fn cache_message(
+        &self,
+        address: &[u8],
+        content: &[u8],
+        ttl: Duration,
+    ) -> anyhow::Result<()> {
+        cache_entry(
+            &self.db.open_tree(MESSAGE_CACHE_TREE)?,
+            address,
+            content,
+            ttl,
+        )
+    }
+
+    async fn get_cached_message(&self, address: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+        cached_entry(&self.db.open_tree(MESSAGE_CACHE_TREE)?, address)
+    }
+
+    async fn save_chunk(&self, cid: &[u8], chunk: &[u8]) -> anyhow::Result<()> {
+        self.db.open_tree(CHUNKS_TREE)?.insert(cid, chunk)?;
+        Ok(())
+    }
+
+    async fn get_chunk(&self, cid: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+        let chunk = self.db.open_tree(CHUNKS_TREE)?.get(cid)?;
+        Ok(chunk.map(|e| e.to_vec()))
+    }
+
+    async fn cache_chunk(&self, cid: &[u8], chunk: &[u8], ttl: Duration) -> anyhow::Result<()> {
+        cache_entry(&self.db.open_tree(CHUNK_CACHE_TREE)?, cid, chunk, ttl)
+    }
+
+    async fn get_cached_chunk(&self, cid: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+        cached_entry(&self.db.open_tree(CHUNK_CACHE_TREE)?, cid)
+    }
+
     async fn get_profile(&self) -> anyhow::Result<Option<Profile>> {
         let profile = self.db.get(b"profile")?;
         Ok(profile.map(|e| bincode::deserialize(&e.to_vec()).unwrap()))
@@ -84,4 +164,77 @@ impl DB for OpenBazaarDb {
             .insert(b"profile", bincode::serialize(profile).unwrap())?;
         Ok(())
     }
+
+    async fn get_onion_key(&self) -> anyhow::Result<Option<String>> {
+        let key = self.db.get(b"onion_key")?;
+        Ok(key.map(|e| String::from_utf8_lossy(&e).to_string()))
+    }
+
+    async fn set_onion_key(&self, key: &str) -> anyhow::Result<()> {
+        self.db.insert(b"onion_key", key.as_bytes())?;
+        Ok(())
+    }
+
+    async fn get_network(&self) -> anyhow::Result<Option<String>> {
+        let network = self.db.get(b"bitcoin_network")?;
+        Ok(network.map(|e| String::from_utf8_lossy(&e).to_string()))
+    }
+
+    async fn set_network(&self, network: &str) -> anyhow::Result<()> {
+        self.db.insert(b"bitcoin_network", network.as_bytes())?;
+        Ok(())
+    }
+
+    async fn save_escrow(&self, escrow: &EscrowRecord) -> anyhow::Result<()> {
+        self.db
+            .open_tree(ESCROWS_TREE)?
+            .insert(escrow.order_id.as_bytes(), bincode::serialize(escrow)?)?;
+        Ok(())
+    }
+
+    async fn get_escrow(&self, order_id: &str) -> anyhow::Result<Option<EscrowRecord>> {
+        let escrow = self.db.open_tree(ESCROWS_TREE)?.get(order_id.as_bytes())?;
+        Ok(escrow.map(|e| bincode::deserialize(&e)).transpose()?)
+    }
+
+    async fn get_escrows(&self) -> anyhow::Result<Vec<EscrowRecord>> {
+        let mut escrows = Vec::new();
+        for entry in self.db.open_tree(ESCROWS_TREE)?.iter() {
+            let (_, escrow) = entry?;
+            escrows.push(bincode::deserialize(&escrow)?);
+        }
+        Ok(escrows)
+    }
+}
+
+/// Stores `content` under `key` in a cache tree along with when it expires.
+fn cache_entry(tree: &sled::Tree, key: &[u8], content: &[u8], ttl: Duration) -> anyhow::Result<()> {
+    let expires = (SystemTime::now() + ttl)
+        .duration_since(UNIX_EPOCH)?
+        .as_secs();
+    let mut entry = expires.to_be_bytes().to_vec();
+    entry.extend_from_slice(content);
+    tree.insert(key, entry)?;
+    Ok(())
+}
+
+fn cached_entry(tree: &sled::Tree, key: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+    let Some(entry) = tree.get(key)? else {
+        return Ok(None);
+    };
+    // Entries too short to hold an expiry are corrupt, treat them as a miss
+    if entry.len() < 8 {
+        tree.remove(key)?;
+        return Ok(None);
+    }
+    let (expires, content) = entry.split_at(8);
+    let expires = u64::from_be_bytes(expires.try_into()?);
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+    // Expired entries are dropped on the first read past their TTL
+    if expires <= now {
+        tree.remove(key)?;
+        return Ok(None);
+    }
+    Ok(Some(content.to_vec()))
 }
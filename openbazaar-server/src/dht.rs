@@ -0,0 +1,174 @@
+#![allow(deprecated)]
+
+use libp2p::core::Endpoint;
+use libp2p::kad::handler::KademliaHandlerEvent;
+use libp2p::kad::record::store::RecordStore;
+use libp2p::kad::{InboundRequest, Kademlia, KademliaEvent, ProviderRecord};
+use libp2p::swarm::{
+    ConnectionDenied, ConnectionId, FromSwarm, NetworkBehaviour, PollParameters, THandler,
+    THandlerInEvent, THandlerOutEvent, ToSwarm,
+};
+use libp2p::Multiaddr;
+use libp2p_identity::PeerId;
+use std::collections::VecDeque;
+use std::ops::{Deref, DerefMut};
+use std::task::{Context, Poll};
+
+/// `Kademlia` that also reports which peer sent each inbound query.
+///
+/// Kademlia answers FindNode, GetProviders and GetRecord requests by itself
+/// and its `InboundRequest` events for them carry no source, so without this
+/// a peer could flood the DHT with queries without ever being rate limited.
+///
+/// The sources are read from `KademliaHandlerEvent`, which libp2p 0.51
+/// deprecates and 0.52 makes private, so this is tied to libp2p 0.51.
+pub struct Dht<TStore> {
+    kademlia: Kademlia<TStore>,
+    inbound_queries: VecDeque<PeerId>,
+    provider_sources: VecDeque<PeerId>,
+}
+
+#[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
+pub enum Event {
+    Kademlia(KademliaEvent),
+    /// A peer sent a FindNode, GetProviders or GetRecord request.
+    InboundQuery {
+        source: PeerId,
+    },
+    /// A peer asked us to store a provider record.
+    AddProvider {
+        source: PeerId,
+        record: ProviderRecord,
+    },
+}
+
+impl<TStore> Dht<TStore> {
+    pub fn new(kademlia: Kademlia<TStore>) -> Self {
+        Self {
+            kademlia,
+            inbound_queries: VecDeque::new(),
+            provider_sources: VecDeque::new(),
+        }
+    }
+}
+
+impl<TStore> Deref for Dht<TStore> {
+    type Target = Kademlia<TStore>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.kademlia
+    }
+}
+
+impl<TStore> DerefMut for Dht<TStore> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.kademlia
+    }
+}
+
+impl<TStore> NetworkBehaviour for Dht<TStore>
+where
+    TStore: RecordStore + Send + 'static,
+{
+    type ConnectionHandler = <Kademlia<TStore> as NetworkBehaviour>::ConnectionHandler;
+    type OutEvent = Event;
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        local_addr: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        self.kademlia.handle_established_inbound_connection(
+            connection_id,
+            peer,
+            local_addr,
+            remote_addr,
+        )
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        addr: &Multiaddr,
+        role_override: Endpoint,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        self.kademlia.handle_established_outbound_connection(
+            connection_id,
+            peer,
+            addr,
+            role_override,
+        )
+    }
+
+    fn handle_pending_outbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        maybe_peer: Option<PeerId>,
+        addresses: &[Multiaddr],
+        effective_role: Endpoint,
+    ) -> Result<Vec<Multiaddr>, ConnectionDenied> {
+        self.kademlia.handle_pending_outbound_connection(
+            connection_id,
+            maybe_peer,
+            addresses,
+            effective_role,
+        )
+    }
+
+    fn on_swarm_event(&mut self, event: FromSwarm<Self::ConnectionHandler>) {
+        self.kademlia.on_swarm_event(event)
+    }
+
+    fn on_connection_handler_event(
+        &mut self,
+        peer_id: PeerId,
+        connection_id: ConnectionId,
+        event: THandlerOutEvent<Self>,
+    ) {
+        if matches!(
+            event,
+            KademliaHandlerEvent::FindNodeReq { .. }
+                | KademliaHandlerEvent::GetProvidersReq { .. }
+                | KademliaHandlerEvent::GetRecord { .. }
+        ) {
+            self.inbound_queries.push_back(peer_id);
+        }
+        // Kademlia drops provider records not sent by the provider itself and
+        // reports every other one, in the order they arrive
+        if let KademliaHandlerEvent::AddProvider { provider, .. } = &event {
+            if provider.node_id == peer_id {
+                self.provider_sources.push_back(peer_id);
+            }
+        }
+        self.kademlia
+            .on_connection_handler_event(peer_id, connection_id, event)
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+        params: &mut impl PollParameters,
+    ) -> Poll<ToSwarm<Self::OutEvent, THandlerInEvent<Self>>> {
+        if let Some(source) = self.inbound_queries.pop_front() {
+            return Poll::Ready(ToSwarm::GenerateEvent(Event::InboundQuery { source }));
+        }
+        self.kademlia.poll(cx, params).map(|action| {
+            action.map_out(|event| match event {
+                KademliaEvent::InboundRequest {
+                    request:
+                        InboundRequest::AddProvider {
+                            record: Some(record),
+                        },
+                } => Event::AddProvider {
+                    source: self.provider_sources.pop_front().unwrap_or(record.provider),
+                    record,
+                },
+                event => Event::Kademlia(event),
+            })
+        })
+    }
+}
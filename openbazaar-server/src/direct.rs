@@ -0,0 +1,32 @@
+use libp2p::request_response::ProtocolName;
+
+use crate::codec::{ProtobufCodec, ProtobufProtocol};
+use crate::openbazaar_direct::{DirectMessage, DirectResponse};
+
+pub const DIRECT_PROTOCOL: &str = "/openbazaar/3.0.0/direct";
+
+// Envelope version understood by this node, bumped on incompatible payload changes
+pub const DIRECT_MESSAGE_VERSION: u32 = 1;
+
+// Upper bound on a single direct message, larger content goes through the DHT
+const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct DirectProtocol();
+
+impl ProtocolName for DirectProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        DIRECT_PROTOCOL.as_bytes()
+    }
+}
+
+impl ProtobufProtocol for DirectProtocol {
+    type Request = DirectMessage;
+    type Response = DirectResponse;
+
+    const MAX_REQUEST_SIZE: usize = MAX_MESSAGE_SIZE;
+    const MAX_RESPONSE_SIZE: usize = MAX_MESSAGE_SIZE;
+}
+
+/// Codec for direct peer messages.
+pub type DirectCodec = ProtobufCodec<DirectProtocol>;
@@ -0,0 +1,877 @@
+use crate::db::DB;
+use crate::network::{Client, Event};
+use crate::openbazaar_direct::{DirectMessageType, EscrowSignature};
+use crate::wallet::{self, OpenBazaarWallet, WalletError, WalletService};
+use bdk::bitcoin::blockdata::opcodes;
+use bdk::bitcoin::blockdata::script::Builder;
+use bdk::bitcoin::consensus::encode::{deserialize, serialize};
+use bdk::bitcoin::secp256k1::{Message as SighashMessage, Secp256k1};
+use bdk::bitcoin::util::bip32::{DerivationPath, ExtendedPubKey};
+use bdk::bitcoin::util::psbt::PartiallySignedTransaction as Psbt;
+use bdk::bitcoin::util::sighash::SighashCache;
+use bdk::bitcoin::{Address, EcdsaSighashType, Network, PublicKey, Script, Transaction, Txid};
+use bdk::keys::bip39::Mnemonic;
+use bdk::keys::{DerivableKey, ExtendedKey};
+use bdk::wallet::AddressIndex;
+use bdk::{FeeRate, SignOptions, Wallet};
+use bdk_chain::keychain::Balance;
+use bdk_file_store::KeychainStore;
+use libp2p::PeerId;
+use prost::Message;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::Mutex;
+
+// Signatures out of the buyer's, vendor's and moderator's needed to spend an escrow
+pub const REQUIRED_SIGNATURES: usize = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EscrowRole {
+    Buyer,
+    Vendor,
+    Moderator,
+}
+
+/// An escrow of a moderated order as tracked in the database.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct EscrowRecord {
+    pub order_id: String,
+    /// Our part in the order.
+    pub role: EscrowRole,
+    /// Escrow keys of the parties, see `EscrowKey::public`.
+    pub buyer: String,
+    pub vendor: String,
+    pub moderator: String,
+    /// Address the buyer funds.
+    pub address: String,
+    /// Serialized PSBTs of the payouts being signed, keyed by their txid.
+    pub payouts: BTreeMap<String, Vec<u8>>,
+    /// Transaction that spent the escrow once broadcast.
+    pub payout_txid: Option<String>,
+}
+
+impl EscrowRecord {
+    fn keys(&self) -> [(EscrowRole, &str); 3] {
+        [
+            (EscrowRole::Buyer, &self.buyer),
+            (EscrowRole::Vendor, &self.vendor),
+            (EscrowRole::Moderator, &self.moderator),
+        ]
+    }
+}
+
+/// The node's BIP48 multisig account for `network`, derived from its mnemonic.
+///
+/// Keys are descriptor keys with their origin, e.g. `[d34db33f/48'/1'/0'/2']tpub...`.
+#[derive(Clone)]
+pub struct EscrowKey {
+    /// Handed to the other parties of an order.
+    pub public: String,
+    private: String,
+}
+
+impl std::fmt::Debug for EscrowKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EscrowKey")
+            .field("public", &self.public)
+            .finish()
+    }
+}
+
+pub fn escrow_key(mnemonic_words: &str, network: Network) -> anyhow::Result<EscrowKey> {
+    let mnemonic = Mnemonic::parse(mnemonic_words)?;
+    let xkey: ExtendedKey = mnemonic.into_extended_key()?;
+    let master = xkey
+        .into_xprv(network)
+        .ok_or_else(|| anyhow::anyhow!("Failed to derive the escrow private key"))?;
+
+    let secp = Secp256k1::new();
+    let coin_type = match network {
+        Network::Bitcoin => 0,
+        _ => 1,
+    };
+    // Script type 2' is P2WSH
+    let path = format!("48'/{}'/0'/2'", coin_type);
+    let account = master.derive_priv(&secp, &DerivationPath::from_str(&format!("m/{}", path))?)?;
+    let origin = format!("[{}/{}]", master.fingerprint(&secp), path);
+
+    Ok(EscrowKey {
+        public: format!("{}{}", origin, ExtendedPubKey::from_priv(&secp, &account)),
+        private: format!("{}{}", origin, account),
+    })
+}
+
+/// Every order gets its own address even between the same three parties.
+fn escrow_index(order_id: &str) -> u32 {
+    let digest = Sha256::digest(order_id.as_bytes());
+    u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]]) & 0x7fff_ffff
+}
+
+/// 2-of-3 P2WSH descriptor over the buyer's, vendor's and moderator's keys.
+///
+/// Keys are sorted within the script, so every party derives the same address whatever the
+/// order they are given in.
+pub fn escrow_descriptor(order_id: &str, keys: [&str; 3]) -> String {
+    let index = escrow_index(order_id);
+    let keys: Vec<_> = keys
+        .iter()
+        .map(|key| format!("{}/0/{}", key, index))
+        .collect();
+    format!(
+        "wsh(sortedmulti({},{}))",
+        REQUIRED_SIGNATURES,
+        keys.join(",")
+    )
+}
+
+/// Public key `key` contributes to the escrow of `order_id`, as derived by `escrow_descriptor`.
+fn escrow_pubkey(order_id: &str, key: &str) -> Result<PublicKey, WalletError> {
+    let invalid = |e: &dyn std::fmt::Display| {
+        WalletError::InvalidArgument(format!("Invalid escrow key {}: {}", key, e))
+    };
+    // Drop the key origin, the xpub is all the derivation needs
+    let xpub = key.split_once(']').map_or(key, |(_, xpub)| xpub);
+    let xpub = ExtendedPubKey::from_str(xpub).map_err(|e| invalid(&e))?;
+    let path = DerivationPath::from_str(&format!("m/0/{}", escrow_index(order_id)))
+        .map_err(|e| invalid(&e))?;
+    let child = xpub
+        .derive_pub(&Secp256k1::verification_only(), &path)
+        .map_err(|e| invalid(&e))?;
+    Ok(child.to_pub())
+}
+
+/// Witness script of the escrow, the same `sortedmulti` as `escrow_descriptor` builds.
+fn escrow_script(pubkeys: &[PublicKey]) -> Script {
+    let mut pubkeys = pubkeys.to_vec();
+    pubkeys.sort_by_key(|pubkey| pubkey.inner.serialize());
+    let builder = pubkeys.iter().fold(
+        Builder::new().push_int(REQUIRED_SIGNATURES as i64),
+        |builder, pubkey| builder.push_key(pubkey),
+    );
+    builder
+        .push_int(pubkeys.len() as i64)
+        .push_opcode(opcodes::all::OP_CHECKMULTISIG)
+        .into_script()
+}
+
+/// Checks every input of `psbt` spends the escrow and every signature on it is a valid
+/// `SIGHASH_ALL` signature of one of `signers`.
+fn verify_signatures(
+    wallet: &OpenBazaarWallet,
+    psbt: &Psbt,
+    script: &Script,
+    signers: &[PublicKey],
+) -> Result<(), WalletError> {
+    let secp = Secp256k1::verification_only();
+    let script_pubkey = Script::new_v0_p2wsh(&script.wscript_hash());
+    let mut cache = SighashCache::new(&psbt.unsigned_tx);
+    for (index, (txin, input)) in psbt.unsigned_tx.input.iter().zip(&psbt.inputs).enumerate() {
+        // The amount signed for comes from our view of the chain, not from the payout
+        let utxo = wallet
+            .get_utxo(txin.previous_output)
+            .filter(|utxo| utxo.txout.script_pubkey == script_pubkey)
+            .ok_or_else(|| {
+                WalletError::InvalidArgument(format!(
+                    "Payout spends {} which is not in the escrow",
+                    txin.previous_output
+                ))
+            })?;
+        for (pubkey, signature) in &input.partial_sigs {
+            if !signers.contains(pubkey) {
+                return Err(WalletError::InvalidArgument(format!(
+                    "Payout is signed by {} which is not a party of the escrow",
+                    pubkey
+                )));
+            }
+            if signature.hash_ty != EcdsaSighashType::All {
+                return Err(WalletError::InvalidArgument(format!(
+                    "Payout signature of {} does not commit to the whole transaction",
+                    pubkey
+                )));
+            }
+            let sighash = cache
+                .segwit_signature_hash(index, script, utxo.txout.value, signature.hash_ty)
+                .map_err(|e| anyhow::anyhow!("Failed to compute payout sighash: {}", e))?;
+            let message = SighashMessage::from_slice(&sighash[..]).map_err(anyhow::Error::from)?;
+            secp.verify_ecdsa(&message, &signature.sig, &pubkey.inner)
+                .map_err(|_| {
+                    WalletError::InvalidArgument(format!("Invalid payout signature of {}", pubkey))
+                })?;
+        }
+    }
+    Ok(())
+}
+
+/// Number of signatures every input of `psbt` has collected.
+pub fn signature_count(psbt: &Psbt) -> usize {
+    psbt.inputs
+        .iter()
+        .map(|input| input.partial_sigs.len())
+        .min()
+        .unwrap_or(0)
+}
+
+/// Adds our signature to every escrow input, leaving the PSBT open for more.
+fn sign(wallet: &OpenBazaarWallet, psbt: &mut Psbt) -> Result<(), WalletError> {
+    let options = SignOptions {
+        try_finalize: false,
+        ..Default::default()
+    };
+    wallet
+        .sign(psbt, options)
+        .map_err(|e| anyhow::anyhow!("Failed to sign payout: {:?}", e))?;
+    Ok(())
+}
+
+fn finalize(wallet: &OpenBazaarWallet, mut psbt: Psbt) -> Result<Transaction, WalletError> {
+    let signatures = signature_count(&psbt);
+    if signatures < REQUIRED_SIGNATURES {
+        return Err(WalletError::InvalidArgument(format!(
+            "Payout has {} of {} signatures",
+            signatures, REQUIRED_SIGNATURES
+        )));
+    }
+    let finalized = wallet
+        .finalize_psbt(&mut psbt, SignOptions::default())
+        .map_err(|e| anyhow::anyhow!("Failed to finalize payout: {:?}", e))?;
+    if !finalized {
+        return Err(anyhow::anyhow!("Payout signatures do not satisfy the escrow").into());
+    }
+    Ok(psbt.extract_tx())
+}
+
+fn decode_psbt(bytes: &[u8]) -> Result<Psbt, WalletError> {
+    deserialize(bytes).map_err(|e| WalletError::InvalidArgument(format!("Invalid PSBT: {}", e)))
+}
+
+/// Escrows of the moderated orders we are a party to.
+///
+/// Each escrow has a wallet of its own holding our escrow key and the other parties' public
+/// keys. A payout is proposed by one party, who signs it and sends it to another, the escrow
+/// is spent once that party approves it with the second signature. Receiving a payout never
+/// signs it.
+#[derive(Clone)]
+pub struct EscrowService<T: DB> {
+    db: T,
+    key: EscrowKey,
+    network: Network,
+    data_dir: String,
+    wallet: WalletService,
+    wallets: Arc<Mutex<HashMap<String, Arc<Mutex<OpenBazaarWallet>>>>>,
+}
+
+impl<T: DB> std::fmt::Debug for EscrowService<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EscrowService")
+            .field("key", &self.key)
+            .field("network", &self.network)
+            .finish()
+    }
+}
+
+impl<T: DB> EscrowService<T> {
+    /// Escrow wallets are kept in `escrows` under `data_dir` and sync against the same chain
+    /// source as `wallet`.
+    pub fn new(
+        db: T,
+        mnemonic_words: &str,
+        network: Network,
+        data_dir: &str,
+        wallet: &WalletService,
+    ) -> anyhow::Result<Self> {
+        let data_dir = format!("{}/escrows", data_dir);
+        std::fs::create_dir_all(&data_dir)?;
+        Ok(Self {
+            db,
+            key: escrow_key(mnemonic_words, network)?,
+            network,
+            data_dir,
+            wallet: wallet.clone(),
+            wallets: Default::default(),
+        })
+    }
+
+    pub fn network(&self) -> Network {
+        self.network
+    }
+
+    /// Our escrow key to hand to the other parties of an order.
+    pub fn key(&self) -> &str {
+        &self.key.public
+    }
+
+    /// Starts tracking the escrow of `order_id` and returns the address the buyer funds.
+    ///
+    /// Opening an escrow again with the same keys returns the same address.
+    pub async fn open(
+        &self,
+        order_id: &str,
+        role: EscrowRole,
+        buyer: &str,
+        vendor: &str,
+        moderator: &str,
+    ) -> Result<Address, WalletError> {
+        let mut record = EscrowRecord {
+            order_id: order_id.to_string(),
+            role,
+            buyer: buyer.to_string(),
+            vendor: vendor.to_string(),
+            moderator: moderator.to_string(),
+            address: String::new(),
+            payouts: BTreeMap::new(),
+            payout_txid: None,
+        };
+
+        if let Some(existing) = self.db.get_escrow(order_id).await? {
+            if existing.keys() != record.keys() || existing.role != role {
+                return Err(WalletError::InvalidArgument(format!(
+                    "Escrow of order {} is already open with other parties",
+                    order_id
+                )));
+            }
+            return Ok(Address::from_str(&existing.address).map_err(anyhow::Error::from)?);
+        }
+
+        let wallet = self.open_wallet(&record)?;
+        let address = wallet
+            .lock()
+            .await
+            .get_address(AddressIndex::Peek(0))
+            .address;
+        record.address = address.to_string();
+        self.db.save_escrow(&record).await?;
+        self.wallets
+            .lock()
+            .await
+            .insert(order_id.to_string(), wallet);
+        Ok(address)
+    }
+
+    /// The escrow of `order_id`, also once it has been paid out.
+    pub async fn escrow(&self, order_id: &str) -> Result<EscrowRecord, WalletError> {
+        self.db.get_escrow(order_id).await?.ok_or_else(|| {
+            WalletError::InvalidArgument(format!("No escrow for order {}", order_id))
+        })
+    }
+
+    /// Syncs the escrow of `order_id` with the chain and returns its balance.
+    pub async fn sync(&self, order_id: &str) -> Result<Balance, WalletError> {
+        let wallet = self.escrow_wallet(order_id).await?;
+        let client = self.wallet.chain_client();
+        wallet::sync_wallet(&wallet, &client, self.wallet.config().stop_gap)
+            .await
+            .map_err(|e| WalletError::ChainSource(e.to_string()))?;
+        let balance = wallet.lock().await.get_balance();
+        Ok(balance)
+    }
+
+    /// Balance of the escrow of `order_id` as of its last sync.
+    pub async fn balance(&self, order_id: &str) -> Result<Balance, WalletError> {
+        let wallet = self.escrow_wallet(order_id).await?;
+        let balance = wallet.lock().await.get_balance();
+        Ok(balance)
+    }
+
+    /// Syncs the escrows not paid out yet on the wallet's interval until the task is dropped.
+    pub async fn run_sync(self) {
+        loop {
+            match self.db.get_escrows().await {
+                Ok(records) => {
+                    for record in records.iter().filter(|r| r.payout_txid.is_none()) {
+                        if let Err(e) = self.sync(&record.order_id).await {
+                            tracing::warn!(
+                                "Escrow sync of order {} failed: {}",
+                                record.order_id,
+                                e
+                            );
+                        }
+                    }
+                }
+                Err(e) => tracing::warn!("Failed to load escrows: {}", e),
+            }
+            tokio::time::sleep(self.wallet.config().sync_interval).await;
+        }
+    }
+
+    /// Builds a payout of the whole escrow to `address`, signs it and keeps it until the
+    /// second signature comes in, alongside any other payout being signed.
+    ///
+    /// Paying the vendor releases the escrow, paying the buyer refunds it. In a dispute the
+    /// moderator proposes the payout to whichever party it decides for. Without a fee rate
+    /// the payout aims to confirm within `DEFAULT_CONFIRMATION_TARGET` blocks.
+    pub async fn propose_payout(
+        &self,
+        order_id: &str,
+        address: &str,
+        fee_rate: Option<f32>,
+    ) -> Result<Psbt, WalletError> {
+        let mut record = self.escrow(order_id).await?;
+        let address = Address::from_str(address)
+            .map_err(|e| WalletError::InvalidArgument(format!("Invalid address: {}", e)))?;
+        if !address.is_valid_for_network(self.network) {
+            return Err(WalletError::InvalidArgument(format!(
+                "Address is not valid on {}",
+                self.network
+            )));
+        }
+        let fee_rate = match fee_rate {
+            Some(fee_rate) => fee_rate,
+            None => {
+                self.wallet
+                    .estimate_fee(wallet::DEFAULT_CONFIRMATION_TARGET)
+                    .await?
+            }
+        };
+
+        let wallet = self.escrow_wallet(order_id).await?;
+        let mut wallet = wallet.lock().await;
+        let mut builder = wallet.build_tx();
+        builder
+            .drain_wallet()
+            .drain_to(address.script_pubkey())
+            .fee_rate(FeeRate::from_sat_per_vb(fee_rate));
+        let (mut psbt, _) = builder.finish().map_err(|e| match e {
+            bdk::Error::NoUtxosSelected => {
+                WalletError::InvalidArgument(format!("Escrow of order {} is not funded", order_id))
+            }
+            bdk::Error::InsufficientFunds { needed, available } => {
+                WalletError::InsufficientFunds { needed, available }
+            }
+            e => WalletError::Other(anyhow::anyhow!("Failed to build payout: {:?}", e)),
+        })?;
+        sign(&wallet, &mut psbt)?;
+
+        // Proposing the same payout again keeps the signatures it has collected
+        let txid = psbt.unsigned_tx.txid().to_string();
+        if let Some(payout) = record.payouts.get(&txid) {
+            let mut payout = decode_psbt(payout)?;
+            payout.combine(psbt).map_err(anyhow::Error::from)?;
+            psbt = payout;
+        }
+        record.payouts.insert(txid, serialize(&psbt));
+        self.db.save_escrow(&record).await?;
+        Ok(psbt)
+    }
+
+    /// The payouts of `order_id` being signed, to check where they pay before approving one.
+    pub async fn payouts(&self, order_id: &str) -> Result<Vec<Psbt>, WalletError> {
+        let record = self.escrow(order_id).await?;
+        record
+            .payouts
+            .values()
+            .map(|payout| decode_psbt(payout))
+            .collect()
+    }
+
+    /// Sends the payout `txid` of `order_id` with the signatures collected so far to `peer`.
+    pub async fn send_payout(
+        &self,
+        client: &Client,
+        peer: PeerId,
+        order_id: &str,
+        txid: &Txid,
+    ) -> Result<(), WalletError> {
+        let record = self.escrow(order_id).await?;
+        let Some(psbt) = record.payouts.get(&txid.to_string()).cloned() else {
+            return Err(WalletError::InvalidArgument(format!(
+                "No payout {} of order {} to send",
+                txid, order_id
+            )));
+        };
+        let message = EscrowSignature {
+            order_id: order_id.to_string(),
+            psbt,
+        };
+        client
+            .send_direct(
+                peer,
+                DirectMessageType::EscrowSignature,
+                message.encode_to_vec(),
+            )
+            .await
+            .map_err(anyhow::Error::from)?;
+        Ok(())
+    }
+
+    /// Merges a payout received from another party with the same payout we have, if any.
+    ///
+    /// Every signature on the payout must be a valid one of the buyer's, vendor's or moderator's
+    /// key, and one of our key is only accepted if it is the one we made. The payout is broadcast
+    /// as soon as it has enough signatures, until then it is kept to be approved or rejected. A
+    /// payout spending the escrow differently from ours is kept as a competing one, a new payout
+    /// without the signature of another party is rejected.
+    pub async fn receive_payout(
+        &self,
+        order_id: &str,
+        mut psbt: Psbt,
+    ) -> Result<Option<Txid>, WalletError> {
+        let mut record = self.escrow(order_id).await?;
+        if let Some(payout_txid) = &record.payout_txid {
+            return Err(WalletError::InvalidArgument(format!(
+                "Escrow of order {} is already paid out by {}",
+                order_id, payout_txid
+            )));
+        }
+        let txid = psbt.unsigned_tx.txid().to_string();
+        let payout = record
+            .payouts
+            .get(&txid)
+            .map(|payout| decode_psbt(payout))
+            .transpose()?;
+
+        // `open_wallet` makes sure the key in our role's place is ours
+        let ours = escrow_pubkey(order_id, &self.key.public)?;
+        let mut others = Vec::new();
+        for (role, key) in record.keys() {
+            if role != record.role {
+                others.push(escrow_pubkey(order_id, key)?);
+            }
+        }
+        let mut pubkeys = others.clone();
+        pubkeys.push(ours);
+        let script = escrow_script(&pubkeys);
+
+        let signed = {
+            let wallet = self.escrow_wallet(order_id).await?;
+            let wallet = wallet.lock().await;
+
+            // Our signatures come from the payout we have, the sender can only hand them back
+            for (index, input) in psbt.inputs.iter_mut().enumerate() {
+                if let Some(signature) = input.partial_sigs.remove(&ours) {
+                    let made = payout
+                        .as_ref()
+                        .and_then(|payout| payout.inputs.get(index))
+                        .and_then(|input| input.partial_sigs.get(&ours));
+                    if made != Some(&signature) {
+                        return Err(WalletError::InvalidArgument(
+                            "Payout carries a signature of our key we did not make".to_string(),
+                        ));
+                    }
+                }
+            }
+            verify_signatures(&wallet, &psbt, &script, &others)?;
+
+            match payout {
+                Some(mut payout) => {
+                    payout.combine(psbt).map_err(|e| {
+                        WalletError::InvalidArgument(format!("Payout does not match ours: {}", e))
+                    })?;
+                    psbt = payout;
+                }
+                None if signature_count(&psbt) == 0 => {
+                    return Err(WalletError::InvalidArgument(
+                        "Payout is not signed by another party of the escrow".to_string(),
+                    ));
+                }
+                None => {}
+            }
+
+            if signature_count(&psbt) >= REQUIRED_SIGNATURES {
+                Some(finalize(&wallet, psbt.clone())?)
+            } else {
+                None
+            }
+        };
+        match signed {
+            Some(tx) => self.settle(record, tx).await.map(Some),
+            None => {
+                record.payouts.insert(txid, serialize(&psbt));
+                self.db.save_escrow(&record).await?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Adds our signature to the payout `txid` of `order_id` and broadcasts it.
+    pub async fn approve(&self, order_id: &str, txid: &Txid) -> Result<Txid, WalletError> {
+        let record = self.escrow(order_id).await?;
+        let Some(payout) = record.payouts.get(&txid.to_string()) else {
+            return Err(WalletError::InvalidArgument(format!(
+                "No payout {} of order {} to approve",
+                txid, order_id
+            )));
+        };
+        let mut psbt = decode_psbt(payout)?;
+
+        let tx = {
+            let wallet = self.escrow_wallet(order_id).await?;
+            let wallet = wallet.lock().await;
+            sign(&wallet, &mut psbt)?;
+            finalize(&wallet, psbt)?
+        };
+        self.settle(record, tx).await
+    }
+
+    /// Discards the payout `txid` of `order_id`, e.g. to propose another one in its place.
+    pub async fn reject_payout(&self, order_id: &str, txid: &Txid) -> Result<(), WalletError> {
+        let mut record = self.escrow(order_id).await?;
+        if record.payouts.remove(&txid.to_string()).is_none() {
+            return Err(WalletError::InvalidArgument(format!(
+                "No payout {} of order {} to reject",
+                txid, order_id
+            )));
+        }
+        self.db.save_escrow(&record).await?;
+        Ok(())
+    }
+
+    /// Broadcasts a payout, any other payout of the escrow can no longer be spent.
+    async fn settle(&self, mut record: EscrowRecord, tx: Transaction) -> Result<Txid, WalletError> {
+        let txid = wallet::broadcast(&self.wallet.chain_client(), tx).await?;
+        record.payouts.clear();
+        record.payout_txid = Some(txid.to_string());
+        self.db.save_escrow(&record).await?;
+        Ok(txid)
+    }
+
+    /// Collects payouts sent by the other parties until the network shuts down.
+    pub async fn run(self, client: Client) {
+        let mut events = client.events();
+        loop {
+            let (peer, message) = match events.recv().await {
+                Ok(Event::DirectMessage { peer, message })
+                    if message.message_type() == DirectMessageType::EscrowSignature =>
+                {
+                    (peer, message)
+                }
+                Ok(_) => continue,
+                Err(RecvError::Lagged(skipped)) => {
+                    tracing::warn!("Escrow service lagged behind by {} events", skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+
+            let result = match EscrowSignature::decode(message.payload.as_slice()) {
+                Ok(signature) => match decode_psbt(&signature.psbt) {
+                    Ok(psbt) => self.receive_payout(&signature.order_id, psbt).await,
+                    Err(e) => Err(e),
+                },
+                Err(e) => Err(WalletError::InvalidArgument(e.to_string())),
+            };
+            match result {
+                Ok(Some(txid)) => tracing::info!("Escrow payout {} broadcast", txid),
+                Ok(None) => tracing::info!("Received escrow payout from {} to approve", peer),
+                Err(e) => tracing::warn!("Rejected escrow payout from {}: {}", peer, e),
+            }
+        }
+    }
+
+    async fn escrow_wallet(
+        &self,
+        order_id: &str,
+    ) -> Result<Arc<Mutex<OpenBazaarWallet>>, WalletError> {
+        let mut wallets = self.wallets.lock().await;
+        if let Some(wallet) = wallets.get(order_id) {
+            return Ok(wallet.clone());
+        }
+        let record = self.escrow(order_id).await?;
+        let wallet = self.open_wallet(&record)?;
+        wallets.insert(order_id.to_string(), wallet.clone());
+        Ok(wallet)
+    }
+
+    /// Opens the escrow's wallet with our key in our role's place, so it can sign.
+    fn open_wallet(
+        &self,
+        record: &EscrowRecord,
+    ) -> Result<Arc<Mutex<OpenBazaarWallet>>, WalletError> {
+        let mut keys = record.keys().map(|(_, key)| key);
+        for (index, (role, key)) in record.keys().into_iter().enumerate() {
+            if role == record.role {
+                if key != self.key.public {
+                    return Err(WalletError::InvalidArgument(format!(
+                        "Escrow key of the {:?} is not ours",
+                        role
+                    )));
+                }
+                keys[index] = &self.key.private;
+            }
+        }
+        let descriptor = escrow_descriptor(&record.order_id, keys);
+
+        let digest = Sha256::digest(record.order_id.as_bytes());
+        let file: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+        let db =
+            KeychainStore::new_from_path(format!("{}/{}-{}.db", self.data_dir, self.network, file))
+                .map_err(|e| anyhow::anyhow!("Failed to open escrow store: {:?}", e))?;
+
+        let mut wallet = Wallet::new(descriptor.as_str(), None, db, self.network)
+            .map_err(|e| WalletError::InvalidArgument(format!("Invalid escrow keys: {:?}", e)))?;
+        // Revealing the address keeps its script indexed, so payouts can be finalized
+        wallet.get_address(AddressIndex::New);
+        wallet
+            .commit()
+            .map_err(|e| anyhow::anyhow!("Failed to save escrow wallet: {:?}", e))?;
+        Ok(Arc::new(Mutex::new(wallet)))
+    }
+}
+
+#[cfg(all(test, feature = "regtest-tests"))]
+mod tests {
+    use super::*;
+    use crate::crypto::generate_mnemonic;
+    use crate::db::OpenBazaarDb;
+    use crate::regtest::Regtest;
+    use crate::wallet::{open_wallet, WalletConfig};
+    use std::time::Duration;
+
+    const ESCROW_AMOUNT: u64 = 100_000;
+
+    fn party(regtest: &Regtest, name: &str) -> EscrowService<OpenBazaarDb> {
+        let data_dir =
+            std::env::temp_dir().join(format!("openbazaar-escrow-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&data_dir);
+        let data_dir = data_dir.to_str().unwrap();
+
+        let mnemonic = generate_mnemonic();
+        let wallet = open_wallet(&mnemonic, data_dir, Network::Regtest).unwrap();
+        let config = WalletConfig {
+            esplora_url: regtest.esplora_url(),
+            ..Default::default()
+        };
+        let wallet = WalletService::new(wallet, config).unwrap();
+        EscrowService::new(
+            OpenBazaarDb::temporary(),
+            &mnemonic,
+            Network::Regtest,
+            data_dir,
+            &wallet,
+        )
+        .unwrap()
+    }
+
+    /// Opens the escrow of `order_id` for every party and checks they agree on its address.
+    async fn open_escrow(order_id: &str, parties: [&EscrowService<OpenBazaarDb>; 3]) -> Address {
+        let [buyer, vendor, moderator] = parties;
+        let roles = [EscrowRole::Buyer, EscrowRole::Vendor, EscrowRole::Moderator];
+        let mut addresses = Vec::new();
+        for (party, role) in parties.into_iter().zip(roles) {
+            let address = party
+                .open(order_id, role, buyer.key(), vendor.key(), moderator.key())
+                .await
+                .unwrap();
+            addresses.push(address);
+        }
+        assert!(addresses.iter().all(|address| *address == addresses[0]));
+        addresses.remove(0)
+    }
+
+    async fn wait_for_funds(escrows: &EscrowService<OpenBazaarDb>, order_id: &str) {
+        for _ in 0..50 {
+            let balance = escrows.sync(order_id).await.unwrap();
+            if balance.confirmed == ESCROW_AMOUNT {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+        panic!("Escrow of order {} was never funded", order_id);
+    }
+
+    #[tokio::test]
+    async fn buyer_and_vendor_release_escrow() {
+        let regtest = Regtest::start();
+        let buyer = party(&regtest, "release-buyer");
+        let vendor = party(&regtest, "release-vendor");
+        let moderator = party(&regtest, "release-moderator");
+        let order_id = "release";
+
+        let address = open_escrow(order_id, [&buyer, &vendor, &moderator]).await;
+        regtest.fund(&address, ESCROW_AMOUNT);
+        wait_for_funds(&vendor, order_id).await;
+        wait_for_funds(&buyer, order_id).await;
+
+        let vendor_address = regtest.new_address();
+        let psbt = vendor
+            .propose_payout(order_id, &vendor_address, Some(1.0))
+            .await
+            .unwrap();
+        assert_eq!(signature_count(&psbt), 1);
+        let payout_txid = psbt.unsigned_tx.txid();
+        // A single signature does not spend the escrow
+        assert!(vendor.approve(order_id, &payout_txid).await.is_err());
+
+        // Payouts whose signatures don't check out are never kept
+        let mut unsigned = psbt.clone();
+        for input in &mut unsigned.inputs {
+            input.partial_sigs.clear();
+        }
+        assert!(buyer.receive_payout(order_id, unsigned).await.is_err());
+        let escrow = buyer.escrow(order_id).await.unwrap();
+        let moderator_pubkey = escrow_pubkey(order_id, &escrow.moderator).unwrap();
+        let mut forged = psbt.clone();
+        for input in &mut forged.inputs {
+            let signature = *input.partial_sigs.values().next().unwrap();
+            input.partial_sigs.clear();
+            input.partial_sigs.insert(moderator_pubkey, signature);
+        }
+        assert!(buyer.receive_payout(order_id, forged).await.is_err());
+        assert!(buyer.payouts(order_id).await.unwrap().is_empty());
+
+        assert_eq!(buyer.receive_payout(order_id, psbt).await.unwrap(), None);
+        let txid = buyer.approve(order_id, &payout_txid).await.unwrap();
+        assert_eq!(txid, payout_txid);
+        regtest.mine(1);
+
+        let received = regtest.received(&vendor_address);
+        assert!(received > ESCROW_AMOUNT - 1_000 && received < ESCROW_AMOUNT);
+        let escrow = buyer.escrow(order_id).await.unwrap();
+        assert!(escrow.payouts.is_empty());
+        assert_eq!(escrow.payout_txid, Some(txid.to_string()));
+        assert_eq!(vendor.sync(order_id).await.unwrap().confirmed, 0);
+    }
+
+    #[tokio::test]
+    async fn moderator_refunds_disputed_escrow() {
+        let regtest = Regtest::start();
+        let buyer = party(&regtest, "dispute-buyer");
+        let vendor = party(&regtest, "dispute-vendor");
+        let moderator = party(&regtest, "dispute-moderator");
+        let order_id = "dispute";
+
+        let address = open_escrow(order_id, [&buyer, &vendor, &moderator]).await;
+        regtest.fund(&address, ESCROW_AMOUNT);
+        wait_for_funds(&moderator, order_id).await;
+        wait_for_funds(&vendor, order_id).await;
+        wait_for_funds(&buyer, order_id).await;
+
+        // The vendor asks both other parties to release the escrow
+        let release = vendor
+            .propose_payout(order_id, &regtest.new_address(), Some(1.0))
+            .await
+            .unwrap();
+        let release_txid = release.unsigned_tx.txid();
+        for party in [&buyer, &moderator] {
+            let result = party.receive_payout(order_id, release.clone()).await;
+            assert_eq!(result.unwrap(), None);
+        }
+
+        // The moderator decides for the buyer, the release does not stand in the way
+        let buyer_address = regtest.new_address();
+        let refund = moderator
+            .propose_payout(order_id, &buyer_address, Some(1.0))
+            .await
+            .unwrap();
+        let refund_txid = refund.unsigned_tx.txid();
+        assert_ne!(refund_txid, release_txid);
+        assert_eq!(moderator.payouts(order_id).await.unwrap().len(), 2);
+        assert_eq!(buyer.receive_payout(order_id, refund).await.unwrap(), None);
+        assert_eq!(buyer.payouts(order_id).await.unwrap().len(), 2);
+
+        buyer.reject_payout(order_id, &release_txid).await.unwrap();
+        assert!(buyer.approve(order_id, &release_txid).await.is_err());
+        let txid = buyer.approve(order_id, &refund_txid).await.unwrap();
+        regtest.mine(1);
+
+        let received = regtest.received(&buyer_address);
+        assert!(received > ESCROW_AMOUNT - 1_000 && received < ESCROW_AMOUNT);
+        let escrow = buyer.escrow(order_id).await.unwrap();
+        assert!(escrow.payouts.is_empty());
+        assert_eq!(escrow.payout_txid, Some(txid.to_string()));
+        assert_eq!(moderator.sync(order_id).await.unwrap().confirmed, 0);
+    }
+}
@@ -1,20 +1,43 @@
+mod address_record;
 mod api;
+mod block;
+mod codec;
+mod config;
+mod content;
 mod crypto;
+mod dag;
 mod db;
+mod dht;
+mod direct;
+mod escrow;
 mod network;
+mod peer_guard;
+mod peer_store;
 mod profile;
+mod record_store;
+#[cfg(all(test, feature = "regtest-tests"))]
+mod regtest;
+mod tor;
+mod transport;
 mod wallet;
 mod webserver;
 
 use crate::openbazaar::open_bazaar_rpc_server::OpenBazaarRpcServer;
 use crate::{
     api::OpenBazaarRpcService,
+    config::Config,
     db::{OpenBazaarDb, DB},
+    escrow::EscrowService,
+    network::NetworkConfig,
+    openbazaar::NodeAddressType,
+    peer_guard::PeerGuardConfig,
+    wallet::{WalletConfig, WalletService},
 };
 use actix_web::{http::Method, web, HttpRequest, HttpResponse, Responder};
+use bdk::bitcoin::Network;
 use clap::{Parser, Subcommand};
-use libp2p::{multiaddr::Protocol, Multiaddr, PeerId};
-use std::{net::SocketAddr, path::PathBuf, str::FromStr};
+use libp2p::{multiaddr::Protocol, Multiaddr};
+use std::{net::SocketAddr, path::PathBuf, str::FromStr, time::Duration};
 use tonic::transport::Server;
 use tonic_web::GrpcWebLayer;
 use tower_http::cors::{Any, CorsLayer};
@@ -24,6 +47,10 @@ pub mod openbazaar {
     include!(concat!(env!("OUT_DIR"), "/openbazaar_rpc.rs"));
 }
 
+pub mod openbazaar_direct {
+    include!(concat!(env!("OUT_DIR"), "/openbazaar_direct.rs"));
+}
+
 #[derive(Parser)]
 #[command(name = "openbazaar3")]
 #[command(about = "OpenBazaar 3 Marketplace", long_about = None)]
@@ -56,6 +83,49 @@ enum Commands {
 
         #[arg(short, long, value_name = "GRPC_SERVER")]
         grpc_server: Option<SocketAddr>,
+
+        #[arg(long = "bootstrap", value_name = "MULTIADDR")]
+        bootstrap_nodes: Vec<Multiaddr>,
+
+        /// Address to listen on, may be repeated (e.g. /ip4/0.0.0.0/udp/4001/quic-v1).
+        /// Defaults to TCP on the libp2p hostname and port.
+        #[arg(long = "listen", value_name = "MULTIADDR")]
+        listen_addresses: Vec<Multiaddr>,
+
+        /// Discover peers on the local network with mDNS
+        #[arg(long)]
+        mdns: bool,
+
+        /// Relay connections for peers that are not publicly reachable
+        #[arg(long)]
+        relay_server: bool,
+
+        /// Connect only through the Tor SOCKS5 proxy at this address (e.g. 127.0.0.1:9050)
+        #[arg(
+            long,
+            value_name = "ADDR",
+            conflicts_with_all = ["listen_addresses", "mdns", "relay_server"]
+        )]
+        tor_proxy: Option<SocketAddr>,
+
+        /// Tor control port used to create our onion service (e.g. 127.0.0.1:9051).
+        /// Without it the node can only dial out.
+        #[arg(long, value_name = "ADDR", requires = "tor_proxy")]
+        tor_control: Option<SocketAddr>,
+
+        /// Password for the Tor control port
+        #[arg(long, value_name = "PASSWORD", requires = "tor_control")]
+        tor_control_password: Option<String>,
+
+        /// Bitcoin network: mainnet, testnet, signet or regtest. A node stays on the network
+        /// it was first started on.
+        #[arg(long, value_name = "NETWORK", value_parser = wallet::parse_network)]
+        network: Option<Network>,
+
+        /// Esplora HTTP API the wallet syncs against (e.g. http://127.0.0.1:3002 for a local
+        /// electrs in regtest)
+        #[arg(long, value_name = "URL")]
+        esplora_url: Option<String>,
     },
 }
 
@@ -70,9 +140,20 @@ fn main() -> anyhow::Result<()> {
             api_server_hostname,
             user,
             grpc_server,
+            bootstrap_nodes,
+            listen_addresses,
+            mdns,
+            relay_server,
+            tor_proxy,
+            tor_control,
+            tor_control_password,
+            network,
+            esplora_url,
         } => {
             println!("Starting OpenBazaar...");
 
+            let config = Config::load(cli.config.as_deref())?;
+
             // Set up tracing
             let collector = tracing_subscriber::fmt()
                 .with_max_level(Level::DEBUG)
@@ -102,56 +183,140 @@ fn main() -> anyhow::Result<()> {
             let kp_ds = ds.clone();
             let keypair = rt.block_on(async move { kp_ds.get_identity().await.unwrap() });
 
+            // The bitcoin network is fixed once a node has started on it
+            let network = match (network, config.network) {
+                (Some(network), _) => network,
+                (None, Some(name)) => wallet::parse_network(&name).map_err(anyhow::Error::msg)?,
+                (None, None) => wallet::DEFAULT_NETWORK,
+            };
+            rt.block_on(wallet::ensure_network(&ds, network))?;
+
             /************
              * Set up libp2p network
              */
 
+            // Bootstrap nodes from the command line win over the config file and built-in defaults
+            let mut bootstrap_nodes = if !bootstrap_nodes.is_empty() {
+                bootstrap_nodes
+            } else if let Some(nodes) = config.bootstrap_nodes {
+                nodes
+                    .iter()
+                    .map(|addr| Multiaddr::from_str(addr))
+                    .collect::<Result<Vec<_>, _>>()?
+            } else {
+                network::BOOTNODES
+                    .iter()
+                    .map(|addr| Multiaddr::from_str(addr))
+                    .collect::<Result<Vec<_>, _>>()?
+            };
+            if let Some(addr) = std::env::var_os("PEER") {
+                let addr = Multiaddr::from_str(&addr.to_string_lossy())
+                    .expect("Failed to parse multiaddr");
+                if !bootstrap_nodes.contains(&addr) {
+                    bootstrap_nodes.push(addr);
+                }
+            }
+            let has_bootstrap_nodes = !bootstrap_nodes.is_empty();
+            if !has_bootstrap_nodes && !mdns {
+                tracing::warn!(
+                    "No bootstrap nodes configured, use --bootstrap to join the network"
+                );
+            }
+
             // Create a new libp2p network and wait for it to spin up
-            let (client, mut event_loop) =
-                rt.block_on(async move { network::new(keypair).await.unwrap() });
+            let net_ds = ds.clone();
+            let defaults = PeerGuardConfig::default();
+            let peer_guard = PeerGuardConfig {
+                max_connections: config.max_connections.unwrap_or(defaults.max_connections),
+                max_connections_per_peer: config
+                    .max_connections_per_peer
+                    .unwrap_or(defaults.max_connections_per_peer),
+                max_connections_per_ip: config
+                    .max_connections_per_ip
+                    .unwrap_or(defaults.max_connections_per_ip),
+                ..defaults
+            };
+            let network_config = NetworkConfig {
+                bootstrap_nodes,
+                mdns,
+                relay_server,
+                tor_proxy,
+                peer_guard,
+            };
+            let (client, mut event_loop) = rt.block_on(async move {
+                network::new(keypair, &net_ds, network_config)
+                    .await
+                    .unwrap()
+            });
 
             // Kick off the event loop handler in a thread
             let event_loop_handler = rt.spawn(async move { event_loop.run().await });
 
-            // Fire up the network listener for incoming connections
+            // Fire up the network listeners for incoming connections, in Tor mode they are only
+            // reached through our onion service
+            let listen_addresses = if tor_proxy.is_some() {
+                vec![format!("/ip4/127.0.0.1/tcp/{}", libp2p_port)
+                    .parse()
+                    .expect("Failed to parse multiaddr")]
+            } else if listen_addresses.is_empty() {
+                vec![format!("/ip4/{}/tcp/{}", libp2p_hostname, libp2p_port)
+                    .parse()
+                    .expect("Failed to parse multiaddr")]
+            } else {
+                listen_addresses
+            };
             let mut listener_client = client.clone();
             rt.block_on(async move {
-                let addr = format!("/ip4/{}/tcp/{}", libp2p_hostname, libp2p_port)
-                    .parse()
-                    .expect("Failed to parse multiaddr");
-                listener_client.start_listening(addr).await.unwrap();
+                for addr in listen_addresses {
+                    listener_client.start_listening(addr).await.unwrap();
+                }
             });
 
-            // Connect to bootstrap node
-            let mut client_dial = client.clone();
-            if let Some(addr) = std::env::var_os("PEER") {
-                let addr = Multiaddr::from_str(&addr.to_string_lossy())
-                    .expect("Failed to parse multiaddr");
-                let peer_id = match addr.iter().last() {
-                    Some(Protocol::P2p(hash)) => {
-                        PeerId::from_multihash(hash).expect("Failed to parse peer ID")
+            // Publish an onion address, the service lives as long as its control connection
+            let onion_service = match tor_control {
+                Some(control) => {
+                    let onion_client = client.clone();
+                    let onion_ds = ds.clone();
+                    let service = rt.block_on(async move {
+                        // Reusing the key keeps our onion address stable across restarts
+                        let key = onion_ds.get_onion_key().await?;
+                        let service = tor::create_onion_service(
+                            control,
+                            tor_control_password.as_deref(),
+                            key.as_deref(),
+                            libp2p_port,
+                            libp2p_port,
+                        )
+                        .await?;
+                        onion_ds.set_onion_key(&service.key).await?;
+
+                        let peer_id = onion_client.get_peer_id().await?;
+                        let address = service.address.clone().with(Protocol::P2p(peer_id.into()));
+                        println!("Onion service available at {}", address);
+                        if let Err(e) = onion_client
+                            .put_clear_address(NodeAddressType::Onion, address.to_string())
+                            .await
+                        {
+                            tracing::warn!("Failed to publish onion address: {}", e);
+                        }
+                        anyhow::Ok(service)
+                    })?;
+                    Some(service)
+                }
+                None => {
+                    if tor_proxy.is_some() {
+                        tracing::warn!("No Tor control port given, the node is not reachable");
                     }
-                    _ => panic!("No peer ID found in multiaddr"),
-                };
-                rt.block_on(async move {
-                    client_dial.dial(peer_id, addr).await.unwrap();
-                })
-            }
-
-            let mut peer_dial = client.clone();
-            if let Some(addr) = std::env::var_os("PEER") {
-                let addr = Multiaddr::from_str(addr.to_str().unwrap())
-                    .expect("Couldn't parse multiaddress");
-                let peer_id = match addr.iter().last() {
-                    Some(Protocol::P2p(hash)) => PeerId::from_multihash(hash).expect("Valid hash."),
-                    _ => panic!("Expect peer multiaddr to contain peer ID."),
-                };
-                rt.block_on(async move {
-                    peer_dial
-                        .dial(peer_id, addr)
-                        .await
-                        .expect("Dial to succeed");
-                });
+                    None
+                }
+            };
+
+            // Join the network through the bootstrap nodes and keep the routing table fresh.
+            // Without bootstrap nodes or peers from previous runs there is nothing to bootstrap
+            // from, the node waits to be found through mDNS or dialed instead.
+            let routing_table_size = rt.block_on(client.get_node_info())?.routing_table_size;
+            if has_bootstrap_nodes || routing_table_size > 0 {
+                rt.spawn(network::run_bootstrap(client.clone()));
             }
 
             // TODO: Set up TLS connection
@@ -162,25 +327,48 @@ fn main() -> anyhow::Result<()> {
                 webserver::start_webserver(http_addr).await
             });
 
-            // TODO: Start up bitcoin wallet
+            // Open the bitcoin wallet and keep it in sync with the chain
             let wallet_ds = ds.clone();
-            rt.block_on(async move {
-                let mnemonic = wallet_ds.get_mnemonic().await.unwrap();
-                wallet::fire_up_wallet(
-                    mnemonic,
-                    format!("data/{}", &data_directory.to_str().unwrap()),
-                );
-            });
+            let mnemonic = rt.block_on(async move { wallet_ds.get_mnemonic().await })?;
+            let wallet_dir = format!("data/{}", &data_directory.to_str().unwrap());
+            let wallet = wallet::open_wallet(&mnemonic, &wallet_dir, network)?;
+            let wallet_defaults = WalletConfig::default();
+            let wallet_config = WalletConfig {
+                esplora_url: esplora_url
+                    .or(config.esplora_url)
+                    .unwrap_or_else(|| wallet::default_esplora_url(network).to_string()),
+                // Behind Tor the wallet must not reveal our IP address either
+                proxy: tor_proxy.map(|proxy| format!("socks5h://{}", proxy)),
+                sync_interval: match config.wallet_sync_interval {
+                    // A zero interval would have the wallet hammer the Esplora server
+                    Some(0) => anyhow::bail!("wallet_sync_interval must be at least 1 second"),
+                    Some(seconds) => Duration::from_secs(seconds),
+                    None => wallet_defaults.sync_interval,
+                },
+                stop_gap: config.wallet_stop_gap.unwrap_or(wallet_defaults.stop_gap),
+            };
+            let wallet = WalletService::new(wallet, wallet_config)?;
+            rt.spawn(wallet.clone().run_sync());
+
+            // Collect the other parties' signatures on payouts of our orders' escrows
+            let escrows = EscrowService::new(ds.clone(), &mnemonic, network, &wallet_dir, &wallet)?;
+            rt.spawn(escrows.clone().run(client.clone()));
+            rt.spawn(escrows.clone().run_sync());
 
             println!("\nOpenBazaar started successfully! (Press Ctrl+C to exit)");
 
+            let shutdown_client = client.clone();
             let signal_handler = rt.spawn(async move {
                 tokio::signal::ctrl_c().await.unwrap();
-                event_loop_handler.abort();
+                // Withdraw our address record before the event loop goes away
+                if let Err(e) = shutdown_client.shutdown().await {
+                    tracing::warn!("Failed to shut down the network cleanly: {}", e);
+                }
+                let _ = event_loop_handler.await;
             });
 
             // Construct OpenBazaar service
-            let ob_service = OpenBazaarRpcService::new(client.clone(), ds);
+            let ob_service = OpenBazaarRpcService::new(client.clone(), ds, wallet, escrows);
 
             let tonic_server = Server::builder();
 
@@ -211,8 +399,9 @@ fn main() -> anyhow::Result<()> {
             rt.block_on(async move {
                 tokio::signal::ctrl_c().await.unwrap();
                 tonic_server_handler.abort();
-                signal_handler.abort();
+                let _ = signal_handler.await;
             });
+            drop(onion_service);
         }
     }
 
@@ -1,135 +1,498 @@
 use futures::StreamExt;
+use libp2p::connection_limits::{self, ConnectionLimits};
+use libp2p::core::transport::ListenerId;
 use libp2p::identity::Keypair;
 use libp2p::kad::record::Key;
-use libp2p::kad::{record::store::MemoryStore, Kademlia};
+use libp2p::kad::store::RecordStore;
 use libp2p::kad::{
-    AddProviderOk, GetClosestPeersOk, GetProvidersOk, GetRecordError, GetRecordOk, KademliaEvent,
-    QueryId, QueryResult,
+    AddProviderOk, BootstrapOk, GetClosestPeersOk, GetProvidersOk, GetRecordError, GetRecordOk,
+    InboundRequest, KademliaEvent, ProviderRecord, PutRecordError, PutRecordOk, QueryId,
+    QueryResult, Quorum, Record,
 };
+use libp2p::kad::{Kademlia, KademliaConfig, KademliaStoreInserts};
 use libp2p::multiaddr::Protocol;
-use libp2p::swarm::SwarmEvent;
-use libp2p::swarm::{NetworkBehaviour, SwarmBuilder};
+use libp2p::request_response::{self, ProtocolSupport, RequestId, ResponseChannel};
+use libp2p::swarm::behaviour::toggle::Toggle;
+use libp2p::swarm::dial_opts::{DialOpts, PeerCondition};
+use libp2p::swarm::{AddressScore, NetworkBehaviour, SwarmBuilder};
+use libp2p::swarm::{SwarmEvent, THandlerErr};
 use libp2p::Multiaddr;
+use libp2p::{autonat, dcutr, gossipsub, identify, mdns, ping, relay};
 use libp2p_identity::PeerId;
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use std::error::Error;
-use tokio::sync::{mpsc, oneshot};
+use std::iter;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc, oneshot};
 use tracing::instrument;
+use void::Void;
 
+use crate::address_record::{self, unix_now, AddressRecordError, SignedNodeData};
+use crate::block::{BlockCodec, BlockProtocol};
+use crate::db::{OpenBazaarDb, DB};
+use crate::dht::{self, Dht};
+use crate::direct::{DirectCodec, DirectProtocol, DIRECT_MESSAGE_VERSION};
 use crate::openbazaar::NodeAddressType;
+use crate::openbazaar_direct::{
+    BlockRequest, BlockResponse, DirectMessage, DirectMessageType, DirectResponse,
+};
+use crate::peer_guard::{self, BannedPeer, Misbehaviour, PeerGuard, PeerGuardConfig};
+use crate::peer_store::{PeerRecord, PeerStore};
+use crate::record_store::SledRecordStore;
+use crate::transport::{build_tor_transport, build_transport};
+
+// Protocol family advertised through identify, peers on other versions are not added to the routing table
+pub const PROTOCOL_VERSION: &str = "/openbazaar/3.0.0";
+
+// Software name and version advertised through identify
+pub const AGENT_VERSION: &str = concat!("openbazaar-server/", env!("CARGO_PKG_VERSION"));
+
+// Built-in bootstrap nodes, used when none are given on the command line or in the config file.
+// These are the public libp2p bootstrap nodes, which speak the same Kademlia protocol.
+pub const BOOTNODES: [&str; 5] = [
+    "/dnsaddr/bootstrap.libp2p.io/p2p/QmNnooDu7bfjPFoTZYxMNLWUQJyrVwtbZg5gBMjTezGAJN",
+    "/dnsaddr/bootstrap.libp2p.io/p2p/QmQCU2EcMqAqQPR2i9bChDtGNJchTbq5TbXJJ16u19uLTa",
+    "/dnsaddr/bootstrap.libp2p.io/p2p/QmbLHAnMoJPWSCR5Zhtx6BHJX9KiKNN6tpvbUcqanj75Nb",
+    "/dnsaddr/bootstrap.libp2p.io/p2p/QmcZf59bWwK5XFi76CZX8cbJ4BhTzzA3gU1ZjYZcYW3dwt",
+    "/ip4/104.131.131.82/tcp/4001/p2p/QmaCpDMGvV2BGHeYERUEnRQAwe3N8SzbUtfsmvsqQLuvuJ",
+];
+
+// How often the routing table is refreshed once the node has joined the network
+const BOOTSTRAP_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+// First delay before retrying a failed bootstrap, doubled on every failure
+const BOOTSTRAP_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+// How long the client waits for commands answered by the event loop itself
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How long a single Kademlia query runs before it fails with a timeout
+const QUERY_TIMEOUT: Duration = Duration::from_secs(30);
 
-// TODO: connect to bootstrap nodes
-// const BOOTNODES: [&str; 1] = [
-//     "/ip4/[insert_ip]/tcp/4001/ipfs/[insert_peer_id]"
-// ];
+// How long the client waits for commands that go out to the network, longer than
+// `QUERY_TIMEOUT` so the query reports its own failure first
+const NETWORK_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Bootstrapping runs a lookup for every bucket of the routing table
+const BOOTSTRAP_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+// Protocol spoken by peers willing to relay connections for us
+const RELAY_HOP_PROTOCOL: &str = "/libp2p/circuit/relay/0.2.0/hop";
+
+// Inbound events buffered for each subscriber before the slowest one starts lagging
+const EVENT_CHANNEL_CAPACITY: usize = 64;
 
 type ShareAddress = Vec<u8>;
 
-pub async fn new(keypair: Keypair) -> Result<(Client, EventLoop), Box<dyn Error>> {
+// How often the node re-announces its clear address record to the DHT
+const CLEAR_ADDRESS_REPUBLISH_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// How long a signed clear address record stays valid
+const CLEAR_ADDRESS_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+// How long shutting down waits for peers to store the record withdrawing our clear address
+const WITHDRAW_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Configuration for the network started by `new`.
+#[derive(Debug, Clone, Default)]
+pub struct NetworkConfig {
+    /// Multiaddrs of the peers used to join the network.
+    pub bootstrap_nodes: Vec<Multiaddr>,
+    /// Discover peers on the local network with mDNS.
+    pub mdns: bool,
+    /// Relay connections for peers that are not publicly reachable.
+    pub relay_server: bool,
+    /// Tor SOCKS5 proxy, when set every connection goes through Tor and only
+    /// the onion address given through `put_clear_address` is published.
+    pub tor_proxy: Option<SocketAddr>,
+    /// Connection limits, peer scoring and bans.
+    pub peer_guard: PeerGuardConfig,
+}
+
+pub async fn new(
+    keypair: Keypair,
+    db: &OpenBazaarDb,
+    config: NetworkConfig,
+) -> Result<(Client, EventLoop), Box<dyn Error>> {
     let peer_id = keypair.public().to_peer_id();
 
+    // Provider and value records are kept in the node's database so they survive restarts
+    let store = SledRecordStore::new(peer_id, db)?;
+
+    // Connections through a relay are dialed and listened on by the relay client
+    let (relay_transport, relay_client) = relay::client::new(peer_id);
+
     // Create transport for determining how to send data on the network
-    let transport = libp2p::tokio_development_transport(keypair).unwrap();
+    let tor = config.tor_proxy.is_some();
+    let transport = match config.tor_proxy {
+        Some(proxy) => build_tor_transport(&keypair, proxy)?,
+        None => build_transport(&keypair, relay_transport)?,
+    };
+
+    // Inbound records are validated by the event loop before they are stored
+    let mut kademlia_config = KademliaConfig::default();
+    kademlia_config.set_record_filtering(KademliaStoreInserts::FilterBoth);
+    kademlia_config.set_query_timeout(QUERY_TIMEOUT);
+
+    // Identify tells peers which addresses we listen on and which protocols we speak
+    let identify_config = identify::Config::new(PROTOCOL_VERSION.to_string(), keypair.public())
+        .with_agent_version(AGENT_VERSION.to_string());
+
+    // Messages are signed by their author and handed to the event loop for validation before
+    // being forwarded
+    let gossipsub_config = gossipsub::ConfigBuilder::default()
+        .validation_mode(gossipsub::ValidationMode::Strict)
+        .validate_messages()
+        .build()?;
+    let gossipsub = gossipsub::Behaviour::new(
+        gossipsub::MessageAuthenticity::Signed(keypair.clone()),
+        gossipsub_config,
+    )?;
+
+    // Local peer discovery is opt-in, it announces the node to everyone on the LAN
+    let mdns = if config.mdns && !tor {
+        Some(mdns::tokio::Behaviour::new(
+            mdns::Config::default(),
+            peer_id,
+        )?)
+    } else {
+        None
+    };
+
+    // Limits apply before any other behaviour gets to handle a connection
+    let connection_limits = ConnectionLimits::default()
+        .with_max_established(Some(config.peer_guard.max_connections))
+        .with_max_established_per_peer(Some(config.peer_guard.max_connections_per_peer));
 
     // Behaviour outlines what bytes to send and to whom
     let behaviour = ComposedBehaviour {
-        kademlia: Kademlia::new(peer_id, MemoryStore::new(peer_id)),
-        // TODO: add in OpenBazazar behaviour
+        connection_limits: connection_limits::Behaviour::new(connection_limits),
+        peer_guard: PeerGuard::new(db, config.peer_guard)?,
+        kademlia: Dht::new(Kademlia::with_config(peer_id, store, kademlia_config)),
+        identify: identify::Behaviour::new(identify_config),
+        ping: ping::Behaviour::default(),
+        direct: request_response::Behaviour::new(
+            DirectCodec::default(),
+            iter::once((DirectProtocol(), ProtocolSupport::Full)),
+            Default::default(),
+        ),
+        block: request_response::Behaviour::new(
+            BlockCodec::default(),
+            iter::once((BlockProtocol(), ProtocolSupport::Full)),
+            Default::default(),
+        ),
+        gossipsub,
+        mdns: Toggle::from(mdns),
+        relay_client,
+        relay_server: Toggle::from(
+            config
+                .relay_server
+                .then(|| relay::Behaviour::new(peer_id, Default::default())),
+        ),
+        // Behind Tor our reachability is that of the onion service
+        autonat: Toggle::from((!tor).then(|| autonat::Behaviour::new(peer_id, Default::default()))),
+        dcutr: dcutr::Behaviour::new(peer_id),
     };
 
     // Create libp2p swarm
-    let swarm = SwarmBuilder::with_tokio_executor(transport, behaviour, peer_id).build();
+    let mut swarm = SwarmBuilder::with_tokio_executor(transport, behaviour, peer_id).build();
+
+    // Peers known from previous runs seed the routing table
+    let peer_store = PeerStore::new(db)?;
+    for peer in peer_store.peers() {
+        for addr in peer.addresses {
+            swarm
+                .behaviour_mut()
+                .kademlia
+                .add_address(&peer.peer_id, addr);
+        }
+    }
 
     // Create command channel with buffer of 1 to process messages in order
     let (command_sender, command_receiver) = mpsc::channel(1);
 
+    // Inbound events are fanned out to every subscriber
+    let (event_sender, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+
     Ok((
         Client {
             sender: command_sender,
+            event_sender: event_sender.clone(),
         },
-        EventLoop::new(swarm, command_receiver),
+        EventLoop::new(
+            swarm,
+            command_receiver,
+            event_sender,
+            keypair,
+            config.bootstrap_nodes,
+            peer_store,
+            db.clone(),
+            tor,
+        ),
     ))
 }
 
+/// Events pushed by the network to the rest of the node.
+#[derive(Clone, Debug)]
+pub enum Event {
+    DirectMessage {
+        peer: PeerId,
+        message: DirectMessage,
+    },
+    PubSubMessage {
+        topic: String,
+        source: PeerId,
+        data: Vec<u8>,
+    },
+    /// First connection to `peer` was established.
+    PeerConnected {
+        peer: PeerId,
+        address: Multiaddr,
+    },
+    /// Last connection to `peer` was closed.
+    PeerDisconnected {
+        peer: PeerId,
+    },
+    ListenAddressAdded {
+        address: Multiaddr,
+    },
+    /// A value or provider record was stored in the DHT.
+    RecordPublished {
+        key: Vec<u8>,
+    },
+    QueryCompleted {
+        kind: QueryKind,
+        success: bool,
+    },
+    BootstrapFinished {
+        /// Peers in the routing table once bootstrapping is done
+        routing_table_size: usize,
+        success: bool,
+    },
+}
+
+/// Kind of a Kademlia query reported by `Event::QueryCompleted`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QueryKind {
+    Bootstrap,
+    GetClosestPeers,
+    GetProviders,
+    StartProviding,
+    RepublishProvider,
+    GetRecord,
+    PutRecord,
+    RepublishRecord,
+}
+
+/// Returns the kind of query `result` belongs to and whether it succeeded.
+fn query_outcome(result: &QueryResult) -> (QueryKind, bool) {
+    match result {
+        QueryResult::Bootstrap(r) => (QueryKind::Bootstrap, r.is_ok()),
+        QueryResult::GetClosestPeers(r) => (QueryKind::GetClosestPeers, r.is_ok()),
+        QueryResult::GetProviders(r) => (QueryKind::GetProviders, r.is_ok()),
+        QueryResult::StartProviding(r) => (QueryKind::StartProviding, r.is_ok()),
+        QueryResult::RepublishProvider(r) => (QueryKind::RepublishProvider, r.is_ok()),
+        QueryResult::GetRecord(r) => (QueryKind::GetRecord, r.is_ok()),
+        QueryResult::PutRecord(r) => (QueryKind::PutRecord, r.is_ok()),
+        QueryResult::RepublishRecord(r) => (QueryKind::RepublishRecord, r.is_ok()),
+    }
+}
+
+/// Errors returned by the network `Client`.
+#[derive(Debug, thiserror::Error)]
+pub enum NetworkError {
+    #[error("Network request timed out")]
+    Timeout,
+    #[error("No peers are reachable")]
+    NoPeers,
+    #[error("Not found in the network")]
+    NotFound,
+    #[error("Not enough peers answered the request")]
+    QuorumFailed,
+    #[error("Network event loop is not running")]
+    EventLoopGone,
+    #[error("Peer rejected the request: {0}")]
+    Rejected(String),
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+impl From<GetRecordError> for NetworkError {
+    fn from(error: GetRecordError) -> Self {
+        match error {
+            GetRecordError::NotFound { .. } => NetworkError::NotFound,
+            GetRecordError::QuorumFailed { .. } => NetworkError::QuorumFailed,
+            GetRecordError::Timeout { .. } => NetworkError::Timeout,
+        }
+    }
+}
+
+impl From<PutRecordError> for NetworkError {
+    fn from(error: PutRecordError) -> Self {
+        match error {
+            PutRecordError::QuorumFailed { .. } => NetworkError::QuorumFailed,
+            PutRecordError::Timeout { .. } => NetworkError::Timeout,
+        }
+    }
+}
+
+impl From<request_response::OutboundFailure> for NetworkError {
+    fn from(error: request_response::OutboundFailure) -> Self {
+        match error {
+            request_response::OutboundFailure::Timeout => NetworkError::Timeout,
+            error => NetworkError::Other(anyhow::Error::from(error)),
+        }
+    }
+}
+
+impl From<gossipsub::PublishError> for NetworkError {
+    fn from(error: gossipsub::PublishError) -> Self {
+        match error {
+            gossipsub::PublishError::InsufficientPeers => NetworkError::NoPeers,
+            error => NetworkError::Other(anyhow::Error::from(error)),
+        }
+    }
+}
+
+type Responder<T> = oneshot::Sender<Result<T, NetworkError>>;
+
 #[derive(Clone, Debug)]
 pub struct Client {
     sender: mpsc::Sender<Command>,
+    event_sender: broadcast::Sender<Event>,
 }
 
 impl Client {
-    #[instrument]
-    pub async fn start_listening(&mut self, addr: Multiaddr) -> anyhow::Result<()> {
+    /// Subscribes to inbound network events, only events after this call are received.
+    pub fn events(&self) -> broadcast::Receiver<Event> {
+        self.event_sender.subscribe()
+    }
+
+    /// Hands a command to the event loop and waits up to `timeout` for its result.
+    async fn request<T>(
+        &self,
+        timeout: Duration,
+        command: impl FnOnce(Responder<T>) -> Command,
+    ) -> Result<T, NetworkError> {
         let (sender, receiver) = oneshot::channel();
         self.sender
-            .send(Command::StartListening { addr, sender })
+            .send(command(sender))
             .await
-            .expect("Failed to send command");
-        receiver.await.expect("Failed to send command")
+            .map_err(|_| NetworkError::EventLoopGone)?;
+        match tokio::time::timeout(timeout, receiver).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => Err(NetworkError::EventLoopGone),
+            Err(_) => Err(NetworkError::Timeout),
+        }
+    }
+
+    /// Asks a peer for the content it provides under `share_addr`, resolving to `None` if the
+    /// peer doesn't have it. `addresses` are dialed when the peer isn't already known.
+    #[instrument(skip(self))]
+    pub async fn fetch_block(
+        &self,
+        peer_id: PeerId,
+        addresses: Vec<Multiaddr>,
+        share_addr: ShareAddress,
+    ) -> Result<Option<Vec<u8>>, NetworkError> {
+        self.request(NETWORK_TIMEOUT, |sender| Command::FetchBlock {
+            peer_id,
+            addresses,
+            share_addr,
+            sender,
+        })
+        .await
+    }
+
+    /// Sends a message straight to a peer, resolving once the peer has accepted it.
+    #[instrument(skip(payload))]
+    pub async fn send_direct(
+        &self,
+        peer_id: PeerId,
+        message_type: DirectMessageType,
+        payload: Vec<u8>,
+    ) -> Result<(), NetworkError> {
+        let message = DirectMessage {
+            version: DIRECT_MESSAGE_VERSION,
+            message_type: message_type.into(),
+            timestamp: unix_now().as_secs(),
+            payload,
+        };
+        self.request(NETWORK_TIMEOUT, |sender| Command::SendDirect {
+            peer_id,
+            message,
+            sender,
+        })
+        .await
     }
 
     #[instrument]
-    pub async fn dial(&mut self, peer_id: PeerId, addr: Multiaddr) -> anyhow::Result<()> {
-        let (sender, receiver) = oneshot::channel();
-        self.sender
-            .send(Command::Dial {
-                peer_id,
-                addr,
-                sender,
-            })
-            .await
-            .expect("Failed to send command");
-        receiver.await.expect("Failed to send command")
+    pub async fn start_listening(&mut self, addr: Multiaddr) -> Result<(), NetworkError> {
+        self.request(COMMAND_TIMEOUT, |sender| Command::StartListening {
+            addr,
+            sender,
+        })
+        .await
     }
 
     #[instrument]
-    pub async fn start_providing(&self, share_addr: ShareAddress) {
-        let (sender, receiver) = oneshot::channel();
-        self.sender
-            .send(Command::StartProviding { share_addr, sender })
-            .await
-            .expect("Command receiver not to be dropped.");
-        receiver.await.expect("Sender not to be dropped.")
+    pub async fn dial(&mut self, peer_id: PeerId, addr: Multiaddr) -> Result<(), NetworkError> {
+        self.request(NETWORK_TIMEOUT, |sender| Command::Dial {
+            peer_id,
+            addr,
+            sender,
+        })
+        .await
+    }
+
+    #[instrument]
+    pub async fn start_providing(&self, share_addr: ShareAddress) -> Result<(), NetworkError> {
+        self.request(NETWORK_TIMEOUT, |sender| Command::StartProviding {
+            share_addr,
+            sender,
+        })
+        .await
     }
 
     #[instrument]
-    pub async fn stop_providing(&self, share_addr: ShareAddress) {
+    pub async fn stop_providing(&self, share_addr: ShareAddress) -> Result<(), NetworkError> {
         self.sender
             .send(Command::StopProviding { share_addr })
             .await
-            .expect("Command receiver not to be dropped.");
+            .map_err(|_| NetworkError::EventLoopGone)
     }
 
     #[instrument]
-    pub async fn get_providers(&self, share_addr: ShareAddress) -> HashSet<PeerId> {
-        let (sender, receiver) = oneshot::channel();
-        self.sender
-            .send(Command::GetProviders { share_addr, sender })
-            .await
-            .expect("Command receiver not to be dropped.");
-        receiver.await.expect("Sender not to be dropped.")
+    pub async fn get_providers(
+        &self,
+        share_addr: ShareAddress,
+    ) -> Result<HashSet<PeerId>, NetworkError> {
+        self.request(NETWORK_TIMEOUT, |sender| Command::GetProviders {
+            share_addr,
+            sender,
+        })
+        .await
     }
 
     // getclosestpeers
     #[instrument]
-    pub async fn get_closest_peers(&self, addr: ShareAddress) -> anyhow::Result<PeerId> {
-        let (sender, receiver) = oneshot::channel();
-        self.sender
-            .send(Command::GetClosestPeers { addr, sender })
-            .await
-            .expect("Command receiver not to be dropped.");
-        receiver.await.expect("Sender not to be dropped.")
+    pub async fn get_closest_peers(&self, addr: ShareAddress) -> Result<PeerId, NetworkError> {
+        self.request(NETWORK_TIMEOUT, |sender| Command::GetClosestPeers {
+            addr,
+            sender,
+        })
+        .await
     }
 
     #[instrument]
-    pub async fn get_clear_address(&self, peer_id: PeerId) -> anyhow::Result<NodeData> {
-        let (sender, receiver) = oneshot::channel();
-        self.sender
-            .send(Command::GetClearAddress { peer_id, sender })
-            .await
-            .expect("Command receiver not to be dropped.");
-        let result = receiver.await.expect("Sender not to be dropped.");
-        result
+    pub async fn get_clear_address(&self, peer_id: PeerId) -> Result<NodeData, NetworkError> {
+        self.request(NETWORK_TIMEOUT, |sender| Command::GetClearAddress {
+            peer_id,
+            sender,
+        })
+        .await
     }
 
     #[instrument]
@@ -137,27 +500,107 @@ impl Client {
         &self,
         address_type: crate::openbazaar::NodeAddressType,
         address: String,
-    ) {
-        let (sender, receiver) = oneshot::channel();
-        self.sender
-            .send(Command::PutClearAddress {
-                address_type,
-                address,
-                sender,
-            })
+    ) -> Result<(), NetworkError> {
+        self.request(NETWORK_TIMEOUT, |sender| Command::PutClearAddress {
+            address_type,
+            address,
+            sender,
+        })
+        .await
+    }
+
+    /// Returns the addresses we are listening on, including our peer id.
+    #[instrument]
+    pub async fn get_listen_addresses(&self) -> Result<Vec<Multiaddr>, NetworkError> {
+        self.request(COMMAND_TIMEOUT, |sender| Command::GetListenAddress {
+            sender,
+        })
+        .await
+    }
+
+    #[instrument]
+    pub async fn get_peer_id(&self) -> Result<PeerId, NetworkError> {
+        self.request(COMMAND_TIMEOUT, |sender| Command::GetPeerId { sender })
             .await
-            .expect("Command receiver not to be dropped.");
-        receiver.await.expect("Sender not to be dropped.")
     }
 
+    /// Returns our addresses and the state of our connection to the network.
     #[instrument]
-    pub async fn get_peer_id(&self) -> PeerId {
-        let (sender, receiver) = oneshot::channel();
-        self.sender
-            .send(Command::GetPeerId { sender })
+    pub async fn get_node_info(&self) -> Result<NodeInfo, NetworkError> {
+        self.request(COMMAND_TIMEOUT, |sender| Command::GetNodeInfo { sender })
+            .await
+    }
+
+    /// Returns the peers in the persistent address book.
+    #[instrument]
+    pub async fn list_peers(&self) -> Result<Vec<PeerRecord>, NetworkError> {
+        self.request(COMMAND_TIMEOUT, |sender| Command::ListPeers { sender })
+            .await
+    }
+
+    /// Returns the peers we currently refuse connections from.
+    #[instrument]
+    pub async fn list_banned_peers(&self) -> Result<Vec<BannedPeer>, NetworkError> {
+        self.request(COMMAND_TIMEOUT, |sender| Command::ListBannedPeers {
+            sender,
+        })
+        .await
+    }
+
+    /// Lifts the ban on `peer_id`, fails with `NotFound` if it is not banned.
+    #[instrument]
+    pub async fn unban_peer(&self, peer_id: PeerId) -> Result<(), NetworkError> {
+        self.request(COMMAND_TIMEOUT, |sender| Command::UnbanPeer {
+            peer_id,
+            sender,
+        })
+        .await
+    }
+
+    /// Joins a pub/sub topic, messages arrive as `Event::PubSubMessage`.
+    ///
+    /// Every call must be matched by an `unsubscribe`, the topic is left once all are.
+    #[instrument]
+    pub async fn subscribe(&self, topic: String) -> Result<(), NetworkError> {
+        self.request(COMMAND_TIMEOUT, |sender| Command::Subscribe {
+            topic,
+            sender,
+        })
+        .await
+    }
+
+    #[instrument]
+    pub async fn unsubscribe(&self, topic: String) -> Result<(), NetworkError> {
+        self.request(COMMAND_TIMEOUT, |sender| Command::Unsubscribe {
+            topic,
+            sender,
+        })
+        .await
+    }
+
+    /// Publishes a message signed with our key to everyone subscribed to the topic.
+    #[instrument(skip(data))]
+    pub async fn publish(&self, topic: String, data: Vec<u8>) -> Result<(), NetworkError> {
+        self.request(COMMAND_TIMEOUT, |sender| Command::Publish {
+            topic,
+            data,
+            sender,
+        })
+        .await
+    }
+
+    /// Connects to the bootstrap nodes and refreshes the routing table.
+    #[instrument]
+    pub async fn bootstrap(&self) -> Result<BootstrapOk, NetworkError> {
+        self.request(BOOTSTRAP_TIMEOUT, |sender| Command::Bootstrap { sender })
+            .await
+    }
+
+    /// Withdraws our clear address record and stops the event loop.
+    #[instrument]
+    pub async fn shutdown(&self) -> Result<(), NetworkError> {
+        self.request(COMMAND_TIMEOUT, |sender| Command::Shutdown { sender })
             .await
-            .expect("Command receiver not to be dropped.");
-        receiver.await.expect("Sender not to be dropped.")
     }
 }
 
@@ -165,72 +608,249 @@ impl Client {
 enum Command {
     StartListening {
         addr: Multiaddr,
-        sender: oneshot::Sender<Result<(), anyhow::Error>>,
+        sender: Responder<()>,
     },
     Dial {
         peer_id: PeerId,
         addr: Multiaddr,
-        sender: oneshot::Sender<Result<(), anyhow::Error>>,
+        sender: Responder<()>,
     },
     StartProviding {
         share_addr: ShareAddress,
-        sender: oneshot::Sender<()>,
+        sender: Responder<()>,
     },
     StopProviding {
         share_addr: ShareAddress,
     },
     GetProviders {
         share_addr: ShareAddress,
-        sender: oneshot::Sender<HashSet<PeerId>>,
+        sender: Responder<HashSet<PeerId>>,
     },
     GetClearAddress {
         peer_id: PeerId,
-        sender: oneshot::Sender<anyhow::Result<NodeData>>,
+        sender: Responder<NodeData>,
     },
     PutClearAddress {
         address_type: crate::openbazaar::NodeAddressType,
         address: String,
-        sender: oneshot::Sender<()>,
+        sender: Responder<()>,
     },
     GetClosestPeers {
         addr: ShareAddress,
-        sender: oneshot::Sender<anyhow::Result<PeerId>>,
+        sender: Responder<PeerId>,
     },
     GetListenAddress {
-        sender: oneshot::Sender<anyhow::Result<Vec<Multiaddr>>>,
+        sender: Responder<Vec<Multiaddr>>,
     },
     GetPeerId {
-        sender: oneshot::Sender<PeerId>,
+        sender: Responder<PeerId>,
+    },
+    GetNodeInfo {
+        sender: Responder<NodeInfo>,
     },
+    ListPeers {
+        sender: Responder<Vec<PeerRecord>>,
+    },
+    ListBannedPeers {
+        sender: Responder<Vec<BannedPeer>>,
+    },
+    UnbanPeer {
+        peer_id: PeerId,
+        sender: Responder<()>,
+    },
+    Bootstrap {
+        sender: Responder<BootstrapOk>,
+    },
+    SendDirect {
+        peer_id: PeerId,
+        message: DirectMessage,
+        sender: Responder<()>,
+    },
+    FetchBlock {
+        peer_id: PeerId,
+        addresses: Vec<Multiaddr>,
+        share_addr: ShareAddress,
+        sender: Responder<Option<Vec<u8>>>,
+    },
+    Subscribe {
+        topic: String,
+        sender: Responder<()>,
+    },
+    Unsubscribe {
+        topic: String,
+        sender: Responder<()>,
+    },
+    Publish {
+        topic: String,
+        data: Vec<u8>,
+        sender: Responder<()>,
+    },
+    Shutdown {
+        sender: Responder<()>,
+    },
+}
+
+/// Bootstraps on start-up and then on an interval, backing off while no bootstrap node is reachable.
+pub async fn run_bootstrap(client: Client) {
+    let mut retry_delay = BOOTSTRAP_RETRY_DELAY;
+    loop {
+        match client.bootstrap().await {
+            Ok(result) => {
+                tracing::info!(
+                    "Bootstrapped with {} queries remaining",
+                    result.num_remaining
+                );
+                retry_delay = BOOTSTRAP_RETRY_DELAY;
+                tokio::time::sleep(BOOTSTRAP_INTERVAL).await;
+            }
+            Err(e) => {
+                tracing::warn!("Bootstrap failed, retrying in {:?}: {}", retry_delay, e);
+                tokio::time::sleep(retry_delay).await;
+                retry_delay = (retry_delay * 2).min(BOOTSTRAP_INTERVAL);
+            }
+        }
+    }
 }
 
 #[derive(NetworkBehaviour)]
 #[behaviour(out_event = "ComposedEvent", event_process = false)]
 struct ComposedBehaviour {
-    kademlia: Kademlia<MemoryStore>,
-    // TODO: implement OpenBazaar network behaviour
+    connection_limits: connection_limits::Behaviour,
+    peer_guard: PeerGuard,
+    kademlia: Dht<SledRecordStore>,
+    identify: identify::Behaviour,
+    ping: ping::Behaviour,
+    direct: request_response::Behaviour<DirectCodec>,
+    block: request_response::Behaviour<BlockCodec>,
+    gossipsub: gossipsub::Behaviour,
+    mdns: Toggle<mdns::tokio::Behaviour>,
+    relay_client: relay::client::Behaviour,
+    relay_server: Toggle<relay::Behaviour>,
+    autonat: Toggle<autonat::Behaviour>,
+    dcutr: dcutr::Behaviour,
 }
 
 #[derive(Debug)]
 enum ComposedEvent {
+    PeerGuard(peer_guard::Event),
     Kademlia(KademliaEvent),
-    // TODO: implement OpenBazaar event
+    InboundQuery(PeerId),
+    InboundProvider {
+        source: PeerId,
+        record: ProviderRecord,
+    },
+    Identify(identify::Event),
+    Ping(ping::Event),
+    Direct(request_response::Event<DirectMessage, DirectResponse>),
+    Block(request_response::Event<BlockRequest, BlockResponse>),
+    Gossipsub(gossipsub::Event),
+    Mdns(mdns::Event),
+    RelayClient(relay::client::Event),
+    RelayServer(relay::Event),
+    Autonat(autonat::Event),
+    Dcutr(dcutr::Event),
+}
+
+impl From<Void> for ComposedEvent {
+    fn from(event: Void) -> Self {
+        void::unreachable(event)
+    }
+}
+
+impl From<peer_guard::Event> for ComposedEvent {
+    fn from(event: peer_guard::Event) -> Self {
+        ComposedEvent::PeerGuard(event)
+    }
+}
+
+impl From<dht::Event> for ComposedEvent {
+    fn from(event: dht::Event) -> Self {
+        match event {
+            dht::Event::Kademlia(event) => ComposedEvent::Kademlia(event),
+            dht::Event::InboundQuery { source } => ComposedEvent::InboundQuery(source),
+            dht::Event::AddProvider { source, record } => {
+                ComposedEvent::InboundProvider { source, record }
+            }
+        }
+    }
+}
+
+impl From<identify::Event> for ComposedEvent {
+    fn from(event: identify::Event) -> Self {
+        ComposedEvent::Identify(event)
+    }
+}
+
+impl From<ping::Event> for ComposedEvent {
+    fn from(event: ping::Event) -> Self {
+        ComposedEvent::Ping(event)
+    }
+}
+
+impl From<request_response::Event<DirectMessage, DirectResponse>> for ComposedEvent {
+    fn from(event: request_response::Event<DirectMessage, DirectResponse>) -> Self {
+        ComposedEvent::Direct(event)
+    }
+}
+
+impl From<request_response::Event<BlockRequest, BlockResponse>> for ComposedEvent {
+    fn from(event: request_response::Event<BlockRequest, BlockResponse>) -> Self {
+        ComposedEvent::Block(event)
+    }
+}
+
+impl From<gossipsub::Event> for ComposedEvent {
+    fn from(event: gossipsub::Event) -> Self {
+        ComposedEvent::Gossipsub(event)
+    }
+}
+
+impl From<mdns::Event> for ComposedEvent {
+    fn from(event: mdns::Event) -> Self {
+        ComposedEvent::Mdns(event)
+    }
+}
+
+impl From<relay::client::Event> for ComposedEvent {
+    fn from(event: relay::client::Event) -> Self {
+        ComposedEvent::RelayClient(event)
+    }
+}
+
+impl From<relay::Event> for ComposedEvent {
+    fn from(event: relay::Event) -> Self {
+        ComposedEvent::RelayServer(event)
+    }
+}
+
+impl From<autonat::Event> for ComposedEvent {
+    fn from(event: autonat::Event) -> Self {
+        ComposedEvent::Autonat(event)
+    }
 }
 
-impl From<KademliaEvent> for ComposedEvent {
-    fn from(event: KademliaEvent) -> Self {
-        ComposedEvent::Kademlia(event)
+impl From<dcutr::Event> for ComposedEvent {
+    fn from(event: dcutr::Event) -> Self {
+        ComposedEvent::Dcutr(event)
     }
 }
 
-// TODO: placeholder implementation
-// impl From<OpenBazaarEvent> for ComposedEvent {
-//     fn from(event: OpenBazaarEvent) -> Self {
-//         ComposedEvent::OpenBazaar(event)
-//     }
-// }
+/// Snapshot of the node returned by `Client::get_node_info`.
+#[derive(Clone, Debug)]
+pub struct NodeInfo {
+    pub peer_id: PeerId,
+    /// Addresses we listen on, with our peer id appended
+    pub listen_addresses: Vec<Multiaddr>,
+    /// Addresses peers observed us on and those we announce explicitly
+    pub external_addresses: Vec<Multiaddr>,
+    pub protocol_version: String,
+    pub agent_version: String,
+    pub uptime: Duration,
+    pub connected_peers: usize,
+    pub routing_table_size: usize,
+}
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct NodeData {
     pub peer_id: Vec<u8>,
     pub address: String,
@@ -239,13 +859,41 @@ pub struct NodeData {
 
 pub struct EventLoop {
     swarm: libp2p::Swarm<ComposedBehaviour>,
+    event_sender: broadcast::Sender<Event>,
+    keypair: Keypair,
     command_receiver: mpsc::Receiver<Command>,
-    pending_dial: HashMap<PeerId, oneshot::Sender<Result<(), anyhow::Error>>>,
-    pending_start_providing: HashMap<QueryId, oneshot::Sender<()>>,
-    pending_get_providers: HashMap<QueryId, oneshot::Sender<HashSet<PeerId>>>,
-    pending_get_closest_peer: HashMap<QueryId, oneshot::Sender<anyhow::Result<PeerId>>>,
-    pending_get_clear_address: HashMap<QueryId, oneshot::Sender<anyhow::Result<NodeData>>>,
+    pending_dial: HashMap<PeerId, Responder<()>>,
+    pending_start_providing: HashMap<QueryId, Responder<()>>,
+    pending_get_providers: HashMap<QueryId, Responder<HashSet<PeerId>>>,
+    pending_get_closest_peer: HashMap<QueryId, Responder<PeerId>>,
+    pending_get_clear_address: HashMap<QueryId, Responder<NodeData>>,
+    pending_put_clear_address: HashMap<QueryId, Responder<()>>,
+    pending_bootstrap: HashMap<QueryId, Responder<BootstrapOk>>,
+    pending_send_direct: HashMap<RequestId, Responder<()>>,
+    pending_fetch_block: HashMap<RequestId, Responder<Option<Vec<u8>>>>,
     providing: HashSet<Key>,
+    bootstrap_nodes: Vec<Multiaddr>,
+    peer_store: PeerStore,
+    // Content served to peers over the block protocol
+    db: OpenBazaarDb,
+    clear_address: Option<NodeData>,
+    // Set once the clear address was given explicitly through `PutClearAddress`
+    clear_address_pinned: bool,
+    clear_address_sequence: u64,
+    // Highest address record sequence number seen for each peer
+    address_sequences: HashMap<PeerId, u64>,
+    // Open subscriptions to each pub/sub topic we are in
+    topic_subscribers: HashMap<String, usize>,
+    // Reachability of this node as determined by AutoNAT
+    nat_status: autonat::NatStatus,
+    // Peers offering to relay connections, with an address to reach them on
+    relays: HashMap<PeerId, Multiaddr>,
+    // Listener on a relayed address, set while we hold a reservation
+    relay_listener: Option<(ListenerId, PeerId)>,
+    // In Tor mode our listen addresses are loopback ones and must not be published
+    tor: bool,
+    // Start of the event loop, reported as the node's uptime
+    started: Instant,
 }
 
 impl EventLoop {
@@ -254,7 +902,7 @@ impl EventLoop {
             Command::StartListening { addr, sender } => {
                 let _ = match self.swarm.listen_on(addr) {
                     Ok(_) => sender.send(Ok(())),
-                    Err(e) => sender.send(Err(anyhow::Error::from(e))),
+                    Err(e) => sender.send(Err(anyhow::Error::from(e).into())),
                 };
             }
             Command::Dial {
@@ -274,21 +922,24 @@ impl EventLoop {
                             self.pending_dial.insert(peer_id, sender);
                         }
                         Err(e) => {
-                            let _ = sender.send(Err(anyhow::Error::from(e)));
+                            let _ = sender.send(Err(anyhow::Error::from(e).into()));
                         }
                     }
+                } else {
+                    let _ = sender.send(Err(anyhow::anyhow!("Already dialing {}", peer_id).into()));
                 }
             }
             Command::StartProviding { share_addr, sender } => {
                 let key: Key = share_addr.to_vec().into();
                 println!("Start providing: {:?}", key);
-                let query_id = self
-                    .swarm
-                    .behaviour_mut()
-                    .kademlia
-                    .start_providing(key)
-                    .expect("No store error.");
-                self.pending_start_providing.insert(query_id, sender);
+                match self.swarm.behaviour_mut().kademlia.start_providing(key) {
+                    Ok(query_id) => {
+                        self.pending_start_providing.insert(query_id, sender);
+                    }
+                    Err(e) => {
+                        let _ = sender.send(Err(anyhow::Error::from(e).into()));
+                    }
+                }
             }
             Command::StopProviding { share_addr } => {
                 let key: Key = share_addr.to_vec().into();
@@ -312,102 +963,668 @@ impl EventLoop {
                 self.pending_get_clear_address.insert(query_id, sender);
             }
             Command::GetListenAddress { sender } => {
-                let peer_id = self.swarm.local_peer_id().to_owned().into();
-                let addr: Vec<Multiaddr> = self
-                    .swarm
-                    .listeners()
-                    .map(|addr| addr.to_owned().with(Protocol::P2p(peer_id)))
-                    .collect();
-                sender
-                    .send(Ok(addr))
-                    .expect("Failed to send listen address.")
+                let _ = sender.send(Ok(self.listen_addresses()));
             }
             Command::GetPeerId { sender } => {
                 let peer_id = self.swarm.local_peer_id().to_owned();
-                sender.send(peer_id).expect("Failed to send peer id.")
+                let _ = sender.send(Ok(peer_id));
             }
-            _ => todo!(),
-        }
-    }
-
-    /// The result of [`Kademlia::bootstrap`].
-    // Bootstrap(BootstrapResult),
-
-    // /// The result of a (automatic) republishing of a provider record.
-    // RepublishProvider(AddProviderResult),
-
-    // /// The result of [`Kademlia::get_record`].
-    // GetRecord(GetRecordResult),
-
-    // /// The result of [`Kademlia::put_record`].
-    // PutRecord(PutRecordResult),
-
-    // /// The result of a (automatic) republishing of a (value-)record.
-    // RepublishRecord(PutRecordResult),
-
-    async fn handle_event(&mut self, event: SwarmEvent<ComposedEvent, std::io::Error>) {
-        match event {
-            SwarmEvent::Behaviour(ComposedEvent::Kademlia(
-                KademliaEvent::OutboundQueryProgressed {
-                    id,
-                    result: QueryResult::GetRecord(Ok(GetRecordOk::FoundRecord(peer_record))),
-                    ..
-                },
-            )) => {
-                let data = peer_record.record.value.clone();
-                let b: anyhow::Result<NodeData, bincode::Error> = bincode::deserialize(&data);
-                let bundle = match b {
-                    Ok(r) => Ok(r),
-                    Err(e) => Err(anyhow::Error::from(e)),
+            Command::GetNodeInfo { sender } => {
+                let info = NodeInfo {
+                    peer_id: *self.swarm.local_peer_id(),
+                    listen_addresses: self.listen_addresses(),
+                    external_addresses: self
+                        .swarm
+                        .external_addresses()
+                        .map(|record| record.addr.clone())
+                        .collect(),
+                    protocol_version: PROTOCOL_VERSION.to_string(),
+                    agent_version: AGENT_VERSION.to_string(),
+                    uptime: self.started.elapsed(),
+                    connected_peers: self.swarm.connected_peers().count(),
+                    routing_table_size: self.routing_table_size(),
                 };
-
-                let _ = self
-                    .pending_get_clear_address
-                    .remove(&id)
-                    .expect("Completed query to previously pending")
-                    .send(bundle);
+                let _ = sender.send(Ok(info));
             }
-            SwarmEvent::Behaviour(ComposedEvent::Kademlia(
-                KademliaEvent::OutboundQueryProgressed {
-                    id,
-                    result:
-                        QueryResult::GetRecord(Err(GetRecordError::NotFound { key, closest_peers })),
-                    ..
-                },
-            )) => {
-                let _ = self
-                    .pending_get_clear_address
-                    .remove(&id)
-                    .expect("Completed query to previously pending")
-                    .send(Ok(NodeData {
-                        peer_id: "".into(),
-                        address: "".into(),
-                        address_type: NodeAddressType::Clear,
-                    }));
+            Command::ListPeers { sender } => {
+                let _ = sender.send(Ok(self.peer_store.peers()));
             }
-            SwarmEvent::Behaviour(ComposedEvent::Kademlia(
-                KademliaEvent::OutboundQueryProgressed {
-                    id,
-                    result:
-                        QueryResult::GetProviders(Ok(GetProvidersOk::FinishedWithNoAdditionalRecord {
-                            closest_peers,
-                            ..
-                        })),
-                    ..
-                },
-            )) => {
-                let closest_peers_set = closest_peers.into_iter().collect::<HashSet<_>>();
-                let _ = self
-                    .pending_get_providers
-                    .remove(&id)
-                    .expect("Completed query to previously pending")
-                    .send(closest_peers_set);
+            Command::ListBannedPeers { sender } => {
+                let banned = self.swarm.behaviour().peer_guard.banned_peers();
+                let _ = sender.send(Ok(banned));
             }
-            SwarmEvent::Behaviour(ComposedEvent::Kademlia(
-                KademliaEvent::OutboundQueryProgressed {
-                    id,
-                    result:
-                        QueryResult::GetProviders(Ok(GetProvidersOk::FoundProviders { providers, key })),
+            Command::UnbanPeer { peer_id, sender } => {
+                let result = if self.swarm.behaviour_mut().peer_guard.unban(&peer_id) {
+                    Ok(())
+                } else {
+                    Err(NetworkError::NotFound)
+                };
+                let _ = sender.send(result);
+            }
+            Command::PutClearAddress {
+                address_type,
+                address,
+                sender,
+            } => {
+                // Peers learn our onion address through identify, the listen addresses are loopback
+                if self.tor {
+                    if let Ok(addr) = address.parse::<Multiaddr>() {
+                        let addr = addr
+                            .into_iter()
+                            .filter(|p| !matches!(p, Protocol::P2p(_)))
+                            .collect();
+                        self.swarm
+                            .add_external_address(addr, AddressScore::Infinite);
+                    }
+                }
+                self.clear_address = Some(NodeData {
+                    peer_id: self.swarm.local_peer_id().to_bytes(),
+                    address,
+                    address_type,
+                });
+                self.clear_address_pinned = true;
+                match self.publish_clear_address() {
+                    Some(query_id) => {
+                        self.pending_put_clear_address.insert(query_id, sender);
+                    }
+                    None => {
+                        let _ = sender
+                            .send(Err(
+                                anyhow::anyhow!("Failed to store clear address record").into()
+                            ));
+                    }
+                }
+            }
+            Command::Bootstrap { sender } => {
+                self.add_bootstrap_nodes();
+                match self.swarm.behaviour_mut().kademlia.bootstrap() {
+                    Ok(query_id) => {
+                        self.pending_bootstrap.insert(query_id, sender);
+                    }
+                    Err(_) => {
+                        let _ = sender.send(Err(NetworkError::NoPeers));
+                    }
+                }
+            }
+            Command::SendDirect {
+                peer_id,
+                message,
+                sender,
+            } => {
+                let request_id = self
+                    .swarm
+                    .behaviour_mut()
+                    .direct
+                    .send_request(&peer_id, message);
+                self.pending_send_direct.insert(request_id, sender);
+            }
+            Command::FetchBlock {
+                peer_id,
+                addresses,
+                share_addr,
+                sender,
+            } => {
+                let block = &mut self.swarm.behaviour_mut().block;
+                for addr in addresses {
+                    block.add_address(&peer_id, addr);
+                }
+                let request_id = block.send_request(
+                    &peer_id,
+                    BlockRequest {
+                        address: share_addr,
+                    },
+                );
+                self.pending_fetch_block.insert(request_id, sender);
+            }
+            Command::Subscribe { topic, sender } => {
+                let ident = gossipsub::IdentTopic::new(topic.clone());
+                let _ = match self.swarm.behaviour_mut().gossipsub.subscribe(&ident) {
+                    Ok(_) => {
+                        *self.topic_subscribers.entry(topic).or_default() += 1;
+                        sender.send(Ok(()))
+                    }
+                    Err(e) => sender.send(Err(anyhow::Error::from(e).into())),
+                };
+            }
+            Command::Unsubscribe { topic, sender } => {
+                // The topic is only left once its last subscriber is gone
+                let remaining = match self.topic_subscribers.get_mut(&topic) {
+                    Some(count) => {
+                        *count -= 1;
+                        *count
+                    }
+                    None => {
+                        let _ = sender.send(Err(NetworkError::NotFound));
+                        return;
+                    }
+                };
+                if remaining > 0 {
+                    let _ = sender.send(Ok(()));
+                    return;
+                }
+                self.topic_subscribers.remove(&topic);
+                let ident = gossipsub::IdentTopic::new(topic);
+                let _ = match self.swarm.behaviour_mut().gossipsub.unsubscribe(&ident) {
+                    Ok(_) => sender.send(Ok(())),
+                    Err(e) => sender.send(Err(e.into())),
+                };
+            }
+            Command::Publish {
+                topic,
+                data,
+                sender,
+            } => {
+                let topic = gossipsub::IdentTopic::new(topic);
+                let _ = match self.swarm.behaviour_mut().gossipsub.publish(topic, data) {
+                    Ok(_) => sender.send(Ok(())),
+                    Err(e) => sender.send(Err(e.into())),
+                };
+            }
+            Command::Shutdown { .. } => unreachable!("Shutdown is handled by the run loop"),
+        }
+    }
+
+    /// (Re-)adds the bootstrap nodes, which Kademlia drops from the routing table when unreachable.
+    fn add_bootstrap_nodes(&mut self) {
+        for addr in self.bootstrap_nodes.clone() {
+            match addr.iter().last() {
+                Some(Protocol::P2p(hash)) => match PeerId::from_multihash(hash) {
+                    Ok(peer_id) => {
+                        self.swarm
+                            .behaviour_mut()
+                            .kademlia
+                            .add_address(&peer_id, addr);
+                    }
+                    Err(_) => tracing::error!("Invalid peer id in bootstrap node {}", addr),
+                },
+                // Without a peer id we can only dial, the peer is added once connected
+                _ => {
+                    if let Err(e) = self.swarm.dial(addr.clone()) {
+                        tracing::error!("Failed to dial bootstrap node {}: {:?}", addr, e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Stores our `NodeData` in the DHT under our own peer id.
+    fn publish_clear_address(&mut self) -> Option<QueryId> {
+        let node_data = self.clear_address.clone()?;
+        self.put_address_record(node_data)
+    }
+
+    /// Signs `node_data` with the next sequence number and stores it under our own peer id.
+    fn put_address_record(&mut self, node_data: NodeData) -> Option<QueryId> {
+        // Millisecond timestamps keep the sequence increasing across restarts
+        let sequence = (unix_now().as_millis() as u64).max(self.clear_address_sequence + 1);
+        let signed = SignedNodeData::sign(&self.keypair, node_data, sequence, CLEAR_ADDRESS_TTL);
+        let value = match signed.and_then(|signed| Ok(signed.to_bytes()?)) {
+            Ok(v) => v,
+            Err(e) => {
+                tracing::error!("Failed to sign clear address: {:?}", e);
+                return None;
+            }
+        };
+        self.clear_address_sequence = sequence;
+
+        let key = Key::from(self.swarm.local_peer_id().to_bytes());
+        let mut record = Record::new(key, value);
+        record.expires = Some(std::time::Instant::now() + CLEAR_ADDRESS_TTL);
+        match self
+            .swarm
+            .behaviour_mut()
+            .kademlia
+            .put_record(record, Quorum::One)
+        {
+            Ok(query_id) => Some(query_id),
+            Err(e) => {
+                tracing::error!("Failed to store clear address record: {:?}", e);
+                None
+            }
+        }
+    }
+
+    /// Hands an inbound direct message to subscribers and acknowledges it.
+    fn handle_direct_message(
+        &mut self,
+        peer: PeerId,
+        message: DirectMessage,
+        channel: ResponseChannel<DirectResponse>,
+    ) {
+        // Dropping the channel closes the stream without an answer
+        if !self.swarm.behaviour_mut().peer_guard.allow_request(&peer) {
+            tracing::debug!("Dropped direct message from {} over the rate limit", peer);
+            return;
+        }
+
+        let response = if message.version != DIRECT_MESSAGE_VERSION {
+            DirectResponse {
+                accepted: false,
+                error: format!("Unsupported message version {}", message.version),
+            }
+        } else {
+            // Nobody listening is not an error, the message is still delivered
+            let _ = self
+                .event_sender
+                .send(Event::DirectMessage { peer, message });
+            DirectResponse {
+                accepted: true,
+                error: String::new(),
+            }
+        };
+
+        if self
+            .swarm
+            .behaviour_mut()
+            .direct
+            .send_response(channel, response)
+            .is_err()
+        {
+            tracing::warn!("Connection to {} closed before the response was sent", peer);
+        }
+    }
+
+    /// Answers a block request with content this node provides or a chunk it holds.
+    ///
+    /// Only provided addresses and chunks are served, everything else in the database stays
+    /// private.
+    async fn handle_block_request(
+        &mut self,
+        peer: PeerId,
+        request: BlockRequest,
+        channel: ResponseChannel<BlockResponse>,
+    ) {
+        if !self.swarm.behaviour_mut().peer_guard.allow_request(&peer) {
+            tracing::debug!("Dropped block request from {} over the rate limit", peer);
+            return;
+        }
+
+        let key = Key::new(&request.address);
+        let provided = self
+            .swarm
+            .behaviour_mut()
+            .kademlia
+            .store_mut()
+            .provided()
+            .any(|record| record.key == key);
+        let content = if provided {
+            self.db.get_message(&request.address).await
+        } else {
+            self.db.get_chunk(&request.address).await
+        };
+        let content = content.unwrap_or_else(|e| {
+            tracing::warn!("Failed to read block for {}: {}", peer, e);
+            None
+        });
+
+        if self
+            .swarm
+            .behaviour_mut()
+            .block
+            .send_response(channel, BlockResponse { content })
+            .is_err()
+        {
+            tracing::warn!("Connection to {} closed before the block was sent", peer);
+        }
+    }
+
+    /// Accepts signed messages for forwarding and hands them to subscribers.
+    ///
+    /// Strict validation has already checked the signature against the author's
+    /// peer id, messages without an author are rejected.
+    fn handle_pubsub_message(
+        &mut self,
+        propagation_source: PeerId,
+        message_id: gossipsub::MessageId,
+        message: gossipsub::Message,
+    ) {
+        let acceptance = match message.source {
+            Some(source) => {
+                let _ = self.event_sender.send(Event::PubSubMessage {
+                    topic: message.topic.into_string(),
+                    source,
+                    data: message.data,
+                });
+                gossipsub::MessageAcceptance::Accept
+            }
+            None => {
+                tracing::warn!("Rejected unsigned message from {}", propagation_source);
+                self.swarm
+                    .behaviour_mut()
+                    .peer_guard
+                    .penalize(&propagation_source, Misbehaviour::InvalidMessage);
+                gossipsub::MessageAcceptance::Reject
+            }
+        };
+
+        if let Err(e) = self
+            .swarm
+            .behaviour_mut()
+            .gossipsub
+            .report_message_validation_result(&message_id, &propagation_source, acceptance)
+        {
+            tracing::error!("Failed to report message validation result: {:?}", e);
+        }
+    }
+
+    /// Verifies a signed address record and checks it is not older than one already seen.
+    fn validate_address_record(&mut self, record: &Record) -> Result<NodeData, AddressRecordError> {
+        let signed = SignedNodeData::from_bytes(&record.value)?;
+        let peer_id = signed.verify(record.key.as_ref())?;
+        address_record::check_sequence(&mut self.address_sequences, peer_id, signed.sequence)?;
+        Ok(signed.node_data)
+    }
+
+    /// Publishes `address` as our clear address.
+    fn set_clear_address(&mut self, address: Multiaddr) {
+        let local_peer_id = *self.swarm.local_peer_id();
+        let address_type = address_type_of(&address);
+        let address = match address.iter().last() {
+            Some(Protocol::P2p(_)) => address,
+            _ => address.with(Protocol::P2p(local_peer_id.into())),
+        };
+        self.clear_address = Some(NodeData {
+            peer_id: local_peer_id.to_bytes(),
+            address: address.to_string(),
+            address_type,
+        });
+        self.publish_clear_address();
+    }
+
+    /// Ranks our own addresses for the address record, higher is better.
+    ///
+    /// A relayed address beats a direct one once AutoNAT has found we are
+    /// not reachable from outside, loopback addresses come last.
+    fn address_rank(&self, addr: &Multiaddr) -> u8 {
+        if is_relayed(addr) {
+            2
+        } else if is_loopback(addr) {
+            0
+        } else if matches!(self.nat_status, autonat::NatStatus::Private) {
+            1
+        } else {
+            3
+        }
+    }
+
+    /// Listens through a relay while we are not publicly reachable.
+    fn reserve_relay(&mut self) {
+        if !matches!(self.nat_status, autonat::NatStatus::Private) || self.relay_listener.is_some()
+        {
+            return;
+        }
+        let Some((relay, addr)) = self.relays.iter().next().map(|(p, a)| (*p, a.clone())) else {
+            tracing::debug!("Not reachable and no relay known yet");
+            return;
+        };
+
+        let circuit = addr
+            .with(Protocol::P2p(relay.into()))
+            .with(Protocol::P2pCircuit);
+        match self.swarm.listen_on(circuit.clone()) {
+            Ok(id) => {
+                tracing::info!("Listening through relay on {}", circuit);
+                self.relay_listener = Some((id, relay));
+            }
+            Err(e) => {
+                tracing::warn!("Failed to listen through relay {}: {:?}", relay, e);
+                self.relays.remove(&relay);
+            }
+        }
+    }
+
+    /// Supersedes our clear address record with one without an address, so peers stop serving
+    /// the address before the record would expire, and waits for peers to store it.
+    async fn withdraw_clear_address(&mut self) {
+        let Some(node_data) = self.clear_address.take() else {
+            return;
+        };
+        let tombstone = NodeData {
+            address: String::new(),
+            ..node_data
+        };
+        let Some(query_id) = self.put_address_record(tombstone) else {
+            return;
+        };
+
+        let stored = tokio::time::timeout(WITHDRAW_TIMEOUT, async {
+            while let Some(event) = self.swarm.next().await {
+                let done = matches!(
+                    &event,
+                    SwarmEvent::Behaviour(ComposedEvent::Kademlia(
+                        KademliaEvent::OutboundQueryProgressed {
+                            id,
+                            result: QueryResult::PutRecord(_),
+                            ..
+                        },
+                    )) if *id == query_id
+                );
+                self.handle_event(event).await;
+                if done {
+                    break;
+                }
+            }
+        })
+        .await;
+        if stored.is_err() {
+            tracing::warn!("Timed out withdrawing clear address record");
+        }
+    }
+
+    /// Returns the addresses we are listening on, including our peer id.
+    fn listen_addresses(&self) -> Vec<Multiaddr> {
+        let peer_id = (*self.swarm.local_peer_id()).into();
+        self.swarm
+            .listeners()
+            .map(|addr| addr.to_owned().with(Protocol::P2p(peer_id)))
+            .collect()
+    }
+
+    fn routing_table_size(&mut self) -> usize {
+        self.swarm
+            .behaviour_mut()
+            .kademlia
+            .kbuckets()
+            .map(|bucket| bucket.num_entries())
+            .sum()
+    }
+
+    /// Tells subscribers about records we stored and queries that finished.
+    fn notify_query_progress(&self, result: &QueryResult, last: bool) {
+        let key = match result {
+            QueryResult::PutRecord(Ok(PutRecordOk { key }))
+            | QueryResult::RepublishRecord(Ok(PutRecordOk { key }))
+            | QueryResult::StartProviding(Ok(AddProviderOk { key }))
+            | QueryResult::RepublishProvider(Ok(AddProviderOk { key })) => Some(key),
+            _ => None,
+        };
+        if let Some(key) = key {
+            let _ = self
+                .event_sender
+                .send(Event::RecordPublished { key: key.to_vec() });
+        }
+
+        if last {
+            let (kind, success) = query_outcome(result);
+            let _ = self
+                .event_sender
+                .send(Event::QueryCompleted { kind, success });
+        }
+    }
+
+    async fn handle_event(
+        &mut self,
+        event: SwarmEvent<ComposedEvent, THandlerErr<ComposedBehaviour>>,
+    ) {
+        if let SwarmEvent::Behaviour(ComposedEvent::Kademlia(
+            KademliaEvent::OutboundQueryProgressed { result, step, .. },
+        )) = &event
+        {
+            self.notify_query_progress(result, step.last);
+        }
+
+        match event {
+            SwarmEvent::Behaviour(ComposedEvent::Kademlia(
+                KademliaEvent::OutboundQueryProgressed {
+                    id,
+                    result: QueryResult::GetRecord(Ok(GetRecordOk::FoundRecord(peer_record))),
+                    ..
+                },
+            )) => {
+                let bundle = match self.validate_address_record(&peer_record.record) {
+                    Ok(r) => r,
+                    Err(e) => {
+                        // Keep waiting, another peer may still return a valid record
+                        tracing::warn!(
+                            "Rejected address record from {:?}: {}",
+                            peer_record.peer,
+                            e
+                        );
+                        match peer_record.peer {
+                            Some(peer) if e.is_misbehaviour() => self
+                                .swarm
+                                .behaviour_mut()
+                                .peer_guard
+                                .penalize(&peer, Misbehaviour::MalformedRecord),
+                            _ => {}
+                        }
+                        return;
+                    }
+                };
+
+                // The query keeps going after the first record, later ones are dropped
+                if let Some(sender) = self.pending_get_clear_address.remove(&id) {
+                    // A record without an address withdraws the peer's clear address
+                    if bundle.address.is_empty() {
+                        let _ = sender.send(Err(NetworkError::NotFound));
+                    } else {
+                        let _ = sender.send(Ok(bundle));
+                    }
+                }
+            }
+            SwarmEvent::Behaviour(ComposedEvent::Kademlia(
+                KademliaEvent::OutboundQueryProgressed {
+                    id,
+                    result:
+                        QueryResult::GetRecord(Ok(GetRecordOk::FinishedWithNoAdditionalRecord {
+                            ..
+                        })),
+                    ..
+                },
+            )) => {
+                // Every record found was rejected
+                if let Some(sender) = self.pending_get_clear_address.remove(&id) {
+                    let _ = sender.send(Err(NetworkError::NotFound));
+                }
+            }
+            SwarmEvent::Behaviour(ComposedEvent::Kademlia(KademliaEvent::InboundRequest {
+                request:
+                    InboundRequest::PutRecord {
+                        source,
+                        record: Some(record),
+                        ..
+                    },
+            })) => {
+                if !self.swarm.behaviour_mut().peer_guard.allow_request(&source) {
+                    tracing::debug!("Dropped address record from {} over the rate limit", source);
+                    return;
+                }
+                match self.validate_address_record(&record) {
+                    Ok(_) => {
+                        if let Err(e) = self.swarm.behaviour_mut().kademlia.store_mut().put(record)
+                        {
+                            tracing::error!(
+                                "Failed to store address record from {}: {:?}",
+                                source,
+                                e
+                            );
+                        }
+                    }
+                    Err(e) if e.is_misbehaviour() => {
+                        tracing::warn!("Rejected address record from {}: {}", source, e);
+                        self.swarm
+                            .behaviour_mut()
+                            .peer_guard
+                            .penalize(&source, Misbehaviour::MalformedRecord);
+                    }
+                    Err(e) => tracing::debug!("Dropped address record from {}: {}", source, e),
+                }
+            }
+            SwarmEvent::Behaviour(ComposedEvent::InboundProvider { source, record }) => {
+                if !self.swarm.behaviour_mut().peer_guard.allow_request(&source) {
+                    tracing::debug!(
+                        "Dropped provider record from {} over the rate limit",
+                        source
+                    );
+                    return;
+                }
+                if let Err(e) = self
+                    .swarm
+                    .behaviour_mut()
+                    .kademlia
+                    .store_mut()
+                    .add_provider(record)
+                {
+                    tracing::error!("Failed to store provider record: {:?}", e);
+                }
+            }
+            SwarmEvent::Behaviour(ComposedEvent::InboundQuery(source)) => {
+                // Kademlia has already answered, counting the query is what
+                // gets a peer flooding the DHT penalized and eventually banned
+                if !self.swarm.behaviour_mut().peer_guard.allow_request(&source) {
+                    tracing::debug!("DHT query from {} over the rate limit", source);
+                }
+            }
+            SwarmEvent::Behaviour(ComposedEvent::Kademlia(
+                KademliaEvent::OutboundQueryProgressed {
+                    id,
+                    result: QueryResult::PutRecord(result),
+                    ..
+                },
+            )) => {
+                match &result {
+                    Ok(PutRecordOk { key }) => {
+                        tracing::debug!("Published clear address record {:?}", key)
+                    }
+                    Err(e) => tracing::error!("Failed to publish clear address record: {:?}", e),
+                }
+                if let Some(sender) = self.pending_put_clear_address.remove(&id) {
+                    let _ = sender.send(result.map(|_| ()).map_err(NetworkError::from));
+                }
+            }
+            SwarmEvent::Behaviour(ComposedEvent::Kademlia(
+                KademliaEvent::OutboundQueryProgressed {
+                    id,
+                    result: QueryResult::GetRecord(Err(e)),
+                    ..
+                },
+            )) => {
+                if let Some(sender) = self.pending_get_clear_address.remove(&id) {
+                    let _ = sender.send(Err(e.into()));
+                }
+            }
+            SwarmEvent::Behaviour(ComposedEvent::Kademlia(
+                KademliaEvent::OutboundQueryProgressed {
+                    id,
+                    result:
+                        QueryResult::GetProviders(Ok(GetProvidersOk::FinishedWithNoAdditionalRecord {
+                            closest_peers,
+                            ..
+                        })),
+                    ..
+                },
+            )) => {
+                // Providers found in the local store have already been answered
+                if let Some(sender) = self.pending_get_providers.remove(&id) {
+                    let closest_peers_set = closest_peers.into_iter().collect::<HashSet<_>>();
+                    let _ = sender.send(Ok(closest_peers_set));
+                }
+            }
+            SwarmEvent::Behaviour(ComposedEvent::Kademlia(
+                KademliaEvent::OutboundQueryProgressed {
+                    id,
+                    result:
+                        QueryResult::GetProviders(Ok(GetProvidersOk::FoundProviders { providers, key })),
                     ..
                 },
             )) => {
@@ -415,11 +1632,20 @@ impl EventLoop {
                 if self.providing.contains(&key) {
                     providers.insert(*self.swarm.local_peer_id());
                 }
-                let _ = self
-                    .pending_get_providers
-                    .remove(&id)
-                    .expect("No pending get providers request.")
-                    .send(providers);
+                if let Some(sender) = self.pending_get_providers.remove(&id) {
+                    let _ = sender.send(Ok(providers));
+                }
+            }
+            SwarmEvent::Behaviour(ComposedEvent::Kademlia(
+                KademliaEvent::OutboundQueryProgressed {
+                    id,
+                    result: QueryResult::GetProviders(Err(_)),
+                    ..
+                },
+            )) => {
+                if let Some(sender) = self.pending_get_providers.remove(&id) {
+                    let _ = sender.send(Err(NetworkError::Timeout));
+                }
             }
             SwarmEvent::Behaviour(ComposedEvent::Kademlia(
                 KademliaEvent::OutboundQueryProgressed {
@@ -454,11 +1680,20 @@ impl EventLoop {
                     peer_id = local_peer_id;
                 }
 
-                let _ = self
-                    .pending_get_closest_peer
-                    .remove(&id)
-                    .expect("Completed query to previously pending")
-                    .send(Ok(peer_id));
+                if let Some(sender) = self.pending_get_closest_peer.remove(&id) {
+                    let _ = sender.send(Ok(peer_id));
+                }
+            }
+            SwarmEvent::Behaviour(ComposedEvent::Kademlia(
+                KademliaEvent::OutboundQueryProgressed {
+                    id,
+                    result: QueryResult::GetClosestPeers(Err(_)),
+                    ..
+                },
+            )) => {
+                if let Some(sender) = self.pending_get_closest_peer.remove(&id) {
+                    let _ = sender.send(Err(NetworkError::Timeout));
+                }
             }
             SwarmEvent::Behaviour(ComposedEvent::Kademlia(
                 KademliaEvent::OutboundQueryProgressed {
@@ -469,31 +1704,323 @@ impl EventLoop {
             )) => {
                 self.providing.insert(key);
                 if let Some(sender) = self.pending_start_providing.remove(&query_id) {
-                    let _ = sender.send(());
+                    let _ = sender.send(Ok(()));
+                }
+            }
+            SwarmEvent::Behaviour(ComposedEvent::Kademlia(
+                KademliaEvent::OutboundQueryProgressed {
+                    id: query_id,
+                    result: QueryResult::StartProviding(Err(_)),
+                    ..
+                },
+            )) => {
+                if let Some(sender) = self.pending_start_providing.remove(&query_id) {
+                    let _ = sender.send(Err(NetworkError::Timeout));
+                }
+            }
+            SwarmEvent::Behaviour(ComposedEvent::Kademlia(
+                KademliaEvent::OutboundQueryProgressed {
+                    id,
+                    result: QueryResult::Bootstrap(result),
+                    step,
+                    ..
+                },
+            )) => {
+                if !step.last {
+                    return;
+                }
+                let routing_table_size = self.routing_table_size();
+                let result = match result {
+                    Ok(_) if routing_table_size == 0 => Err(NetworkError::NoPeers),
+                    Ok(ok) => Ok(ok),
+                    Err(_) => Err(NetworkError::Timeout),
+                };
+                let _ = self.event_sender.send(Event::BootstrapFinished {
+                    routing_table_size,
+                    success: result.is_ok(),
+                });
+                if let Some(sender) = self.pending_bootstrap.remove(&id) {
+                    let _ = sender.send(result);
                 }
             }
             SwarmEvent::Behaviour(ComposedEvent::Kademlia(..)) => {}
+            SwarmEvent::Behaviour(ComposedEvent::PeerGuard(peer_guard::Event::Banned(banned))) => {
+                // Keep the peer out of queries and away from future dials
+                self.swarm
+                    .behaviour_mut()
+                    .kademlia
+                    .remove_peer(&banned.peer_id);
+                self.peer_store.remove(&banned.peer_id);
+                self.relays.remove(&banned.peer_id);
+            }
+            SwarmEvent::Behaviour(ComposedEvent::Identify(identify::Event::Received {
+                peer_id,
+                info,
+            })) => {
+                if info.protocol_version != PROTOCOL_VERSION {
+                    tracing::debug!(
+                        "Not adding peer {} speaking {}",
+                        peer_id,
+                        info.protocol_version
+                    );
+                    return;
+                }
+                if info.protocols.iter().any(|p| p == RELAY_HOP_PROTOCOL) {
+                    if let Some(addr) = info.listen_addrs.iter().find(|a| !is_relayed(a)) {
+                        self.relays.insert(peer_id, addr.clone());
+                        self.reserve_relay();
+                    }
+                }
+                // These are the addresses the peer listens on, unlike the ephemeral
+                // port an inbound connection comes from. Loopback and private addresses
+                // are only reachable when the peer saw us on one too, i.e. from the same
+                // host or local network.
+                let local_peer = !is_global(&info.observed_addr);
+                for addr in info.listen_addrs {
+                    if !local_peer && !is_global(&addr) {
+                        tracing::trace!("Ignoring local address {} of peer {}", addr, peer_id);
+                        continue;
+                    }
+                    tracing::debug!("Adding identified peer {} with address {}", peer_id, addr);
+                    if let Err(e) = self.peer_store.record_seen(&peer_id, &addr) {
+                        tracing::error!("Failed to record peer {}: {:?}", peer_id, e);
+                    }
+                    self.swarm
+                        .behaviour_mut()
+                        .kademlia
+                        .add_address(&peer_id, addr);
+                }
+            }
+            SwarmEvent::Behaviour(ComposedEvent::Identify(..)) => {}
+            SwarmEvent::Behaviour(ComposedEvent::Ping(ping::Event { peer, result })) => {
+                tracing::trace!("Ping {}: {:?}", peer, result);
+            }
+            SwarmEvent::Behaviour(ComposedEvent::Direct(request_response::Event::Message {
+                peer,
+                message,
+            })) => match message {
+                request_response::Message::Request {
+                    request, channel, ..
+                } => self.handle_direct_message(peer, request, channel),
+                request_response::Message::Response {
+                    request_id,
+                    response,
+                } => {
+                    if let Some(sender) = self.pending_send_direct.remove(&request_id) {
+                        let result = if response.accepted {
+                            Ok(())
+                        } else {
+                            tracing::debug!("Peer {} rejected message: {}", peer, response.error);
+                            Err(NetworkError::Rejected(response.error))
+                        };
+                        let _ = sender.send(result);
+                    }
+                }
+            },
+            SwarmEvent::Behaviour(ComposedEvent::Direct(
+                request_response::Event::OutboundFailure {
+                    request_id, error, ..
+                },
+            )) => {
+                if let Some(sender) = self.pending_send_direct.remove(&request_id) {
+                    let _ = sender.send(Err(error.into()));
+                }
+            }
+            SwarmEvent::Behaviour(ComposedEvent::Direct(
+                request_response::Event::InboundFailure { peer, error, .. },
+            )) => {
+                tracing::warn!(
+                    "Failed to receive direct message from {}: {:?}",
+                    peer,
+                    error
+                );
+            }
+            SwarmEvent::Behaviour(ComposedEvent::Direct(
+                request_response::Event::ResponseSent { .. },
+            )) => {}
+            SwarmEvent::Behaviour(ComposedEvent::Block(request_response::Event::Message {
+                peer,
+                message,
+            })) => match message {
+                request_response::Message::Request {
+                    request, channel, ..
+                } => self.handle_block_request(peer, request, channel).await,
+                request_response::Message::Response {
+                    request_id,
+                    response,
+                } => {
+                    if let Some(sender) = self.pending_fetch_block.remove(&request_id) {
+                        let _ = sender.send(Ok(response.content));
+                    }
+                }
+            },
+            SwarmEvent::Behaviour(ComposedEvent::Block(
+                request_response::Event::OutboundFailure {
+                    request_id, error, ..
+                },
+            )) => {
+                if let Some(sender) = self.pending_fetch_block.remove(&request_id) {
+                    let _ = sender.send(Err(error.into()));
+                }
+            }
+            SwarmEvent::Behaviour(ComposedEvent::Block(
+                request_response::Event::InboundFailure { peer, error, .. },
+            )) => {
+                tracing::warn!("Failed to serve block to {}: {:?}", peer, error);
+            }
+            SwarmEvent::Behaviour(ComposedEvent::Block(
+                request_response::Event::ResponseSent { .. },
+            )) => {}
+            SwarmEvent::Behaviour(ComposedEvent::Gossipsub(gossipsub::Event::Message {
+                propagation_source,
+                message_id,
+                message,
+            })) => self.handle_pubsub_message(propagation_source, message_id, message),
+            SwarmEvent::Behaviour(ComposedEvent::Gossipsub(..)) => {}
+            SwarmEvent::Behaviour(ComposedEvent::Mdns(mdns::Event::Discovered(peers))) => {
+                let mut discovered: HashMap<PeerId, Vec<Multiaddr>> = HashMap::new();
+                for (peer_id, addr) in peers {
+                    tracing::debug!("Discovered local peer {} at {}", peer_id, addr);
+                    self.swarm
+                        .behaviour_mut()
+                        .kademlia
+                        .add_address(&peer_id, addr.clone());
+                    discovered.entry(peer_id).or_default().push(addr);
+                }
+                // Dialing by peer id checks the connection reaches the announced peer
+                for (peer_id, addresses) in discovered {
+                    let opts = DialOpts::peer_id(peer_id)
+                        .addresses(addresses)
+                        .condition(PeerCondition::Disconnected)
+                        .build();
+                    if let Err(e) = self.swarm.dial(opts) {
+                        tracing::debug!("Failed to dial local peer {}: {:?}", peer_id, e);
+                    }
+                }
+            }
+            SwarmEvent::Behaviour(ComposedEvent::Mdns(mdns::Event::Expired(peers))) => {
+                for (peer_id, addr) in peers {
+                    tracing::debug!("Local peer {} at {} expired", peer_id, addr);
+                    self.swarm
+                        .behaviour_mut()
+                        .kademlia
+                        .remove_address(&peer_id, &addr);
+                }
+            }
             SwarmEvent::NewListenAddr { address, .. } => {
-                let local_peer_id = *self.swarm.local_peer_id();
-                println!(
-                    "Local node is listening on {:?}",
-                    address.with(Protocol::P2p(local_peer_id.into()))
-                )
+                println!("Local node is listening on {:?}", address);
+                let _ = self.event_sender.send(Event::ListenAddressAdded {
+                    address: address.clone(),
+                });
+
+                // Prefer the best address found so far unless one was set explicitly
+                let replace = match &self.clear_address {
+                    None => !self.tor,
+                    Some(current) => {
+                        !self.clear_address_pinned
+                            && current
+                                .address
+                                .parse::<Multiaddr>()
+                                .map(|a| self.address_rank(&address) > self.address_rank(&a))
+                                .unwrap_or(true)
+                    }
+                };
+                if replace {
+                    self.set_clear_address(address);
+                }
+            }
+            SwarmEvent::ListenerClosed { listener_id, .. } => {
+                if let Some((id, relay)) = self.relay_listener {
+                    if id == listener_id {
+                        tracing::info!("Lost relay reservation with {}", relay);
+                        self.relay_listener = None;
+                        self.relays.remove(&relay);
+                        self.reserve_relay();
+                    }
+                }
+            }
+            SwarmEvent::Behaviour(ComposedEvent::Autonat(autonat::Event::StatusChanged {
+                old,
+                new,
+            })) => {
+                tracing::info!("Reachability changed from {:?} to {:?}", old, new);
+                self.nat_status = new;
+                match &self.nat_status {
+                    autonat::NatStatus::Public(address) => {
+                        let address = address.clone();
+                        if !self.clear_address_pinned {
+                            self.set_clear_address(address);
+                        }
+                    }
+                    autonat::NatStatus::Private => self.reserve_relay(),
+                    autonat::NatStatus::Unknown => {}
+                }
+            }
+            SwarmEvent::Behaviour(ComposedEvent::Autonat(event)) => {
+                tracing::trace!("AutoNAT: {:?}", event);
+            }
+            SwarmEvent::Behaviour(ComposedEvent::RelayClient(
+                relay::client::Event::ReservationReqFailed { relay_peer_id, .. },
+            )) => {
+                tracing::warn!("Relay {} refused our reservation", relay_peer_id);
+                self.relays.remove(&relay_peer_id);
+                if let Some((id, relay)) = self.relay_listener {
+                    if relay == relay_peer_id {
+                        self.swarm.remove_listener(id);
+                    }
+                }
+            }
+            SwarmEvent::Behaviour(ComposedEvent::RelayClient(event)) => {
+                tracing::debug!("Relay client: {:?}", event);
+            }
+            SwarmEvent::Behaviour(ComposedEvent::RelayServer(event)) => {
+                tracing::debug!("Relay server: {:?}", event);
+            }
+            SwarmEvent::Behaviour(ComposedEvent::Dcutr(event)) => {
+                tracing::debug!("Hole punching: {:?}", event);
+            }
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                num_established,
+                ..
+            } => {
+                if num_established == 0 {
+                    let _ = self
+                        .event_sender
+                        .send(Event::PeerDisconnected { peer: peer_id });
+                }
             }
-            SwarmEvent::ConnectionClosed { .. } => {}
             SwarmEvent::ConnectionEstablished {
-                peer_id, endpoint, ..
+                peer_id,
+                endpoint,
+                num_established,
+                ..
             } => {
-                println!(
-                    "Adding peer {} with address {}",
-                    peer_id,
-                    endpoint.get_remote_address().clone()
-                );
-                self.swarm
-                    .behaviour_mut()
-                    .kademlia
-                    .add_address(&peer_id, endpoint.get_remote_address().clone());
+                if num_established.get() == 1 {
+                    let _ = self.event_sender.send(Event::PeerConnected {
+                        peer: peer_id,
+                        address: endpoint.get_remote_address().clone(),
+                    });
+                }
+
+                // Only a dialed address is known to be reachable, listeners learn
+                // the peer's addresses through identify
                 if endpoint.is_dialer() {
+                    println!(
+                        "Adding peer {} with address {}",
+                        peer_id,
+                        endpoint.get_remote_address().clone()
+                    );
+                    self.swarm
+                        .behaviour_mut()
+                        .kademlia
+                        .add_address(&peer_id, endpoint.get_remote_address().clone());
+                    if let Err(e) = self
+                        .peer_store
+                        .record_seen(&peer_id, endpoint.get_remote_address())
+                    {
+                        tracing::error!("Failed to record peer {}: {:?}", peer_id, e);
+                    }
                     if let Some(sender) = self.pending_dial.remove(&peer_id) {
                         let _ = sender.send(Ok(()));
                     }
@@ -506,22 +2033,17 @@ impl EventLoop {
             } => {
                 tracing::debug!("local address: {:?}", local_addr);
                 tracing::debug!("send back address: {:?}", send_back_addr);
-                let mut remote_addr = send_back_addr.clone();
-                let remote_port = remote_addr.pop().unwrap();
-                let new_remote_port = match remote_port {
-                    Protocol::Tcp(p) => Protocol::Tcp(p - 1),
-                    _ => {
-                        tracing::error!("This is a fatal error this shouldn't have happened");
-                        remote_port
-                    }
-                };
-                remote_addr.push(new_remote_port);
-                self.swarm
-                    .dial(remote_addr)
-                    .expect("Error dialing send back addr");
             }
-            SwarmEvent::OutgoingConnectionError { error, .. } => {
+            SwarmEvent::OutgoingConnectionError { peer_id, error } => {
                 tracing::error!("Had outgoing connection error {:?}", &error);
+                if let Some(peer_id) = peer_id {
+                    if let Err(e) = self.peer_store.record_failure(&peer_id) {
+                        tracing::error!("Failed to record dial failure: {:?}", e);
+                    }
+                    if let Some(sender) = self.pending_dial.remove(&peer_id) {
+                        let _ = sender.send(Err(anyhow::Error::from(error).into()));
+                    }
+                }
             }
             e => {
                 tracing::error!("Unknown event: {:?}", e)
@@ -529,34 +2051,559 @@ impl EventLoop {
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn new(
         swarm: libp2p::Swarm<ComposedBehaviour>,
         command_receiver: mpsc::Receiver<Command>,
+        event_sender: broadcast::Sender<Event>,
+        keypair: Keypair,
+        bootstrap_nodes: Vec<Multiaddr>,
+        peer_store: PeerStore,
+        db: OpenBazaarDb,
+        tor: bool,
     ) -> Self {
         Self {
             swarm,
+            event_sender,
+            keypair,
             command_receiver,
             pending_dial: Default::default(),
             pending_start_providing: Default::default(),
             pending_get_providers: Default::default(),
             pending_get_closest_peer: Default::default(),
             pending_get_clear_address: Default::default(),
+            pending_put_clear_address: Default::default(),
+            pending_bootstrap: Default::default(),
+            pending_send_direct: Default::default(),
+            pending_fetch_block: Default::default(),
             providing: Default::default(),
+            bootstrap_nodes,
+            peer_store,
+            db,
+            clear_address: None,
+            clear_address_pinned: false,
+            clear_address_sequence: 0,
+            address_sequences: Default::default(),
+            topic_subscribers: Default::default(),
+            nat_status: autonat::NatStatus::Unknown,
+            relays: Default::default(),
+            relay_listener: None,
+            tor,
+            started: Instant::now(),
         }
     }
 
     pub async fn run(&mut self) {
+        let mut republish = tokio::time::interval(CLEAR_ADDRESS_REPUBLISH_INTERVAL);
+        // The first tick completes immediately and there is nothing to publish yet
+        republish.tick().await;
+
         loop {
             tokio::select! {
                 event = self.swarm.next() => {
-                    println!("Event => {:?}", event);
+                    tracing::trace!("Event => {:?}", event);
                     self.handle_event(event.unwrap()).await
                 },
                 command = self.command_receiver.recv() => match command {
+                    Some(Command::Shutdown { sender }) => {
+                        self.withdraw_clear_address().await;
+                        let _ = sender.send(Ok(()));
+                        return;
+                    }
                     Some(c) => self.handle_command(c).await,
-                    None => return,
+                    None => {
+                        self.withdraw_clear_address().await;
+                        return;
+                    }
+                },
+                _ = republish.tick() => {
+                    self.publish_clear_address();
                 }
             }
         }
     }
 }
+
+fn address_type_of(addr: &Multiaddr) -> NodeAddressType {
+    if is_relayed(addr) {
+        return NodeAddressType::Relay;
+    }
+    match addr.iter().next() {
+        Some(Protocol::Onion3(_)) => NodeAddressType::Onion,
+        Some(Protocol::Ip4(_)) => NodeAddressType::Ipv4,
+        Some(Protocol::Ip6(_)) => NodeAddressType::Ipv6,
+        _ => NodeAddressType::Clear,
+    }
+}
+
+fn is_relayed(addr: &Multiaddr) -> bool {
+    addr.iter().any(|p| p == Protocol::P2pCircuit)
+}
+
+fn is_loopback(addr: &Multiaddr) -> bool {
+    match addr.iter().next() {
+        Some(Protocol::Ip4(ip)) => ip.is_loopback(),
+        Some(Protocol::Ip6(ip)) => ip.is_loopback(),
+        _ => false,
+    }
+}
+
+/// Whether `addr` can be reached from the internet, DNS names and onion addresses are assumed
+/// to be.
+fn is_global(addr: &Multiaddr) -> bool {
+    match addr.iter().next() {
+        Some(Protocol::Ip4(ip)) => {
+            let [a, b, ..] = ip.octets();
+            // 100.64.0.0/10 is shared address space used behind carrier-grade NAT
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || (a == 100 && (b & 0xc0) == 64))
+        }
+        Some(Protocol::Ip6(ip)) => {
+            let first = ip.segments()[0];
+            // fc00::/7 are unique local and fe80::/10 link-local addresses
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || (first & 0xfe00) == 0xfc00
+                || (first & 0xffc0) == 0xfe80)
+        }
+        _ => true,
+    }
+}
+
+/// Waits for `client` to report the address it listens on, which is only known once the
+/// transport has bound its port.
+#[cfg(test)]
+pub async fn wait_for_listen_addr(client: &Client) -> Multiaddr {
+    for _ in 0..50 {
+        if let Some(addr) = client.get_listen_addresses().await.unwrap().pop() {
+            return addr;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("Node never reported its listen address");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bootnodes_name_their_peer_id() {
+        for addr in BOOTNODES {
+            let addr: Multiaddr = addr.parse().unwrap();
+            let Some(Protocol::P2p(hash)) = addr.iter().last() else {
+                panic!("No peer id in {}", addr);
+            };
+            assert!(PeerId::from_multihash(hash).is_ok());
+        }
+    }
+
+    #[test]
+    fn only_internet_addresses_are_global() {
+        for addr in [
+            "/ip4/127.0.0.1/tcp/4001",
+            "/ip4/192.168.1.10/tcp/4001",
+            "/ip4/100.64.0.1/tcp/4001",
+            "/ip6/::1/tcp/4001",
+            "/ip6/fd00::1/tcp/4001",
+            "/ip6/fe80::1/tcp/4001",
+        ] {
+            assert!(!is_global(&addr.parse().unwrap()), "{}", addr);
+        }
+        for addr in [
+            "/ip4/1.2.3.4/tcp/4001",
+            "/ip6/2001:4860::1/udp/4001/quic-v1",
+            "/dns4/example.com/tcp/443/wss",
+        ] {
+            assert!(is_global(&addr.parse().unwrap()), "{}", addr);
+        }
+    }
+
+    #[tokio::test]
+    async fn providers_survive_event_loop_restart() {
+        let db = OpenBazaarDb::temporary();
+        let keypair = Keypair::generate_ed25519();
+        let local_peer_id = keypair.public().to_peer_id();
+        let share_addr: ShareAddress = b"openbazaar-listing".to_vec();
+
+        let (client, mut event_loop) = new(keypair.clone(), &db, Default::default()).await.unwrap();
+        let handle = tokio::spawn(async move { event_loop.run().await });
+        client.start_providing(share_addr.clone()).await.unwrap();
+        handle.abort();
+        let _ = handle.await;
+
+        let (client, mut event_loop) = new(keypair, &db, Default::default()).await.unwrap();
+        tokio::spawn(async move { event_loop.run().await });
+        let providers = client.get_providers(share_addr).await.unwrap();
+
+        assert!(providers.contains(&local_peer_id));
+    }
+
+    async fn connect_over(listen_addr: &str) {
+        let keypair = Keypair::generate_ed25519();
+        let listener_peer_id = keypair.public().to_peer_id();
+        let (mut listener, mut event_loop) =
+            new(keypair, &OpenBazaarDb::temporary(), Default::default())
+                .await
+                .unwrap();
+        tokio::spawn(async move { event_loop.run().await });
+        listener
+            .start_listening(listen_addr.parse().unwrap())
+            .await
+            .unwrap();
+
+        let addr = wait_for_listen_addr(&listener).await;
+
+        let (mut dialer, mut event_loop) = new(
+            Keypair::generate_ed25519(),
+            &OpenBazaarDb::temporary(),
+            Default::default(),
+        )
+        .await
+        .unwrap();
+        tokio::spawn(async move { event_loop.run().await });
+        dialer.dial(listener_peer_id, addr).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn connections_are_reported_as_events() {
+        let keypair = Keypair::generate_ed25519();
+        let listener_peer_id = keypair.public().to_peer_id();
+        let (mut listener, mut event_loop) =
+            new(keypair, &OpenBazaarDb::temporary(), Default::default())
+                .await
+                .unwrap();
+        let mut listener_events = listener.events();
+        tokio::spawn(async move { event_loop.run().await });
+        listener
+            .start_listening("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .await
+            .unwrap();
+
+        let addr = loop {
+            if let Event::ListenAddressAdded { address } = listener_events.recv().await.unwrap() {
+                break address;
+            }
+        };
+
+        let (mut dialer, mut event_loop) = new(
+            Keypair::generate_ed25519(),
+            &OpenBazaarDb::temporary(),
+            Default::default(),
+        )
+        .await
+        .unwrap();
+        let mut dialer_events = dialer.events();
+        tokio::spawn(async move { event_loop.run().await });
+        dialer.dial(listener_peer_id, addr.clone()).await.unwrap();
+
+        let event = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Event::PeerConnected { peer, address } = dialer_events.recv().await.unwrap()
+                {
+                    break (peer, address);
+                }
+            }
+        })
+        .await
+        .expect("Dialer to report the connection");
+        assert_eq!(event, (listener_peer_id, addr));
+    }
+
+    #[tokio::test]
+    async fn provided_blocks_are_served() {
+        let db = OpenBazaarDb::temporary();
+        let keypair = Keypair::generate_ed25519();
+        let provider_peer_id = keypair.public().to_peer_id();
+        let (mut provider, mut event_loop) = new(keypair, &db, Default::default()).await.unwrap();
+        tokio::spawn(async move { event_loop.run().await });
+        provider
+            .start_listening("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .await
+            .unwrap();
+
+        let share_addr: ShareAddress = b"openbazaar-listing".to_vec();
+        db.save_message(&share_addr, b"listing").await.unwrap();
+        db.save_message(b"private", b"not provided").await.unwrap();
+        provider.start_providing(share_addr.clone()).await.unwrap();
+
+        let addr = wait_for_listen_addr(&provider).await;
+
+        let (fetcher, mut event_loop) = new(
+            Keypair::generate_ed25519(),
+            &OpenBazaarDb::temporary(),
+            Default::default(),
+        )
+        .await
+        .unwrap();
+        tokio::spawn(async move { event_loop.run().await });
+
+        let block = fetcher
+            .fetch_block(provider_peer_id, vec![addr], share_addr)
+            .await
+            .unwrap();
+        assert_eq!(block, Some(b"listing".to_vec()));
+
+        let block = fetcher
+            .fetch_block(provider_peer_id, Vec::new(), b"private".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(block, None);
+    }
+
+    #[tokio::test]
+    async fn withdrawn_clear_address_is_not_served() {
+        let (mut reader, mut event_loop) = new(
+            Keypair::generate_ed25519(),
+            &OpenBazaarDb::temporary(),
+            Default::default(),
+        )
+        .await
+        .unwrap();
+        tokio::spawn(async move { event_loop.run().await });
+        reader
+            .start_listening("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .await
+            .unwrap();
+        let reader_peer_id = reader.get_peer_id().await.unwrap();
+
+        let addr = wait_for_listen_addr(&reader).await;
+
+        let keypair = Keypair::generate_ed25519();
+        let publisher_peer_id = keypair.public().to_peer_id();
+        let (mut publisher, mut event_loop) =
+            new(keypair, &OpenBazaarDb::temporary(), Default::default())
+                .await
+                .unwrap();
+        let handle = tokio::spawn(async move { event_loop.run().await });
+        publisher.dial(reader_peer_id, addr).await.unwrap();
+
+        // The reader joins the publisher's routing table once identify has run
+        let address = format!("/ip4/192.0.2.1/tcp/4001/p2p/{}", publisher_peer_id);
+        let mut published = false;
+        for _ in 0..50 {
+            if publisher
+                .put_clear_address(NodeAddressType::Clear, address.clone())
+                .await
+                .is_ok()
+            {
+                published = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert!(
+            published,
+            "Publisher to store its clear address on the reader"
+        );
+        let node_data = reader.get_clear_address(publisher_peer_id).await.unwrap();
+        assert_eq!(node_data.address, address);
+
+        publisher.shutdown().await.unwrap();
+        let _ = handle.await;
+
+        let result = reader.get_clear_address(publisher_peer_id).await;
+        assert!(
+            matches!(result, Err(NetworkError::NotFound)),
+            "{:?}",
+            result
+        );
+    }
+
+    #[tokio::test]
+    async fn relayed_address_is_published_when_unreachable() {
+        // Relays hand out their external addresses in reservations, so give the relay one
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let relay_addr: Multiaddr = format!("/ip4/127.0.0.1/tcp/{}", port).parse().unwrap();
+        let keypair = Keypair::generate_ed25519();
+        let relay_peer_id = keypair.public().to_peer_id();
+        let config = NetworkConfig {
+            relay_server: true,
+            ..Default::default()
+        };
+        let (mut relay, mut event_loop) = new(keypair, &OpenBazaarDb::temporary(), config)
+            .await
+            .unwrap();
+        event_loop
+            .swarm
+            .add_external_address(relay_addr.clone(), AddressScore::Infinite);
+        tokio::spawn(async move { event_loop.run().await });
+        relay.start_listening(relay_addr.clone()).await.unwrap();
+
+        let keypair = Keypair::generate_ed25519();
+        let node_peer_id = keypair.public().to_peer_id();
+        let (mut node, mut event_loop) =
+            new(keypair, &OpenBazaarDb::temporary(), Default::default())
+                .await
+                .unwrap();
+        // AutoNAT needs peers on the internet to find this out
+        event_loop.nat_status = autonat::NatStatus::Private;
+        let mut node_events = node.events();
+        tokio::spawn(async move { event_loop.run().await });
+        node.dial(relay_peer_id, relay_addr).await.unwrap();
+
+        let relayed = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                if let Event::ListenAddressAdded { address } = node_events.recv().await.unwrap() {
+                    if is_relayed(&address) {
+                        break address;
+                    }
+                }
+            }
+        })
+        .await
+        .expect("Node to listen through the relay");
+
+        let mut published = None;
+        for _ in 0..50 {
+            if let Ok(node_data) = relay.get_clear_address(node_peer_id).await {
+                published = Some(node_data);
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        let node_data = published.expect("Node to publish its relayed address");
+        assert!(
+            node_data.address.starts_with(&relayed.to_string()),
+            "{} is not {}",
+            node_data.address,
+            relayed
+        );
+        assert_eq!(node_data.address_type, NodeAddressType::Relay);
+    }
+
+    #[tokio::test]
+    async fn peers_flooding_dht_queries_are_banned() {
+        let config = NetworkConfig {
+            peer_guard: PeerGuardConfig {
+                max_requests_per_window: 1,
+                ban_threshold: -20,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (mut node, mut event_loop) = new(
+            Keypair::generate_ed25519(),
+            &OpenBazaarDb::temporary(),
+            config,
+        )
+        .await
+        .unwrap();
+        tokio::spawn(async move { event_loop.run().await });
+        node.start_listening("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .await
+            .unwrap();
+        let node_peer_id = node.get_peer_id().await.unwrap();
+
+        let addr = wait_for_listen_addr(&node).await;
+
+        let keypair = Keypair::generate_ed25519();
+        let flooder_peer_id = keypair.public().to_peer_id();
+        let (mut flooder, mut event_loop) =
+            new(keypair, &OpenBazaarDb::temporary(), Default::default())
+                .await
+                .unwrap();
+        tokio::spawn(async move { event_loop.run().await });
+        flooder.dial(node_peer_id, addr).await.unwrap();
+
+        let mut banned = false;
+        for _ in 0..50 {
+            let _ = flooder.get_providers(b"openbazaar-listing".to_vec()).await;
+            let bans = node.list_banned_peers().await.unwrap();
+            if bans.iter().any(|ban| ban.peer_id == flooder_peer_id) {
+                banned = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert!(banned, "Node to ban the peer flooding it with queries");
+    }
+
+    #[tokio::test]
+    async fn topic_is_left_with_its_last_subscriber() {
+        let (client, mut event_loop) = new(
+            Keypair::generate_ed25519(),
+            &OpenBazaarDb::temporary(),
+            Default::default(),
+        )
+        .await
+        .unwrap();
+        tokio::spawn(async move { event_loop.run().await });
+        let topic = "openbazaar-listings".to_string();
+
+        client.subscribe(topic.clone()).await.unwrap();
+        client.subscribe(topic.clone()).await.unwrap();
+        client.unsubscribe(topic.clone()).await.unwrap();
+        client.unsubscribe(topic.clone()).await.unwrap();
+
+        let result = client.unsubscribe(topic).await;
+        assert!(
+            matches!(result, Err(NetworkError::NotFound)),
+            "{:?}",
+            result
+        );
+    }
+
+    #[tokio::test]
+    async fn local_peers_are_discovered_over_mdns() {
+        let config = NetworkConfig {
+            mdns: true,
+            ..Default::default()
+        };
+        let mut peer_ids = Vec::new();
+        let mut clients = Vec::new();
+        let mut events = Vec::new();
+        for _ in 0..2 {
+            let keypair = Keypair::generate_ed25519();
+            peer_ids.push(keypair.public().to_peer_id());
+            let (mut client, mut event_loop) =
+                new(keypair, &OpenBazaarDb::temporary(), config.clone())
+                    .await
+                    .unwrap();
+            events.push(client.events());
+            tokio::spawn(async move { event_loop.run().await });
+            client
+                .start_listening("/ip4/0.0.0.0/tcp/0".parse().unwrap())
+                .await
+                .unwrap();
+            // The event loop stops once its clients are dropped
+            clients.push(client);
+        }
+
+        let mut first_events = events.remove(0);
+        let peer = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                if let Event::PeerConnected { peer, .. } = first_events.recv().await.unwrap() {
+                    break peer;
+                }
+            }
+        })
+        .await
+        .expect("Nodes to find each other over mDNS");
+        assert_eq!(peer, peer_ids[1]);
+    }
+
+    #[tokio::test]
+    async fn nodes_connect_over_tcp() {
+        connect_over("/ip4/127.0.0.1/tcp/0").await;
+    }
+
+    #[tokio::test]
+    async fn nodes_connect_over_quic() {
+        connect_over("/ip4/127.0.0.1/udp/0/quic-v1").await;
+    }
+
+    #[tokio::test]
+    async fn nodes_connect_over_websocket() {
+        connect_over("/ip4/127.0.0.1/tcp/0/ws").await;
+    }
+}
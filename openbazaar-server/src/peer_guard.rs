@@ -0,0 +1,437 @@
+use libp2p::core::Endpoint;
+use libp2p::multiaddr::Protocol;
+use libp2p::swarm::{
+    dummy, CloseConnection, ConnectionClosed, ConnectionDenied, ConnectionId, FromSwarm,
+    ListenFailure, NetworkBehaviour, PollParameters, THandler, THandlerInEvent, THandlerOutEvent,
+    ToSwarm,
+};
+use libp2p::Multiaddr;
+use libp2p_identity::PeerId;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::net::IpAddr;
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+use void::Void;
+
+use crate::address_record::unix_now;
+use crate::db::OpenBazaarDb;
+
+const BANS_TREE: &str = "banned_peers";
+
+// Points a penalized peer earns back every minute, scores never go above zero
+const SCORE_RECOVERY_PER_MINUTE: i32 = 5;
+
+/// Configuration for connection limits and the `PeerGuard`.
+#[derive(Debug, Clone)]
+pub struct PeerGuardConfig {
+    /// Established connections across all peers.
+    pub max_connections: u32,
+    /// Established connections to a single peer.
+    pub max_connections_per_peer: u32,
+    /// Inbound connections from a single IP address, loopback addresses are
+    /// exempt as every connection through Tor comes from one.
+    pub max_connections_per_ip: u32,
+    /// Requests a peer may send within `rate_limit_window` before every
+    /// further one is penalized.
+    pub max_requests_per_window: u32,
+    pub rate_limit_window: Duration,
+    /// Peers whose score drops to this value are banned.
+    pub ban_threshold: i32,
+    pub ban_duration: Duration,
+}
+
+impl Default for PeerGuardConfig {
+    fn default() -> Self {
+        Self {
+            max_connections: 128,
+            max_connections_per_peer: 4,
+            max_connections_per_ip: 8,
+            max_requests_per_window: 100,
+            rate_limit_window: Duration::from_secs(60),
+            ban_threshold: -100,
+            ban_duration: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+}
+
+/// Behaviour a peer is penalized for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Misbehaviour {
+    /// Sent a DHT record that failed validation
+    MalformedRecord,
+    /// Sent a pub/sub message that failed validation
+    InvalidMessage,
+    /// Sent more requests than allowed by the rate limit
+    RequestFlood,
+}
+
+impl Misbehaviour {
+    fn penalty(self) -> i32 {
+        match self {
+            Misbehaviour::MalformedRecord => 25,
+            Misbehaviour::InvalidMessage => 10,
+            Misbehaviour::RequestFlood => 20,
+        }
+    }
+}
+
+impl fmt::Display for Misbehaviour {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Misbehaviour::MalformedRecord => write!(f, "malformed record"),
+            Misbehaviour::InvalidMessage => write!(f, "invalid message"),
+            Misbehaviour::RequestFlood => write!(f, "too many requests"),
+        }
+    }
+}
+
+/// A peer we refuse connections from.
+#[derive(Clone, Debug)]
+pub struct BannedPeer {
+    pub peer_id: PeerId,
+    /// Seconds since the unix epoch at which the ban is lifted
+    pub until: u64,
+    pub reason: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct StoredBan {
+    until: u64,
+    reason: String,
+}
+
+/// Events emitted by the `PeerGuard`.
+#[derive(Debug)]
+pub enum Event {
+    /// A peer was banned and its connections are being closed.
+    Banned(BannedPeer),
+}
+
+#[derive(Debug, thiserror::Error)]
+enum Denied {
+    #[error("peer is banned until {0}")]
+    Banned(u64),
+    #[error("too many connections from {0}")]
+    TooManyFromIp(IpAddr),
+}
+
+struct Score {
+    value: i32,
+    updated: Instant,
+}
+
+struct RequestWindow {
+    started: Instant,
+    count: u32,
+}
+
+/// Keeps misbehaving peers off the node.
+///
+/// Limits inbound connections per IP address, scores peers for the
+/// misbehaviour reported by the event loop and bans them for a while once
+/// their score drops too low. Bans are persisted in the node's database so
+/// they survive restarts.
+pub struct PeerGuard {
+    config: PeerGuardConfig,
+    bans: sled::Tree,
+    scores: HashMap<PeerId, Score>,
+    requests: HashMap<PeerId, RequestWindow>,
+    connections_per_ip: HashMap<IpAddr, HashSet<ConnectionId>>,
+    connection_ips: HashMap<ConnectionId, IpAddr>,
+    pending: VecDeque<ToSwarm<Event, Void>>,
+    waker: Option<Waker>,
+}
+
+impl PeerGuard {
+    pub fn new(db: &OpenBazaarDb, config: PeerGuardConfig) -> anyhow::Result<Self> {
+        let guard = Self {
+            config,
+            bans: db.db.open_tree(BANS_TREE)?,
+            scores: Default::default(),
+            requests: Default::default(),
+            connections_per_ip: Default::default(),
+            connection_ips: Default::default(),
+            pending: Default::default(),
+            waker: None,
+        };
+        // Listing drops the bans that expired while we were offline
+        guard.banned_peers();
+        Ok(guard)
+    }
+
+    /// Lowers the score of `peer`, banning it once the threshold is reached.
+    pub fn penalize(&mut self, peer: &PeerId, misbehaviour: Misbehaviour) {
+        let now = Instant::now();
+        let score = self.scores.entry(*peer).or_insert(Score {
+            value: 0,
+            updated: now,
+        });
+        let minutes = now.duration_since(score.updated).as_secs() / 60;
+        let recovered = (minutes as i32).saturating_mul(SCORE_RECOVERY_PER_MINUTE);
+        score.value = score.value.saturating_add(recovered).min(0) - misbehaviour.penalty();
+        score.updated = now;
+
+        tracing::debug!(
+            "Penalized {} for {}, score is now {}",
+            peer,
+            misbehaviour,
+            score.value
+        );
+        if score.value <= self.config.ban_threshold {
+            self.ban(peer, misbehaviour.to_string());
+        }
+    }
+
+    /// Counts a request from `peer`, returns false if it is over the rate
+    /// limit and should be dropped.
+    pub fn allow_request(&mut self, peer: &PeerId) -> bool {
+        let now = Instant::now();
+        let window = self.requests.entry(*peer).or_insert(RequestWindow {
+            started: now,
+            count: 0,
+        });
+        if now.duration_since(window.started) >= self.config.rate_limit_window {
+            window.started = now;
+            window.count = 0;
+        }
+        window.count += 1;
+
+        if window.count > self.config.max_requests_per_window {
+            self.penalize(peer, Misbehaviour::RequestFlood);
+            return false;
+        }
+        true
+    }
+
+    /// Bans `peer` for the configured duration and closes its connections.
+    pub fn ban(&mut self, peer: &PeerId, reason: String) {
+        let until = unix_now().as_secs() + self.config.ban_duration.as_secs();
+        let stored = StoredBan {
+            until,
+            reason: reason.clone(),
+        };
+        let bytes = bincode::serialize(&stored).expect("Failed to serialize ban");
+        if let Err(e) = self.bans.insert(peer.to_bytes(), bytes) {
+            tracing::error!("Failed to persist ban: {:?}", e);
+        }
+        self.scores.remove(peer);
+        self.requests.remove(peer);
+
+        tracing::info!("Banned {} until {}: {}", peer, until, reason);
+        self.pending.push_back(ToSwarm::CloseConnection {
+            peer_id: *peer,
+            connection: CloseConnection::All,
+        });
+        self.pending
+            .push_back(ToSwarm::GenerateEvent(Event::Banned(BannedPeer {
+                peer_id: *peer,
+                until,
+                reason,
+            })));
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// Lifts the ban on `peer`, returns false if it was not banned.
+    pub fn unban(&mut self, peer: &PeerId) -> bool {
+        self.scores.remove(peer);
+        match self.bans.remove(peer.to_bytes()) {
+            Ok(removed) => removed.is_some(),
+            Err(e) => {
+                tracing::error!("Failed to remove ban: {:?}", e);
+                false
+            }
+        }
+    }
+
+    pub fn banned_peers(&self) -> Vec<BannedPeer> {
+        let now = unix_now().as_secs();
+        self.bans
+            .iter()
+            .filter_map(|entry| entry.ok())
+            .filter_map(|(key, value)| {
+                let stored: Option<StoredBan> = bincode::deserialize(&value).ok();
+                match stored {
+                    Some(stored) if stored.until > now => Some(BannedPeer {
+                        peer_id: PeerId::from_bytes(&key).ok()?,
+                        until: stored.until,
+                        reason: stored.reason,
+                    }),
+                    _ => {
+                        let _ = self.bans.remove(key);
+                        None
+                    }
+                }
+            })
+            .collect()
+    }
+
+    /// Returns the end of the ban on `peer`, if it is banned.
+    fn banned_until(&self, peer: &PeerId) -> Option<u64> {
+        let bytes = self.bans.get(peer.to_bytes()).ok()??;
+        let stored: StoredBan = bincode::deserialize(&bytes).ok()?;
+        (stored.until > unix_now().as_secs()).then_some(stored.until)
+    }
+
+    fn check_banned(&self, peer: &PeerId) -> Result<(), ConnectionDenied> {
+        match self.banned_until(peer) {
+            Some(until) => Err(ConnectionDenied::new(Denied::Banned(until))),
+            None => Ok(()),
+        }
+    }
+
+    fn forget_connection(&mut self, connection_id: &ConnectionId) {
+        if let Some(ip) = self.connection_ips.remove(connection_id) {
+            if let Some(connections) = self.connections_per_ip.get_mut(&ip) {
+                connections.remove(connection_id);
+                if connections.is_empty() {
+                    self.connections_per_ip.remove(&ip);
+                }
+            }
+        }
+    }
+}
+
+impl NetworkBehaviour for PeerGuard {
+    type ConnectionHandler = dummy::ConnectionHandler;
+    type OutEvent = Event;
+
+    fn handle_pending_inbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        _: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<(), ConnectionDenied> {
+        let ip = match remote_addr.iter().next() {
+            Some(Protocol::Ip4(ip)) => IpAddr::V4(ip),
+            Some(Protocol::Ip6(ip)) => IpAddr::V6(ip),
+            _ => return Ok(()),
+        };
+        if ip.is_loopback() {
+            return Ok(());
+        }
+
+        let connections = self.connections_per_ip.entry(ip).or_default();
+        if connections.len() >= self.config.max_connections_per_ip as usize {
+            return Err(ConnectionDenied::new(Denied::TooManyFromIp(ip)));
+        }
+        connections.insert(connection_id);
+        self.connection_ips.insert(connection_id, ip);
+
+        Ok(())
+    }
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        _: ConnectionId,
+        peer: PeerId,
+        _: &Multiaddr,
+        _: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        self.check_banned(&peer)?;
+        Ok(dummy::ConnectionHandler)
+    }
+
+    fn handle_pending_outbound_connection(
+        &mut self,
+        _: ConnectionId,
+        maybe_peer: Option<PeerId>,
+        _: &[Multiaddr],
+        _: Endpoint,
+    ) -> Result<Vec<Multiaddr>, ConnectionDenied> {
+        if let Some(peer) = maybe_peer {
+            self.check_banned(&peer)?;
+        }
+        Ok(vec![])
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        _: ConnectionId,
+        peer: PeerId,
+        _: &Multiaddr,
+        _: Endpoint,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        self.check_banned(&peer)?;
+        Ok(dummy::ConnectionHandler)
+    }
+
+    fn on_swarm_event(&mut self, event: FromSwarm<Self::ConnectionHandler>) {
+        match event {
+            FromSwarm::ConnectionClosed(ConnectionClosed { connection_id, .. }) => {
+                self.forget_connection(&connection_id)
+            }
+            FromSwarm::ListenFailure(ListenFailure { connection_id, .. }) => {
+                self.forget_connection(&connection_id)
+            }
+            _ => {}
+        }
+    }
+
+    fn on_connection_handler_event(
+        &mut self,
+        _: PeerId,
+        _: ConnectionId,
+        event: THandlerOutEvent<Self>,
+    ) {
+        void::unreachable(event)
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+        _: &mut impl PollParameters,
+    ) -> Poll<ToSwarm<Self::OutEvent, THandlerInEvent<Self>>> {
+        if let Some(action) = self.pending.pop_front() {
+            return Poll::Ready(action);
+        }
+        self.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bans_survive_restart() {
+        let db = OpenBazaarDb::temporary();
+        let peer = PeerId::random();
+
+        let mut guard = PeerGuard::new(&db, Default::default()).unwrap();
+        for _ in 0..3 {
+            guard.penalize(&peer, Misbehaviour::MalformedRecord);
+        }
+        assert!(guard.banned_peers().is_empty());
+        guard.penalize(&peer, Misbehaviour::MalformedRecord);
+        assert!(guard.check_banned(&peer).is_err());
+
+        let mut guard = PeerGuard::new(&db, Default::default()).unwrap();
+        let banned = guard.banned_peers();
+        assert_eq!(banned.len(), 1);
+        assert_eq!(banned[0].peer_id, peer);
+
+        assert!(guard.unban(&peer));
+        assert!(guard.check_banned(&peer).is_ok());
+        assert!(!guard.unban(&peer));
+    }
+
+    #[test]
+    fn flooding_peer_is_rate_limited() {
+        let config = PeerGuardConfig {
+            max_requests_per_window: 2,
+            ..Default::default()
+        };
+        let mut guard = PeerGuard::new(&OpenBazaarDb::temporary(), config).unwrap();
+        let peer = PeerId::random();
+
+        assert!(guard.allow_request(&peer));
+        assert!(guard.allow_request(&peer));
+        assert!(!guard.allow_request(&peer));
+        assert!(guard.allow_request(&PeerId::random()));
+    }
+}
@@ -0,0 +1,256 @@
+use libp2p::Multiaddr;
+use libp2p_identity::PeerId;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::address_record::unix_now;
+use crate::db::OpenBazaarDb;
+
+const PEERS_TREE: &str = "peers";
+
+// Addresses kept per peer, the most recently seen first
+const MAX_ADDRESSES_PER_PEER: usize = 8;
+
+/// Configuration for a `PeerStore`.
+#[derive(Debug, Clone)]
+pub struct PeerStoreConfig {
+    /// Peers not seen for this long are dropped on start-up.
+    pub max_age: Duration,
+    /// Peers whose consecutive dial failures reach this count are dropped.
+    pub max_failures: u32,
+}
+
+impl Default for PeerStoreConfig {
+    fn default() -> Self {
+        Self {
+            max_age: Duration::from_secs(7 * 24 * 60 * 60),
+            max_failures: 10,
+        }
+    }
+}
+
+/// A peer we have been connected to.
+#[derive(Clone, Debug)]
+pub struct PeerRecord {
+    pub peer_id: PeerId,
+    pub addresses: Vec<Multiaddr>,
+    /// Seconds since the unix epoch at which we were last connected to the peer
+    pub last_seen: u64,
+    /// Dial failures since the last successful connection
+    pub failures: u32,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct StoredPeer {
+    addresses: Vec<Vec<u8>>,
+    last_seen: u64,
+    failures: u32,
+}
+
+/// Address book of known peers persisted in the node's sled database.
+///
+/// It is loaded into the Kademlia routing table on start-up so a restarted
+/// node can rejoin the network without its bootstrap nodes.
+pub struct PeerStore {
+    config: PeerStoreConfig,
+    peers: sled::Tree,
+}
+
+impl PeerStore {
+    pub fn new(db: &OpenBazaarDb) -> anyhow::Result<Self> {
+        Self::with_config(db, Default::default())
+    }
+
+    pub fn with_config(db: &OpenBazaarDb, config: PeerStoreConfig) -> anyhow::Result<Self> {
+        let store = Self {
+            config,
+            peers: db.db.open_tree(PEERS_TREE)?,
+        };
+        store.prune()?;
+        Ok(store)
+    }
+
+    /// Drops peers that are too old or keep failing to connect.
+    pub fn prune(&self) -> anyhow::Result<()> {
+        let oldest = unix_now()
+            .as_secs()
+            .saturating_sub(self.config.max_age.as_secs());
+
+        for entry in self.peers.iter() {
+            let (key, value) = entry?;
+            match bincode::deserialize::<StoredPeer>(&value) {
+                Ok(p) if p.last_seen >= oldest && p.failures < self.config.max_failures => {}
+                _ => {
+                    self.peers.remove(key)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Records a successful connection to `peer_id` through `addr`.
+    pub fn record_seen(&self, peer_id: &PeerId, addr: &Multiaddr) -> anyhow::Result<()> {
+        let mut stored = self.load(peer_id).unwrap_or(StoredPeer {
+            addresses: Vec::new(),
+            last_seen: 0,
+            failures: 0,
+        });
+
+        let addr = addr.to_vec();
+        stored.addresses.retain(|a| a != &addr);
+        stored.addresses.insert(0, addr);
+        stored.addresses.truncate(MAX_ADDRESSES_PER_PEER);
+        stored.last_seen = unix_now().as_secs();
+        stored.failures = 0;
+
+        self.save(peer_id, &stored)
+    }
+
+    /// Records a failed dial, peers we have never reached are not added.
+    pub fn record_failure(&self, peer_id: &PeerId) -> anyhow::Result<()> {
+        if let Some(mut stored) = self.load(peer_id) {
+            stored.failures = stored.failures.saturating_add(1);
+            if stored.failures >= self.config.max_failures {
+                self.remove(peer_id);
+            } else {
+                self.save(peer_id, &stored)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn remove(&self, peer_id: &PeerId) {
+        if let Err(e) = self.peers.remove(peer_id.to_bytes()) {
+            tracing::error!("Failed to remove peer: {:?}", e);
+        }
+    }
+
+    pub fn peers(&self) -> Vec<PeerRecord> {
+        self.peers
+            .iter()
+            .filter_map(|entry| entry.ok())
+            .filter_map(|(key, value)| {
+                let stored: StoredPeer = bincode::deserialize(&value).ok()?;
+                Some(PeerRecord {
+                    peer_id: PeerId::from_bytes(&key).ok()?,
+                    addresses: stored
+                        .addresses
+                        .into_iter()
+                        .filter_map(|a| Multiaddr::try_from(a).ok())
+                        .collect(),
+                    last_seen: stored.last_seen,
+                    failures: stored.failures,
+                })
+            })
+            .collect()
+    }
+
+    fn load(&self, peer_id: &PeerId) -> Option<StoredPeer> {
+        match self.peers.get(peer_id.to_bytes()) {
+            Ok(Some(bytes)) => bincode::deserialize(&bytes).ok(),
+            Ok(None) => None,
+            Err(e) => {
+                tracing::error!("Failed to read peer: {:?}", e);
+                None
+            }
+        }
+    }
+
+    fn save(&self, peer_id: &PeerId, stored: &StoredPeer) -> anyhow::Result<()> {
+        self.peers
+            .insert(peer_id.to_bytes(), bincode::serialize(stored)?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(port: u16) -> Multiaddr {
+        format!("/ip4/1.2.3.4/tcp/{}", port).parse().unwrap()
+    }
+
+    #[test]
+    fn peers_survive_restart() {
+        let db = OpenBazaarDb::temporary();
+        let peer = PeerId::random();
+
+        let store = PeerStore::new(&db).unwrap();
+        store.record_seen(&peer, &address(4001)).unwrap();
+        store.record_seen(&peer, &address(4002)).unwrap();
+        drop(store);
+
+        let peers = PeerStore::new(&db).unwrap().peers();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].peer_id, peer);
+        assert_eq!(peers[0].addresses, vec![address(4002), address(4001)]);
+    }
+
+    #[test]
+    fn peers_not_seen_for_too_long_are_pruned() {
+        let db = OpenBazaarDb::temporary();
+        let (old, recent) = (PeerId::random(), PeerId::random());
+
+        let store = PeerStore::new(&db).unwrap();
+        store.record_seen(&recent, &address(4001)).unwrap();
+        let last_seen = unix_now().as_secs() - store.config.max_age.as_secs() - 1;
+        let stale = StoredPeer {
+            addresses: vec![address(4001).to_vec()],
+            last_seen,
+            failures: 0,
+        };
+        store.save(&old, &stale).unwrap();
+        drop(store);
+
+        let peers = PeerStore::new(&db).unwrap().peers();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].peer_id, recent);
+    }
+
+    #[test]
+    fn peers_failing_too_often_are_dropped() {
+        let db = OpenBazaarDb::temporary();
+        let config = PeerStoreConfig {
+            max_failures: 3,
+            ..Default::default()
+        };
+        let (failing, flaky) = (PeerId::random(), PeerId::random());
+
+        let store = PeerStore::with_config(&db, config.clone()).unwrap();
+        store.record_seen(&failing, &address(4001)).unwrap();
+        store.record_seen(&flaky, &address(4002)).unwrap();
+        for _ in 0..2 {
+            store.record_failure(&failing).unwrap();
+            store.record_failure(&flaky).unwrap();
+        }
+        // A successful connection resets the count
+        store.record_seen(&flaky, &address(4002)).unwrap();
+        store.record_failure(&failing).unwrap();
+        store.record_failure(&flaky).unwrap();
+
+        let peers = store.peers();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].peer_id, flaky);
+        assert_eq!(peers[0].failures, 1);
+        drop(store);
+
+        // A lower limit also applies to peers stored before it was set
+        let config = PeerStoreConfig {
+            max_failures: 1,
+            ..config
+        };
+        assert!(PeerStore::with_config(&db, config)
+            .unwrap()
+            .peers()
+            .is_empty());
+    }
+
+    #[test]
+    fn unreached_peers_are_not_added_on_failure() {
+        let store = PeerStore::new(&OpenBazaarDb::temporary()).unwrap();
+        store.record_failure(&PeerId::random()).unwrap();
+        assert!(store.peers().is_empty());
+    }
+}
@@ -0,0 +1,385 @@
+use libp2p::kad::store::{Error, RecordStore, Result};
+use libp2p::kad::{KBucketKey, ProviderRecord, Record, RecordKey as Key, K_VALUE};
+use libp2p::Multiaddr;
+use libp2p_identity::PeerId;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::db::OpenBazaarDb;
+
+const RECORDS_TREE: &str = "kad_records";
+const PROVIDERS_TREE: &str = "kad_providers";
+
+/// Configuration for a `SledRecordStore`.
+#[derive(Debug, Clone)]
+pub struct SledRecordStoreConfig {
+    /// The maximum number of records.
+    pub max_records: usize,
+    /// The maximum size of record values, in bytes.
+    pub max_value_bytes: usize,
+    /// The maximum number of providers stored for a key.
+    pub max_providers_per_key: usize,
+    /// The maximum number of keys we hold provider records for.
+    pub max_provided_keys: usize,
+}
+
+impl Default for SledRecordStoreConfig {
+    fn default() -> Self {
+        Self {
+            max_records: 1024,
+            max_value_bytes: 65 * 1024,
+            max_provided_keys: 1024,
+            max_providers_per_key: K_VALUE.get(),
+        }
+    }
+}
+
+/// Kademlia record store persisted in the node's sled database.
+///
+/// Value records and provider records live in two dedicated trees so they
+/// survive a restart of the node. Expirations are stored as unix timestamps
+/// and converted back to monotonic instants when read.
+pub struct SledRecordStore {
+    local_id: PeerId,
+    config: SledRecordStoreConfig,
+    records: sled::Tree,
+    providers: sled::Tree,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct StoredRecord {
+    value: Vec<u8>,
+    publisher: Option<Vec<u8>>,
+    expires: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct StoredProvider {
+    provider: Vec<u8>,
+    expires: Option<u64>,
+    addresses: Vec<Vec<u8>>,
+}
+
+impl SledRecordStore {
+    pub fn new(local_id: PeerId, db: &OpenBazaarDb) -> anyhow::Result<Self> {
+        Self::with_config(local_id, db, Default::default())
+    }
+
+    pub fn with_config(
+        local_id: PeerId,
+        db: &OpenBazaarDb,
+        config: SledRecordStoreConfig,
+    ) -> anyhow::Result<Self> {
+        let store = Self {
+            local_id,
+            config,
+            records: db.db.open_tree(RECORDS_TREE)?,
+            providers: db.db.open_tree(PROVIDERS_TREE)?,
+        };
+        store.prune_expired()?;
+        Ok(store)
+    }
+
+    /// Drops every record and provider record that expired while the node was offline.
+    fn prune_expired(&self) -> anyhow::Result<()> {
+        for entry in self.records.iter() {
+            let (key, value) = entry?;
+            match bincode::deserialize::<StoredRecord>(&value) {
+                Ok(r) if !is_expired(r.expires) => {}
+                _ => {
+                    self.records.remove(key)?;
+                }
+            }
+        }
+
+        for entry in self.providers.iter() {
+            let (key, _) = entry?;
+            let key = Key::from(key.to_vec());
+            let providers = self.load_providers(&key);
+            if providers.is_empty() {
+                self.providers.remove(key.to_vec())?;
+            } else {
+                self.save_providers(&key, &providers)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn load_providers(&self, key: &Key) -> Vec<ProviderRecord> {
+        let stored: Vec<StoredProvider> = match self.providers.get(key.to_vec()) {
+            Ok(Some(bytes)) => bincode::deserialize(&bytes).unwrap_or_default(),
+            Ok(None) => return Vec::new(),
+            Err(e) => {
+                tracing::error!("Failed to read provider records: {:?}", e);
+                return Vec::new();
+            }
+        };
+
+        stored
+            .into_iter()
+            .filter(|p| !is_expired(p.expires))
+            .filter_map(|p| {
+                Some(ProviderRecord {
+                    key: key.clone(),
+                    provider: PeerId::from_bytes(&p.provider).ok()?,
+                    expires: p.expires.map(to_instant),
+                    addresses: p
+                        .addresses
+                        .into_iter()
+                        .filter_map(|a| Multiaddr::try_from(a).ok())
+                        .collect(),
+                })
+            })
+            .collect()
+    }
+
+    fn save_providers(&self, key: &Key, providers: &[ProviderRecord]) -> anyhow::Result<()> {
+        if providers.is_empty() {
+            self.providers.remove(key.to_vec())?;
+            return Ok(());
+        }
+
+        let stored: Vec<StoredProvider> = providers
+            .iter()
+            .map(|p| StoredProvider {
+                provider: p.provider.to_bytes(),
+                expires: p.expires.map(to_unix),
+                addresses: p.addresses.iter().map(|a| a.to_vec()).collect(),
+            })
+            .collect();
+        self.providers
+            .insert(key.to_vec(), bincode::serialize(&stored)?)?;
+        Ok(())
+    }
+
+    fn decode_record(key: &[u8], bytes: &[u8]) -> Option<Record> {
+        let stored: StoredRecord = bincode::deserialize(bytes).ok()?;
+        if is_expired(stored.expires) {
+            return None;
+        }
+        Some(Record {
+            key: Key::from(key.to_vec()),
+            value: stored.value,
+            publisher: stored.publisher.and_then(|p| PeerId::from_bytes(&p).ok()),
+            expires: stored.expires.map(to_instant),
+        })
+    }
+}
+
+impl RecordStore for SledRecordStore {
+    type RecordsIter<'a> = std::vec::IntoIter<Cow<'a, Record>>;
+    type ProvidedIter<'a> = std::vec::IntoIter<Cow<'a, ProviderRecord>>;
+
+    fn get(&self, k: &Key) -> Option<Cow<'_, Record>> {
+        match self.records.get(k.to_vec()) {
+            Ok(Some(bytes)) => Self::decode_record(k.as_ref(), &bytes).map(Cow::Owned),
+            Ok(None) => None,
+            Err(e) => {
+                tracing::error!("Failed to read record: {:?}", e);
+                None
+            }
+        }
+    }
+
+    fn put(&mut self, r: Record) -> Result<()> {
+        if r.value.len() >= self.config.max_value_bytes {
+            return Err(Error::ValueTooLarge);
+        }
+
+        let key = r.key.to_vec();
+        if !matches!(self.records.contains_key(&key), Ok(true))
+            && self.records.len() >= self.config.max_records
+        {
+            return Err(Error::MaxRecords);
+        }
+
+        let stored = StoredRecord {
+            value: r.value,
+            publisher: r.publisher.map(|p| p.to_bytes()),
+            expires: r.expires.map(to_unix),
+        };
+        // The store's error type has no I/O variant, a record that can't be kept is
+        // rejected as if it were too large
+        let bytes = bincode::serialize(&stored).map_err(|e| {
+            tracing::error!("Failed to serialize record: {:?}", e);
+            Error::ValueTooLarge
+        })?;
+        self.records.insert(key, bytes).map_err(|e| {
+            tracing::error!("Failed to persist record: {:?}", e);
+            Error::ValueTooLarge
+        })?;
+
+        Ok(())
+    }
+
+    fn remove(&mut self, k: &Key) {
+        if let Err(e) = self.records.remove(k.to_vec()) {
+            tracing::error!("Failed to remove record: {:?}", e);
+        }
+    }
+
+    fn records(&self) -> Self::RecordsIter<'_> {
+        self.records
+            .iter()
+            .filter_map(|entry| entry.ok())
+            .filter_map(|(key, value)| Self::decode_record(&key, &value))
+            .map(Cow::Owned)
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    fn add_provider(&mut self, record: ProviderRecord) -> Result<()> {
+        let mut providers = self.load_providers(&record.key);
+
+        if providers.is_empty() && self.providers.len() >= self.config.max_provided_keys {
+            return Err(Error::MaxProvidedKeys);
+        }
+
+        if let Some(i) = providers.iter().position(|p| p.provider == record.provider) {
+            // In-place update of an existing provider record.
+            providers[i] = record.clone();
+        } else {
+            // Keep the providers sorted by their distance to the key.
+            let key = KBucketKey::new(record.key.clone());
+            let distance = KBucketKey::from(record.provider).distance(&key);
+            let i = providers
+                .iter()
+                .position(|p| distance < KBucketKey::from(p.provider).distance(&key))
+                .unwrap_or(providers.len());
+            if i >= self.config.max_providers_per_key {
+                return Ok(());
+            }
+            providers.insert(i, record.clone());
+            providers.truncate(self.config.max_providers_per_key);
+        }
+
+        // Same as for records, a provider record that can't be kept is rejected as too large
+        self.save_providers(&record.key, &providers).map_err(|e| {
+            tracing::error!("Failed to persist provider records: {:?}", e);
+            Error::ValueTooLarge
+        })
+    }
+
+    fn providers(&self, key: &Key) -> Vec<ProviderRecord> {
+        self.load_providers(key)
+    }
+
+    fn provided(&self) -> Self::ProvidedIter<'_> {
+        self.providers
+            .iter()
+            .filter_map(|entry| entry.ok())
+            .flat_map(|(key, _)| self.load_providers(&Key::from(key.to_vec())))
+            .filter(|p| p.provider == self.local_id)
+            .map(Cow::Owned)
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    fn remove_provider(&mut self, key: &Key, provider: &PeerId) {
+        let mut providers = self.load_providers(key);
+        providers.retain(|p| &p.provider != provider);
+        if let Err(e) = self.save_providers(key, &providers) {
+            tracing::error!("Failed to remove provider record: {:?}", e);
+        }
+    }
+}
+
+// Compared as unix timestamps, an expiration in the past converts to an instant of now
+fn is_expired(expires: Option<u64>) -> bool {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    expires.is_some_and(|t| t <= now)
+}
+
+// Instants are only meaningful within one process, so expirations are
+// persisted as seconds since the unix epoch.
+fn to_unix(instant: Instant) -> u64 {
+    let remaining = instant.saturating_duration_since(Instant::now());
+    (SystemTime::now() + remaining)
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn to_instant(unix: u64) -> Instant {
+    let expires = UNIX_EPOCH + Duration::from_secs(unix);
+    let remaining = expires
+        .duration_since(SystemTime::now())
+        .unwrap_or_default();
+    Instant::now() + remaining
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(key: &[u8], value: Vec<u8>) -> Record {
+        Record::new(Key::new(&key), value)
+    }
+
+    #[test]
+    fn records_are_limited_in_number_and_size() {
+        let config = SledRecordStoreConfig {
+            max_records: 2,
+            max_value_bytes: 16,
+            ..Default::default()
+        };
+        let mut store =
+            SledRecordStore::with_config(PeerId::random(), &OpenBazaarDb::temporary(), config)
+                .unwrap();
+
+        assert!(matches!(
+            store.put(record(b"large", vec![0; 16])),
+            Err(Error::ValueTooLarge)
+        ));
+        store.put(record(b"first", vec![1])).unwrap();
+        store.put(record(b"second", vec![2])).unwrap();
+        assert!(matches!(
+            store.put(record(b"third", vec![3])),
+            Err(Error::MaxRecords)
+        ));
+
+        // Replacing a stored record doesn't count against the limit
+        store.put(record(b"first", vec![4])).unwrap();
+        assert_eq!(store.get(&Key::new(&b"first")).unwrap().value, vec![4]);
+    }
+
+    #[test]
+    fn expired_records_are_pruned_on_open() {
+        let db = OpenBazaarDb::temporary();
+        let local_id = PeerId::random();
+        let mut store = SledRecordStore::new(local_id, &db).unwrap();
+
+        let mut expired = record(b"expired", vec![1]);
+        expired.expires = Some(Instant::now());
+        let mut live = record(b"live", vec![2]);
+        live.expires = Some(Instant::now() + Duration::from_secs(60));
+        store.put(expired).unwrap();
+        store.put(live).unwrap();
+
+        let key = Key::new(&b"listing");
+        for (provider, ttl) in [(PeerId::random(), 0), (local_id, 60)] {
+            store
+                .add_provider(ProviderRecord {
+                    key: key.clone(),
+                    provider,
+                    expires: Some(Instant::now() + Duration::from_secs(ttl)),
+                    addresses: Vec::new(),
+                })
+                .unwrap();
+        }
+        assert!(store.get(&Key::new(&b"expired")).is_none());
+        drop(store);
+
+        let store = SledRecordStore::new(local_id, &db).unwrap();
+        assert_eq!(store.records.len(), 1);
+        assert!(store.get(&Key::new(&b"live")).is_some());
+        let providers = store.providers(&key);
+        assert_eq!(providers.len(), 1);
+        assert_eq!(providers[0].provider, local_id);
+    }
+}
@@ -0,0 +1,79 @@
+use bdk::bitcoin::Address;
+use electrsd::bitcoind::bitcoincore_rpc::{bitcoin, RpcApi};
+use electrsd::bitcoind::{self, BitcoinD};
+use electrsd::ElectrsD;
+use std::str::FromStr;
+
+/// A bitcoind and an Esplora electrs on regtest, both stopped when dropped.
+///
+/// Shared by the wallet and escrow tests, which only build with the `regtest-tests` feature.
+pub struct Regtest {
+    bitcoind: BitcoinD,
+    electrsd: ElectrsD,
+}
+
+impl Regtest {
+    pub fn start() -> Self {
+        let bitcoind = BitcoinD::new(bitcoind::downloaded_exe_path().unwrap()).unwrap();
+        let mut conf = electrsd::Conf::default();
+        conf.http_enabled = true;
+        let electrsd =
+            ElectrsD::with_conf(electrsd::downloaded_exe_path().unwrap(), &bitcoind, &conf)
+                .unwrap();
+        let regtest = Self { bitcoind, electrsd };
+        // Coinbase outputs spend after 100 blocks
+        regtest.mine(101);
+        regtest
+    }
+
+    pub fn esplora_url(&self) -> String {
+        format!("http://{}", self.electrsd.esplora_url.as_ref().unwrap())
+    }
+
+    pub fn new_address(&self) -> String {
+        let client = &self.bitcoind.client;
+        client.get_new_address(None, None).unwrap().to_string()
+    }
+
+    pub fn mine(&self, blocks: u64) {
+        let address = bitcoin::Address::from_str(&self.new_address()).unwrap();
+        self.bitcoind
+            .client
+            .generate_to_address(blocks, &address)
+            .unwrap();
+        self.electrsd.trigger().unwrap();
+    }
+
+    /// Pays `sats` to `address` and mines the payment.
+    pub fn fund(&self, address: &Address, sats: u64) {
+        self.send(address, sats);
+        self.mine(1);
+    }
+
+    /// Pays `sats` to `address`, left in the mempool until the next block is mined.
+    pub fn send(&self, address: &Address, sats: u64) {
+        let address = bitcoin::Address::from_str(&address.to_string()).unwrap();
+        self.bitcoind
+            .client
+            .send_to_address(
+                &address,
+                bitcoin::Amount::from_sat(sats),
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+    }
+
+    pub fn received(&self, address: &str) -> u64 {
+        let address = bitcoin::Address::from_str(address).unwrap();
+        self.bitcoind
+            .client
+            .get_received_by_address(&address, Some(1))
+            .unwrap()
+            .to_sat()
+    }
+}
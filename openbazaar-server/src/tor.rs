@@ -0,0 +1,411 @@
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use libp2p::core::transport::{ListenerId, TransportError, TransportEvent};
+use libp2p::multiaddr::{Onion3Addr, Protocol};
+use libp2p::{tcp, Multiaddr, Transport};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+const SOCKS_VERSION: u8 = 5;
+const SOCKS_NO_AUTHENTICATION: u8 = 0;
+const SOCKS_CONNECT: u8 = 1;
+const SOCKS_ATYP_IPV4: u8 = 1;
+const SOCKS_ATYP_DOMAIN: u8 = 3;
+const SOCKS_ATYP_IPV6: u8 = 4;
+
+/// Transport that dials every peer through a Tor SOCKS5 proxy.
+///
+/// Listening is limited to loopback addresses, inbound connections arrive
+/// through an onion service forwarding to them (see `create_onion_service`),
+/// so the node never exposes its IP address.
+pub struct TorTransport {
+    proxy: SocketAddr,
+    listener: tcp::tokio::Transport,
+}
+
+impl TorTransport {
+    pub fn new(proxy: SocketAddr) -> Self {
+        Self {
+            proxy,
+            listener: tcp::tokio::Transport::new(tcp::Config::default().nodelay(true)),
+        }
+    }
+}
+
+impl Transport for TorTransport {
+    type Output = tcp::tokio::TcpStream;
+    type Error = io::Error;
+    type ListenerUpgrade = <tcp::tokio::Transport as Transport>::ListenerUpgrade;
+    type Dial = BoxFuture<'static, Result<Self::Output, Self::Error>>;
+
+    fn listen_on(&mut self, addr: Multiaddr) -> Result<ListenerId, TransportError<Self::Error>> {
+        let loopback = match addr.iter().next() {
+            Some(Protocol::Ip4(ip)) => ip.is_loopback(),
+            Some(Protocol::Ip6(ip)) => ip.is_loopback(),
+            _ => false,
+        };
+        if !loopback {
+            return Err(TransportError::MultiaddrNotSupported(addr));
+        }
+        self.listener.listen_on(addr)
+    }
+
+    fn remove_listener(&mut self, id: ListenerId) -> bool {
+        self.listener.remove_listener(id)
+    }
+
+    fn dial(&mut self, addr: Multiaddr) -> Result<Self::Dial, TransportError<Self::Error>> {
+        let target = match SocksTarget::from_multiaddr(&addr) {
+            Some(target) => target,
+            None => return Err(TransportError::MultiaddrNotSupported(addr)),
+        };
+        let proxy = self.proxy;
+        Ok(async move {
+            let stream = socks5_connect(proxy, &target).await?;
+            Ok(tcp::tokio::TcpStream(stream))
+        }
+        .boxed())
+    }
+
+    fn dial_as_listener(
+        &mut self,
+        addr: Multiaddr,
+    ) -> Result<Self::Dial, TransportError<Self::Error>> {
+        self.dial(addr)
+    }
+
+    fn poll(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<TransportEvent<Self::ListenerUpgrade, Self::Error>> {
+        Pin::new(&mut self.get_mut().listener).poll(cx)
+    }
+
+    fn address_translation(&self, _listen: &Multiaddr, _observed: &Multiaddr) -> Option<Multiaddr> {
+        // Observed addresses are those of Tor relays, never our own
+        None
+    }
+}
+
+/// Destination of a SOCKS5 `CONNECT`, names are resolved by the proxy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum SocksTarget {
+    Ip(SocketAddr),
+    Domain(String, u16),
+}
+
+impl SocksTarget {
+    /// Accepts `/onion3/<id>:<port>`, `/dns*/<name>/tcp/<port>` and
+    /// `/ip*/<ip>/tcp/<port>`, optionally followed by `/p2p/<peer id>`.
+    fn from_multiaddr(addr: &Multiaddr) -> Option<Self> {
+        let mut protocols = addr.iter();
+        let target = match protocols.next()? {
+            Protocol::Onion3(onion) => SocksTarget::Domain(onion_host(&onion), onion.port()),
+            Protocol::Dns(name) | Protocol::Dns4(name) | Protocol::Dns6(name) => {
+                match protocols.next()? {
+                    Protocol::Tcp(port) => SocksTarget::Domain(name.to_string(), port),
+                    _ => return None,
+                }
+            }
+            Protocol::Ip4(ip) => match protocols.next()? {
+                Protocol::Tcp(port) => SocksTarget::Ip(SocketAddr::new(IpAddr::V4(ip), port)),
+                _ => return None,
+            },
+            Protocol::Ip6(ip) => match protocols.next()? {
+                Protocol::Tcp(port) => SocksTarget::Ip(SocketAddr::new(IpAddr::V6(ip), port)),
+                _ => return None,
+            },
+            _ => return None,
+        };
+
+        match protocols.next() {
+            None | Some(Protocol::P2p(_)) => Some(target),
+            Some(_) => None,
+        }
+    }
+}
+
+/// Opens a TCP stream to `target` through the SOCKS5 proxy at `proxy`.
+async fn socks5_connect(proxy: SocketAddr, target: &SocksTarget) -> io::Result<TcpStream> {
+    let mut stream = TcpStream::connect(proxy).await?;
+    stream.set_nodelay(true)?;
+
+    stream
+        .write_all(&[SOCKS_VERSION, 1, SOCKS_NO_AUTHENTICATION])
+        .await?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply != [SOCKS_VERSION, SOCKS_NO_AUTHENTICATION] {
+        return Err(socks_error("proxy requires authentication"));
+    }
+
+    let mut request = vec![SOCKS_VERSION, SOCKS_CONNECT, 0];
+    let port = match target {
+        SocksTarget::Ip(SocketAddr::V4(addr)) => {
+            request.push(SOCKS_ATYP_IPV4);
+            request.extend_from_slice(&addr.ip().octets());
+            addr.port()
+        }
+        SocksTarget::Ip(SocketAddr::V6(addr)) => {
+            request.push(SOCKS_ATYP_IPV6);
+            request.extend_from_slice(&addr.ip().octets());
+            addr.port()
+        }
+        SocksTarget::Domain(name, port) => {
+            let len = u8::try_from(name.len()).map_err(|_| socks_error("host name too long"))?;
+            request.push(SOCKS_ATYP_DOMAIN);
+            request.push(len);
+            request.extend_from_slice(name.as_bytes());
+            *port
+        }
+    };
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[0] != SOCKS_VERSION {
+        return Err(socks_error("unexpected reply version"));
+    }
+    if reply[1] != 0 {
+        return Err(socks_error(&format!(
+            "connect failed with code {}",
+            reply[1]
+        )));
+    }
+
+    // Skip the bound address, Tor always reports a placeholder
+    let address_len = match reply[3] {
+        SOCKS_ATYP_IPV4 => 4,
+        SOCKS_ATYP_IPV6 => 16,
+        SOCKS_ATYP_DOMAIN => stream.read_u8().await? as usize,
+        _ => return Err(socks_error("unexpected address type")),
+    };
+    let mut bound = vec![0u8; address_len + 2];
+    stream.read_exact(&mut bound).await?;
+
+    Ok(stream)
+}
+
+fn socks_error(message: &str) -> io::Error {
+    io::Error::other(format!("SOCKS5: {}", message))
+}
+
+/// Returns the `.onion` host name of an onion address.
+fn onion_host(onion: &Onion3Addr) -> String {
+    const ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+    // The 35 byte address is exactly 56 base32 characters
+    let mut host = String::with_capacity(62);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for &byte in onion.hash().iter() {
+        buffer = (buffer << 8) | byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            host.push(ALPHABET[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    host.push_str(".onion");
+    host
+}
+
+/// An onion service forwarding to one of our loopback listeners.
+///
+/// Tor removes the service when the control connection closes, so this must
+/// be kept alive for as long as the node should be reachable.
+pub struct OnionService {
+    pub address: Multiaddr,
+    /// Private key of the service, passing it back in keeps the same address
+    pub key: String,
+    _control: BufReader<TcpStream>,
+}
+
+/// Creates an onion service on `virtual_port` through the Tor control port.
+///
+/// Connections to the service are forwarded to `local_port` on 127.0.0.1.
+pub async fn create_onion_service(
+    control: SocketAddr,
+    password: Option<&str>,
+    key: Option<&str>,
+    virtual_port: u16,
+    local_port: u16,
+) -> anyhow::Result<OnionService> {
+    let mut control = BufReader::new(TcpStream::connect(control).await?);
+
+    let authenticate = match password {
+        Some(password) => format!("AUTHENTICATE \"{}\"\r\n", password.replace('"', "\\\"")),
+        None => "AUTHENTICATE\r\n".to_string(),
+    };
+    control_command(&mut control, &authenticate).await?;
+
+    let key = key.unwrap_or("NEW:ED25519-V3");
+    let reply = control_command(
+        &mut control,
+        &format!(
+            "ADD_ONION {} Port={},127.0.0.1:{}\r\n",
+            key, virtual_port, local_port
+        ),
+    )
+    .await?;
+
+    let service_id = reply
+        .iter()
+        .find_map(|line| line.strip_prefix("ServiceID="))
+        .ok_or_else(|| anyhow::anyhow!("Tor did not return a service id"))?;
+    // Only returned for new services
+    let key = reply
+        .iter()
+        .find_map(|line| line.strip_prefix("PrivateKey="))
+        .unwrap_or(key)
+        .to_string();
+    let address = format!("/onion3/{}:{}", service_id, virtual_port).parse()?;
+
+    Ok(OnionService {
+        address,
+        key,
+        _control: control,
+    })
+}
+
+/// Sends a command to the control port and returns the lines of a successful reply.
+async fn control_command(
+    control: &mut BufReader<TcpStream>,
+    command: &str,
+) -> anyhow::Result<Vec<String>> {
+    control.get_mut().write_all(command.as_bytes()).await?;
+
+    let mut lines = Vec::new();
+    loop {
+        let mut line = String::new();
+        if control.read_line(&mut line).await? == 0 {
+            anyhow::bail!("Tor closed the control connection");
+        }
+        let line = line.trim_end();
+        if line.len() < 4 {
+            anyhow::bail!("Malformed control port reply: {}", line);
+        }
+        let (status, rest) = line.split_at(3);
+        if status != "250" {
+            anyhow::bail!("Tor rejected the command: {}", line);
+        }
+        lines.push(rest[1..].to_string());
+        // A space after the status marks the last line of the reply
+        if rest.starts_with(' ') {
+            return Ok(lines);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::OpenBazaarDb;
+    use crate::network::{self, NetworkConfig};
+    use libp2p::identity::Keypair;
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    const ONION: &str = "vww6ybal4bd7szmgncyruucpgfkqahzddi37ktceo3ah7ngmcopnpyyd";
+
+    /// Stand-in for Tor, connects every SOCKS5 `CONNECT` to `target` and
+    /// reports the requested host.
+    async fn socks_proxy(target: SocketAddr) -> (SocketAddr, mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (hosts, requested) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            loop {
+                let (mut client, _) = listener.accept().await.unwrap();
+                let hosts = hosts.clone();
+                tokio::spawn(async move {
+                    let mut greeting = [0u8; 3];
+                    client.read_exact(&mut greeting).await?;
+                    client
+                        .write_all(&[SOCKS_VERSION, SOCKS_NO_AUTHENTICATION])
+                        .await?;
+
+                    let mut request = [0u8; 4];
+                    client.read_exact(&mut request).await?;
+                    assert_eq!(request[3], SOCKS_ATYP_DOMAIN);
+                    let mut host = vec![0u8; client.read_u8().await? as usize];
+                    client.read_exact(&mut host).await?;
+                    let port = client.read_u16().await?;
+                    let _ = hosts.send(format!("{}:{}", String::from_utf8_lossy(&host), port));
+
+                    let mut upstream = TcpStream::connect(target).await?;
+                    client
+                        .write_all(&[SOCKS_VERSION, 0, 0, SOCKS_ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+                        .await?;
+                    tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+                    io::Result::Ok(())
+                });
+            }
+        });
+
+        (addr, requested)
+    }
+
+    #[test]
+    fn onion_host_matches_address() {
+        let addr: Multiaddr = format!("/onion3/{}:4001", ONION).parse().unwrap();
+        let target = SocksTarget::from_multiaddr(&addr).unwrap();
+        assert_eq!(
+            target,
+            SocksTarget::Domain(format!("{}.onion", ONION), 4001)
+        );
+    }
+
+    #[tokio::test]
+    async fn dials_onion_address_through_proxy() {
+        let keypair = Keypair::generate_ed25519();
+        let listener_peer_id = keypair.public().to_peer_id();
+        let (mut listener, mut event_loop) =
+            network::new(keypair, &OpenBazaarDb::temporary(), Default::default())
+                .await
+                .unwrap();
+        tokio::spawn(async move { event_loop.run().await });
+        listener
+            .start_listening("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .await
+            .unwrap();
+
+        // The onion service of the listener forwards to its loopback listener
+        let local_port = network::wait_for_listen_addr(&listener)
+            .await
+            .iter()
+            .find_map(|p| match p {
+                Protocol::Tcp(port) => Some(port),
+                _ => None,
+            })
+            .expect("Listener to listen on TCP");
+        let (proxy, mut requested) = socks_proxy(([127, 0, 0, 1], local_port).into()).await;
+
+        let config = NetworkConfig {
+            tor_proxy: Some(proxy),
+            ..Default::default()
+        };
+        let (mut dialer, mut event_loop) = network::new(
+            Keypair::generate_ed25519(),
+            &OpenBazaarDb::temporary(),
+            config,
+        )
+        .await
+        .unwrap();
+        tokio::spawn(async move { event_loop.run().await });
+
+        let onion = format!("/onion3/{}:4001", ONION).parse().unwrap();
+        dialer.dial(listener_peer_id, onion).await.unwrap();
+
+        assert_eq!(
+            requested.recv().await.unwrap(),
+            format!("{}.onion:4001", ONION)
+        );
+    }
+}
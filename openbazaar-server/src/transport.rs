@@ -0,0 +1,66 @@
+use futures::future::Either;
+use libp2p::core::muxing::StreamMuxerBox;
+use libp2p::core::transport::Boxed;
+use libp2p::core::upgrade;
+use libp2p::identity::Keypair;
+use libp2p::{dns, noise, quic, relay, tcp, websocket, yamux, Transport};
+use libp2p_identity::PeerId;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use crate::tor::TorTransport;
+
+// How long the security and multiplexer handshakes may take on a new stream based connection
+const UPGRADE_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Builds the transport used by the swarm.
+///
+/// Listens and dials over TCP (`/ip4/.../tcp/...`), WebSocket
+/// (`/ip4/.../tcp/.../ws`), QUIC (`/ip4/.../udp/.../quic-v1`) and through
+/// relays (`.../p2p/<relay>/p2p-circuit`). TCP, WebSocket and relayed
+/// connections are secured with noise and multiplexed with yamux, QUIC brings
+/// both itself. `/dns` addresses are resolved for all of them.
+pub fn build_transport(
+    keypair: &Keypair,
+    relay_transport: relay::client::Transport,
+) -> anyhow::Result<Boxed<(PeerId, StreamMuxerBox)>> {
+    let tcp_config = tcp::Config::default().nodelay(true);
+    let tcp = tcp::tokio::Transport::new(tcp_config.clone());
+    let websocket = websocket::WsConfig::new(tcp::tokio::Transport::new(tcp_config));
+
+    let streams = relay_transport
+        .or_transport(websocket)
+        .or_transport(tcp)
+        .upgrade(upgrade::Version::V1)
+        .authenticate(noise::Config::new(keypair)?)
+        .multiplex(yamux::Config::default())
+        .timeout(UPGRADE_TIMEOUT);
+
+    let quic = quic::tokio::Transport::new(quic::Config::new(keypair));
+
+    let transport = quic.or_transport(streams).map(|output, _| match output {
+        Either::Left((peer_id, connection)) => (peer_id, StreamMuxerBox::new(connection)),
+        Either::Right((peer_id, muxer)) => (peer_id, StreamMuxerBox::new(muxer)),
+    });
+
+    Ok(dns::TokioDnsConfig::system(transport)?.boxed())
+}
+
+/// Builds the transport used by the swarm in Tor mode.
+///
+/// Every connection is dialed through the SOCKS5 proxy at `proxy`, which is
+/// what lets us reach `/onion3/...` addresses. Only TCP is available, QUIC and
+/// relays would bypass Tor, and listening is limited to loopback addresses
+/// that our onion service forwards to.
+pub fn build_tor_transport(
+    keypair: &Keypair,
+    proxy: SocketAddr,
+) -> anyhow::Result<Boxed<(PeerId, StreamMuxerBox)>> {
+    Ok(TorTransport::new(proxy)
+        .upgrade(upgrade::Version::V1)
+        .authenticate(noise::Config::new(keypair)?)
+        .multiplex(yamux::Config::default())
+        .timeout(UPGRADE_TIMEOUT)
+        .map(|(peer_id, muxer), _| (peer_id, StreamMuxerBox::new(muxer)))
+        .boxed())
+}
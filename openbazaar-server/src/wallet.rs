@@ -1,116 +1,552 @@
-use bdk::bitcoin::Network;
+use crate::db::{OpenBazaarDb, DB};
+use bdk::bitcoin::{Address, BlockHash, Network, Script, Transaction, Txid};
 use bdk::keys::bip39::Mnemonic;
 use bdk::keys::{DerivableKey, ExtendedKey};
 use bdk::template::Bip84;
 use bdk::wallet::AddressIndex;
-use bdk::Wallet;
+use bdk::{FeeRate, KeychainKind, SignOptions, TransactionDetails, Wallet};
+use bdk_chain::keychain::Balance;
+use bdk_chain::ConfirmationTime;
 use bdk_esplora::{esplora_client, EsploraExt};
 use bdk_file_store::KeychainStore;
 use std::collections::BTreeMap;
-use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::Mutex;
 
-const SEND_AMOUNT: u64 = 5000;
+// Network used when none is configured
+pub const DEFAULT_NETWORK: Network = Network::Testnet;
+
+// How often the wallet asks the chain source for new transactions
+const SYNC_INTERVAL: Duration = Duration::from_secs(60);
+
+// Unused addresses scanned past the last used one before a keychain is considered done
 const STOP_GAP: usize = 50;
+
+// Scripts looked up at the same time against the Esplora server
 const PARALLEL_REQUESTS: usize = 5;
 
-pub fn fire_up_wallet(mnemonic_words: String, data_dir: String) {
-    let network = Network::Testnet;
+// Blocks a send without an explicit fee rate aims to confirm within
+pub const DEFAULT_CONFIRMATION_TARGET: usize = 6;
 
-    let mnemonic = Mnemonic::parse(&mnemonic_words).unwrap();
+#[derive(Debug, thiserror::Error)]
+pub enum WalletError {
+    #[error("{0}")]
+    InvalidArgument(String),
+    #[error("Insufficient funds, {needed} sats needed but {available} sats available")]
+    InsufficientFunds { needed: u64, available: u64 },
+    #[error("Chain source request failed: {0}")]
+    ChainSource(String),
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
 
-    let xkey: ExtendedKey = mnemonic.into_extended_key().unwrap();
-    let xpriv = xkey.into_xprv(network).unwrap();
+pub type OpenBazaarWallet = Wallet<KeychainStore<KeychainKind, ConfirmationTime>>;
 
-    // Create the data folder if doesn't exist
-    std::fs::create_dir_all(&data_dir).expect("Failed to create data folder");
+/// Parses a network name as given with `--network` or in the config file.
+pub fn parse_network(name: &str) -> Result<Network, String> {
+    match name {
+        "mainnet" => Ok(Network::Bitcoin),
+        name => Network::from_str(name).map_err(|_| {
+            format!(
+                "Unknown network {}, expected mainnet, testnet, signet or regtest",
+                name
+            )
+        }),
+    }
+}
 
-    let db = KeychainStore::new_from_path(format!("{}/wallet.db", &data_dir))
-        .expect("Failed to create keychain store");
+/// Records `network` as the one the node runs on, or fails if it was first started on another.
+///
+/// Orders, listings and peers of one network mean nothing on another.
+pub async fn ensure_network(db: &OpenBazaarDb, network: Network) -> anyhow::Result<()> {
+    match db.get_network().await? {
+        Some(stored) if stored != network.to_string() => anyhow::bail!(
+            "This node runs on {}, start it with --network {} or use another --user for {}",
+            stored,
+            stored,
+            network
+        ),
+        Some(_) => Ok(()),
+        None => db.set_network(&network.to_string()).await,
+    }
+}
+
+/// Esplora server used when none is configured, a local electrs for regtest.
+pub fn default_esplora_url(network: Network) -> &'static str {
+    match network {
+        Network::Bitcoin => "https://mempool.space/api",
+        Network::Testnet => "https://mempool.space/testnet/api",
+        Network::Signet => "https://mempool.space/signet/api",
+        Network::Regtest => "http://127.0.0.1:3002",
+    }
+}
 
-    let mut wallet = Wallet::new(
-        Bip84(xpriv.clone(), bdk::KeychainKind::External),
-        Some(Bip84(xpriv, bdk::KeychainKind::Internal)),
+/// Chain source and schedule for syncing the wallet.
+#[derive(Clone, Debug)]
+pub struct WalletConfig {
+    /// Base URL of the Esplora HTTP API, e.g. a local electrs in regtest.
+    pub esplora_url: String,
+    /// Proxy the Esplora requests go through, e.g. `socks5h://127.0.0.1:9050` for Tor.
+    pub proxy: Option<String>,
+    pub sync_interval: Duration,
+    pub stop_gap: usize,
+}
+
+impl Default for WalletConfig {
+    fn default() -> Self {
+        Self {
+            esplora_url: default_esplora_url(DEFAULT_NETWORK).to_string(),
+            proxy: None,
+            sync_interval: SYNC_INTERVAL,
+            stop_gap: STOP_GAP,
+        }
+    }
+}
+
+/// Opens the BIP84 wallet for `network` derived from the node's mnemonic, creating its data
+/// folder if needed.
+///
+/// Every network has its own wallet file. Revealed addresses, transactions and the chain
+/// checkpoints of the last sync are kept in it, so syncing resumes from where it stopped.
+pub fn open_wallet(
+    mnemonic_words: &str,
+    data_dir: &str,
+    network: Network,
+) -> anyhow::Result<OpenBazaarWallet> {
+    let mnemonic = Mnemonic::parse(mnemonic_words)?;
+    let xkey: ExtendedKey = mnemonic.into_extended_key()?;
+    let xpriv = xkey
+        .into_xprv(network)
+        .ok_or_else(|| anyhow::anyhow!("Failed to derive the wallet's private key"))?;
+
+    std::fs::create_dir_all(data_dir)?;
+    let wallet_file = format!("{}/wallet-{}.db", data_dir, network);
+
+    // Wallets used to be testnet only and kept in a single file
+    let legacy_file = format!("{}/wallet.db", data_dir);
+    if network == Network::Testnet
+        && Path::new(&legacy_file).exists()
+        && !Path::new(&wallet_file).exists()
+    {
+        std::fs::rename(&legacy_file, &wallet_file)?;
+    }
+
+    let db = KeychainStore::new_from_path(wallet_file)
+        .map_err(|e| anyhow::anyhow!("Failed to open wallet store: {:?}", e))?;
+
+    Wallet::new(
+        Bip84(xpriv, KeychainKind::External),
+        Some(Bip84(xpriv, KeychainKind::Internal)),
         db,
         network,
     )
-    .unwrap();
-
-    // println!(
-    //     "mnemonic: {}\n\nrecv desc (pub key): {:#?}\n\nchng desc (pub key): {:#?}",
-    //     mnemonic_words,
-    //     wallet.get_descriptor_for_keychain(bdk::KeychainKind::External),
-    //     wallet.get_descriptor_for_keychain(bdk::KeychainKind::Internal),
-    // );
-
-    println!(
-        "Revealed address: {}",
-        wallet.get_address(AddressIndex::New)
-    );
+    .map_err(|e| anyhow::anyhow!("Failed to open wallet: {:?}", e))
+}
+
+fn synced_height(wallet: &OpenBazaarWallet) -> Option<u32> {
+    wallet.checkpoints().keys().next_back().copied()
+}
+
+/// Progress of syncing the wallet with the chain.
+#[derive(Clone, Debug, Default)]
+pub struct SyncStatus {
+    /// Height of the last block synced to, kept in the `KeychainStore` across restarts.
+    pub height: Option<u32>,
+    pub last_synced: Option<SystemTime>,
+    /// Error of the last sync if it failed.
+    pub last_error: Option<String>,
+}
+
+/// The node's wallet, opened once at start-up and shared by everything that pays or gets paid.
+///
+/// Clones are handles to the same wallet. Calls wait for each other on an async mutex, a sync
+/// only holds it while reading what to scan and applying the result.
+#[derive(Clone)]
+pub struct WalletService {
+    wallet: Arc<Mutex<OpenBazaarWallet>>,
+    client: esplora_client::BlockingClient,
+    config: WalletConfig,
+    status: Arc<std::sync::Mutex<SyncStatus>>,
+}
+
+impl std::fmt::Debug for WalletService {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WalletService")
+            .field("config", &self.config)
+            .field("status", &self.sync_status())
+            .finish()
+    }
+}
+
+impl WalletService {
+    pub fn new(wallet: OpenBazaarWallet, config: WalletConfig) -> anyhow::Result<Self> {
+        let mut builder = esplora_client::Builder::new(&config.esplora_url);
+        if let Some(proxy) = &config.proxy {
+            builder = builder.proxy(proxy);
+        }
+        let client = builder.build_blocking().map_err(|e| {
+            anyhow::anyhow!("Invalid Esplora endpoint {}: {:?}", config.esplora_url, e)
+        })?;
+
+        let status = SyncStatus {
+            height: synced_height(&wallet),
+            ..Default::default()
+        };
+        Ok(Self {
+            wallet: Arc::new(Mutex::new(wallet)),
+            client,
+            config,
+            status: Arc::new(std::sync::Mutex::new(status)),
+        })
+    }
+
+    /// Client of the chain source the wallet syncs against.
+    pub fn chain_client(&self) -> esplora_client::BlockingClient {
+        self.client.clone()
+    }
+
+    pub fn config(&self) -> &WalletConfig {
+        &self.config
+    }
+
+    pub fn sync_status(&self) -> SyncStatus {
+        self.status
+            .lock()
+            .expect("Sync status lock poisoned")
+            .clone()
+    }
+
+    pub async fn balance(&self) -> Balance {
+        self.wallet.lock().await.get_balance()
+    }
+
+    /// Reveals the next unused receiving address.
+    pub async fn get_new_address(&self) -> Result<Address, WalletError> {
+        let mut wallet = self.wallet.lock().await;
+        let address = wallet.get_address(AddressIndex::New).address;
+        // Revealed addresses are persisted so they are never handed out twice
+        wallet
+            .commit()
+            .map_err(|e| anyhow::anyhow!("Failed to save wallet: {:?}", e))?;
+        Ok(address)
+    }
+
+    /// Returns wallet transactions newest first, skipping `offset` and returning at most
+    /// `limit`, along with the total number of transactions.
+    pub async fn list_transactions(
+        &self,
+        offset: usize,
+        limit: usize,
+    ) -> (Vec<TransactionDetails>, usize) {
+        let wallet = self.wallet.lock().await;
+        let mut transactions: Vec<_> = wallet
+            .transactions()
+            .filter_map(|(_, tx)| wallet.get_tx(tx.txid(), false))
+            .collect();
+        // Unconfirmed transactions first, then by descending height
+        transactions.sort_by_key(|tx| match tx.confirmation_time {
+            ConfirmationTime::Unconfirmed => std::cmp::Reverse(u32::MAX),
+            ConfirmationTime::Confirmed { height, .. } => std::cmp::Reverse(height),
+        });
+
+        let total = transactions.len();
+        let page = transactions.into_iter().skip(offset).take(limit).collect();
+        (page, total)
+    }
+
+    /// Returns the fee rate in sat/vB the chain source expects to confirm within
+    /// `target_blocks`.
+    pub async fn estimate_fee(&self, target_blocks: usize) -> Result<f32, WalletError> {
+        if target_blocks == 0 {
+            return Err(WalletError::InvalidArgument(
+                "Confirmation target must be at least one block".to_string(),
+            ));
+        }
+        let client = self.client.clone();
+        let estimates = tokio::task::spawn_blocking(move || client.get_fee_estimates())
+            .await
+            .map_err(anyhow::Error::from)?
+            .map_err(|e| WalletError::ChainSource(format!("{:?}", e)))?;
+        esplora_client::convert_fee_rate(target_blocks, estimates)
+            .map_err(|e| WalletError::ChainSource(format!("{:?}", e)))
+    }
+
+    /// Pays `amount` sats to `address` and broadcasts the transaction.
+    ///
+    /// Without a fee rate the transaction aims to confirm within
+    /// `DEFAULT_CONFIRMATION_TARGET` blocks. The broadcast transaction is added to the wallet
+    /// as unconfirmed, so the balance reflects it and its inputs are not spent again before the
+    /// next sync.
+    pub async fn send(
+        &self,
+        address: &str,
+        amount: u64,
+        fee_rate: Option<f32>,
+        rbf: bool,
+    ) -> Result<Txid, WalletError> {
+        let address = Address::from_str(address)
+            .map_err(|e| WalletError::InvalidArgument(format!("Invalid address: {}", e)))?;
+        let fee_rate = match fee_rate {
+            Some(fee_rate) => fee_rate,
+            None => self.estimate_fee(DEFAULT_CONFIRMATION_TARGET).await?,
+        };
+
+        let tx = {
+            let mut wallet = self.wallet.lock().await;
+            if !address.is_valid_for_network(wallet.network()) {
+                return Err(WalletError::InvalidArgument(format!(
+                    "Address is not valid on {}",
+                    wallet.network()
+                )));
+            }
+
+            let mut builder = wallet.build_tx();
+            builder
+                .add_recipient(address.script_pubkey(), amount)
+                .fee_rate(FeeRate::from_sat_per_vb(fee_rate));
+            if rbf {
+                builder.enable_rbf();
+            }
+            let (mut psbt, _) = builder.finish().map_err(|e| match e {
+                bdk::Error::InsufficientFunds { needed, available } => {
+                    WalletError::InsufficientFunds { needed, available }
+                }
+                bdk::Error::OutputBelowDustLimit(_) => {
+                    WalletError::InvalidArgument("Amount is below the dust limit".to_string())
+                }
+                e => WalletError::Other(anyhow::anyhow!("Failed to build transaction: {:?}", e)),
+            })?;
+
+            let finalized = wallet
+                .sign(&mut psbt, SignOptions::default())
+                .map_err(|e| anyhow::anyhow!("Failed to sign transaction: {:?}", e))?;
+            if !finalized {
+                return Err(anyhow::anyhow!("Transaction is not fully signed").into());
+            }
+            // Keep the change address revealed for the transaction
+            wallet
+                .commit()
+                .map_err(|e| anyhow::anyhow!("Failed to save wallet: {:?}", e))?;
+            psbt.extract_tx()
+        };
+
+        let txid = broadcast(&self.client, tx.clone()).await?;
+
+        // The payment went out, failing to record it only delays it until the next sync
+        let mut wallet = self.wallet.lock().await;
+        let applied = wallet
+            .insert_tx(tx, ConfirmationTime::Unconfirmed)
+            .map_err(|e| anyhow::anyhow!("{:?}", e))
+            .and_then(|_| wallet.commit().map_err(|e| anyhow::anyhow!("{:?}", e)));
+        if let Err(e) = applied {
+            tracing::warn!(
+                "Failed to add sent transaction {} to the wallet: {}",
+                txid,
+                e
+            );
+        }
+        Ok(txid)
+    }
+
+    /// Syncs the wallet on the configured interval until the task is dropped.
+    ///
+    /// Failed syncs are logged and retried on the next interval.
+    pub async fn run_sync(self) {
+        loop {
+            match self.sync().await {
+                Ok(()) => {
+                    let balance = self.balance().await;
+                    tracing::info!(
+                        "Wallet synced to height {:?}, balance {} sats",
+                        self.sync_status().height,
+                        balance
+                    );
+                }
+                Err(e) => tracing::warn!(
+                    "Wallet sync against {} failed: {}",
+                    self.config.esplora_url,
+                    e
+                ),
+            }
+            tokio::time::sleep(self.config.sync_interval).await;
+        }
+    }
+
+    /// Runs one incremental sync and records its outcome in the sync status.
+    pub async fn sync(&self) -> anyhow::Result<()> {
+        let result = sync_wallet(&self.wallet, &self.client, self.config.stop_gap).await;
+
+        let mut status = self.status.lock().expect("Sync status lock poisoned");
+        match &result {
+            Ok(height) => {
+                status.height = *height;
+                status.last_synced = Some(SystemTime::now());
+                status.last_error = None;
+            }
+            Err(e) => status.last_error = Some(e.to_string()),
+        }
+        result.map(|_| ())
+    }
+}
+
+/// Scans every keychain up to `stop_gap` scripts past its last revealed address, addresses
+/// found in use are revealed and extend the next scan. Returns the height synced to.
+pub async fn sync_wallet(
+    wallet: &Mutex<OpenBazaarWallet>,
+    client: &esplora_client::BlockingClient,
+    stop_gap: usize,
+) -> anyhow::Result<Option<u32>> {
+    let (checkpoints, spks) = {
+        let wallet = wallet.lock().await;
+        let checkpoints: BTreeMap<u32, BlockHash> = wallet.checkpoints().clone();
+        let spks: BTreeMap<KeychainKind, Vec<(u32, Script)>> = wallet
+            .spks_of_all_keychains()
+            .into_iter()
+            .map(|(keychain, spks)| {
+                let revealed = wallet
+                    .derivation_index(keychain)
+                    .map_or(0, |i| i as usize + 1);
+                (keychain, spks.take(revealed + stop_gap).collect())
+            })
+            .collect();
+        (checkpoints, spks)
+    };
+
+    let client = client.clone();
+    let update = tokio::task::spawn_blocking(move || {
+        client.scan(
+            &checkpoints,
+            spks,
+            core::iter::empty(),
+            core::iter::empty(),
+            stop_gap,
+            PARALLEL_REQUESTS,
+        )
+    })
+    .await??;
+
+    // A reorg or a concurrent sync since the scan started leaves the update unconnected,
+    // the next sync starts over from the new checkpoints
+    let mut wallet = wallet.lock().await;
+    wallet
+        .apply_update(update)
+        .map_err(|e| anyhow::anyhow!("Failed to apply update: {:?}", e))?;
     wallet
         .commit()
-        .expect("couldn't save new address to the wallet");
-    let balance = wallet.get_balance();
-    println!("Balance: {} sats", balance);
-
-    // print!("Syncing...");
-    // // Scanning the chain...
-    // let esplora_url = "https://mempool.space/testnet/api";
-    // let client = esplora_client::Builder::new(esplora_url)
-    //     .build_blocking()
-    //     .expect("something is screwed with esplora");
-    // let checkpoints = wallet.checkpoints();
-    // let spks: BTreeMap<_, _> = wallet
-    //     .spks_of_all_keychains()
-    //     .into_iter()
-    //     .map(|(k, spks)| {
-    //         let mut first = true;
-    //         (
-    //             k,
-    //             spks.inspect(move |(spk_i, _)| {
-    //                 if first {
-    //                     first = false;
-    //                     print!("\nScanning keychain [{:?}]:", k);
-    //                 }
-    //                 print!(" {}", spk_i);
-    //                 let _ = std::io::stdout().flush();
-    //             }),
-    //         )
-    //     })
-    //     .collect();
-    // let update = client
-    //     .scan(
-    //         checkpoints,
-    //         spks,
-    //         core::iter::empty(),
-    //         core::iter::empty(),
-    //         STOP_GAP,
-    //         PARALLEL_REQUESTS,
-    //     )
-    //     .expect("failed to scan");
-
-    // wallet.apply_update(update).expect("failed to apply update");
-    // wallet.commit().expect("failed to commit update");
-
-    // if balance.total() < SEND_AMOUNT {
-    //     println!(
-    //         "Please send at least {} sats to the receiving address",
-    //         SEND_AMOUNT
-    //     );
-    //     std::process::exit(0);
-    // }
-
-    // let faucet_address = Address::from_str("mkHS9ne12qx9pS9VojpwU5xtRd4T7X7ZUt").expect("Failed to parse address");
-
-    // let mut tx_builder = wallet.build_tx();
-    // tx_builder
-    //     .add_recipient(faucet_address.script_pubkey(), SEND_AMOUNT)
-    //     .enable_rbf();
-
-    // let (mut psbt, _) = tx_builder.finish().expect("Failed to build transaction");
-    // let finalized = wallet.sign(&mut psbt, SignOptions::default()).expect("Failed to sign transaction");
-    // assert!(finalized);
-
-    // let tx = psbt.extract_tx();
-    // client.broadcast(&tx).expect("Failed to broadcast transaction");
-    // println!("Tx broadcasted! Txid: {}", tx.txid());
+        .map_err(|e| anyhow::anyhow!("Failed to save wallet: {:?}", e))?;
+    Ok(synced_height(&wallet))
+}
+
+/// Hands a fully signed transaction to the chain source.
+pub async fn broadcast(
+    client: &esplora_client::BlockingClient,
+    tx: Transaction,
+) -> Result<Txid, WalletError> {
+    let client = client.clone();
+    let txid = tx.txid();
+    tokio::task::spawn_blocking(move || client.broadcast(&tx))
+        .await
+        .map_err(anyhow::Error::from)?
+        .map_err(|e| WalletError::ChainSource(format!("{:?}", e)))?;
+    Ok(txid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn node_stays_on_its_first_network() {
+        let db = OpenBazaarDb::temporary();
+        ensure_network(&db, Network::Regtest).await.unwrap();
+        ensure_network(&db, Network::Regtest).await.unwrap();
+
+        assert!(ensure_network(&db, Network::Testnet).await.is_err());
+        let stored = db.get_network().await.unwrap();
+        assert_eq!(stored, Some(Network::Regtest.to_string()));
+    }
+}
+
+#[cfg(all(test, feature = "regtest-tests"))]
+mod sync_tests {
+    use super::*;
+    use crate::crypto::generate_mnemonic;
+    use crate::regtest::Regtest;
+
+    const AMOUNT: u64 = 50_000;
+
+    fn wallet_service(
+        regtest: &Regtest,
+        mnemonic: &str,
+        data_dir: &str,
+        stop_gap: usize,
+    ) -> WalletService {
+        let wallet = open_wallet(mnemonic, data_dir, Network::Regtest).unwrap();
+        let config = WalletConfig {
+            esplora_url: regtest.esplora_url(),
+            stop_gap,
+            ..Default::default()
+        };
+        WalletService::new(wallet, config).unwrap()
+    }
+
+    /// Receiving address `index` without revealing it, as if paid by another wallet of the same
+    /// mnemonic.
+    async fn unrevealed_address(wallet: &WalletService, index: u32) -> Address {
+        let wallet = wallet.wallet.lock().await;
+        wallet.get_address(AddressIndex::Peek(index)).address
+    }
+
+    /// Syncs until the confirmed balance is `expected`, electrs indexes new blocks with a delay.
+    async fn wait_for_balance(wallet: &WalletService, expected: u64) -> bool {
+        for _ in 0..50 {
+            wallet.sync().await.unwrap();
+            if wallet.balance().await.confirmed == expected {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+        false
+    }
+
+    #[tokio::test]
+    async fn sync_finds_payments_within_stop_gap() {
+        let regtest = Regtest::start();
+        let data_dir =
+            std::env::temp_dir().join(format!("openbazaar-wallet-sync-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&data_dir);
+        let data_dir = data_dir.to_str().unwrap();
+        let mnemonic = generate_mnemonic();
+
+        let wallet = wallet_service(&regtest, &mnemonic, data_dir, 20);
+        regtest.fund(&unrevealed_address(&wallet, 10).await, AMOUNT);
+        assert!(wait_for_balance(&wallet, AMOUNT).await);
+        let status = wallet.sync_status();
+        assert!(status.height.is_some());
+        assert_eq!(status.last_error, None);
+        // Addresses found in use are revealed and extend the next scan
+        let index = wallet
+            .wallet
+            .lock()
+            .await
+            .derivation_index(KeychainKind::External);
+        assert_eq!(index, Some(10));
+
+        // Mined in the same block, index 40 is further past the last used address than the
+        // stop gap reaches
+        regtest.send(&unrevealed_address(&wallet, 40).await, AMOUNT);
+        regtest.fund(&unrevealed_address(&wallet, 11).await, AMOUNT);
+        assert!(wait_for_balance(&wallet, 2 * AMOUNT).await);
+        wallet.sync().await.unwrap();
+        assert_eq!(wallet.balance().await.confirmed, 2 * AMOUNT);
+        drop(wallet);
+
+        // Reopened, the wallet resumes from its checkpoints and a wider stop gap finds the rest
+        let wallet = wallet_service(&regtest, &mnemonic, data_dir, 40);
+        assert!(wallet.sync_status().height.is_some());
+        assert!(wait_for_balance(&wallet, 3 * AMOUNT).await);
+    }
 }